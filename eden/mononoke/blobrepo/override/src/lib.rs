@@ -11,6 +11,7 @@ use blobrepo::BlobRepo;
 use blobrepo::BlobRepoInner;
 use blobstore::Blobstore;
 use bonsai_hg_mapping::ArcBonsaiHgMapping;
+use bookmarks::ArcBookmarks;
 use cacheblob::LeaseOps;
 use changeset_fetcher::SimpleChangesetFetcher;
 use changesets::ArcChangesets;
@@ -136,3 +137,16 @@ impl DangerousOverride<ArcBonsaiHgMapping> for BlobRepoInner {
         }
     }
 }
+
+impl DangerousOverride<ArcBookmarks> for BlobRepoInner {
+    fn dangerous_override<F>(&self, modify: F) -> Self
+    where
+        F: FnOnce(ArcBookmarks) -> ArcBookmarks,
+    {
+        let bookmarks = modify(self.bookmarks.clone());
+        Self {
+            bookmarks,
+            ..self.clone()
+        }
+    }
+}
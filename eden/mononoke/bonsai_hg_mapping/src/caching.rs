@@ -254,6 +254,21 @@ impl BonsaiHgMapping for CachingBonsaiHgMapping {
 
         self.mapping.get_hg_in_range(ctx, low, high, limit).await
     }
+
+    fn prime_cache(&self, _ctx: &CoreContext, entries: &[BonsaiHgMappingEntry]) {
+        let repo_id = self.repo_id();
+        for entry in entries {
+            let cache_entry = BonsaiHgMappingCacheEntry::new(repo_id, entry.hg_cs_id, entry.bcs_id);
+            // The entry is looked up by either side of the mapping, so cache it under both.
+            for cs in [
+                BonsaiOrHgChangesetId::Bonsai(entry.bcs_id),
+                BonsaiOrHgChangesetId::Hg(entry.hg_cs_id),
+            ] {
+                let key = get_cache_key(repo_id, &cs);
+                let _ = self.cache_pool.set_cached(&key, &cache_entry, None);
+            }
+        }
+    }
 }
 
 fn get_cache_key(repo_id: RepositoryId, cs: &BonsaiOrHgChangesetId) -> String {
@@ -153,6 +153,13 @@ pub trait BonsaiHgMapping: Send + Sync {
         high: HgChangesetId,
         limit: usize,
     ) -> Result<Vec<HgChangesetId>, Error>;
+
+    /// Populate any caches held by this instance of BonsaiHgMapping with these entries, e.g.
+    /// from a snapshot taken by another process. The entries must be for the repository
+    /// associated with this `BonsaiHgMapping`.
+    fn prime_cache(&self, _ctx: &CoreContext, _entries: &[BonsaiHgMappingEntry]) {
+        // No-op by default.
+    }
 }
 
 #[derive(Clone)]
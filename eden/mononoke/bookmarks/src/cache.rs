@@ -98,6 +98,17 @@ impl Cache {
         }
     }
 
+    /// Create a cache that is already populated with this data
+    fn new_primed(data: CacheData, expires: Instant) -> Self {
+        let current = future::ready(Arc::new(Ok(data))).boxed().shared();
+
+        Cache {
+            expires,
+            freshness: Freshness::MaybeStale,
+            current,
+        }
+    }
+
     /// Checks if current cache contains failed result
     fn is_failed(&self) -> bool {
         match self.current.peek() {
@@ -341,6 +352,25 @@ impl Bookmarks for CachedBookmarks {
         let mut cache = self.cache.lock().expect("lock poisoned");
         *cache = None;
     }
+
+    /// Install these bookmarks as the cache, unless we already have one.
+    fn prime_cache(&self, _ctx: &CoreContext, bookmarks: &[(Bookmark, ChangesetId)]) {
+        let ttl = match ttl() {
+            Some(ttl) => ttl,
+            None => return,
+        };
+
+        let data = bookmarks
+            .iter()
+            .filter(|(bookmark, _)| bookmark.kind().is_public())
+            .map(|(bookmark, cs_id)| (bookmark.name().clone(), (*bookmark.kind(), *cs_id)))
+            .collect();
+
+        let mut cache = self.cache.lock().expect("lock poisoned");
+        if cache.is_none() {
+            *cache = Some(Cache::new_primed(data, Instant::now() + ttl));
+        }
+    }
 }
 
 impl BookmarkTransaction for CachedBookmarksTransaction {
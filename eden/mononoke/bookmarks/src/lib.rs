@@ -95,6 +95,12 @@ pub trait Bookmarks: Send + Sync + 'static {
     fn drop_caches(&self) {
         // No-op by default.
     }
+
+    /// Populate any caches held by this instance of Bookmarks with these publishing bookmarks,
+    /// e.g. from a snapshot taken by another process.
+    fn prime_cache(&self, _ctx: &CoreContext, _bookmarks: &[(Bookmark, ChangesetId)]) {
+        // No-op by default.
+    }
 }
 
 #[async_trait]
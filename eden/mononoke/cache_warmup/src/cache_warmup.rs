@@ -5,12 +5,15 @@
  * GNU General Public License version 2.
  */
 
+use std::num::NonZeroUsize;
+
 use anyhow::Context;
 use anyhow::Error;
 use blobrepo::BlobRepo;
 use blobrepo_hg::BlobRepoHg;
 use blobstore::Loadable;
 use bookmarks::BookmarkName;
+use bounded_traversal::bounded_traversal_stream;
 use cloned::cloned;
 use context::CoreContext;
 use context::PerfCounterType;
@@ -24,14 +27,20 @@ use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
 use futures_stats::TimedFutureExt;
 use manifest::Entry;
+use manifest::Manifest;
 use manifest::ManifestOps;
 use mercurial_derived_data::DeriveHgChangeset;
+use mercurial_derived_data::MappedHgChangesetId;
 use mercurial_types::HgChangesetId;
 use mercurial_types::HgFileNodeId;
+use mercurial_types::HgManifestId;
+use mercurial_types::MPath;
 use mercurial_types::RepoPath;
 use metaconfig_types::CacheWarmupParams;
+use microwave::LoadOptions;
 use microwave::SnapshotLocation;
 use mononoke_types::ChangesetId;
+use mononoke_types::DateTime;
 use revset::AncestorsNodeStream;
 use slog::debug;
 use slog::info;
@@ -53,13 +62,26 @@ mod errors {
 pub enum CacheWarmupTarget {
     Bookmark(BookmarkName),
     Changeset(ChangesetId),
+    /// Warm up from each of these changesets in turn, with the same limits for each.
+    Changesets(Vec<ChangesetId>),
 }
 
+/// How many manifest entries are fetched in parallel while warming up, unless the request says
+/// otherwise.
+pub const DEFAULT_CONCURRENCY: usize = 100;
+
 #[derive(Debug)]
 pub struct CacheWarmupRequest {
     pub target: CacheWarmupTarget,
     pub commit_limit: usize,
     pub microwave_preload: bool,
+    /// Only warm up the manifests that have changed since this time.
+    pub since: Option<DateTime>,
+    /// How many manifest entries to fetch (and derive data for) in parallel.
+    pub concurrency: NonZeroUsize,
+    /// Look up the data derived for the warmup target before deriving it, and only derive it if
+    /// it is missing. This skips the derivation machinery in repos that are already derived.
+    pub only_missing_derived_data: bool,
 }
 
 impl From<CacheWarmupParams> for CacheWarmupRequest {
@@ -74,10 +96,78 @@ impl From<CacheWarmupParams> for CacheWarmupRequest {
             target: CacheWarmupTarget::Bookmark(bookmark),
             commit_limit,
             microwave_preload,
+            since: None,
+            concurrency: NonZeroUsize::new(DEFAULT_CONCURRENCY).unwrap(),
+            only_missing_derived_data: false,
         }
     }
 }
 
+/// Cache usage observed while warming up.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheWarmupStats {
+    /// Reads that were served from cachelib or memcache.
+    pub cache_hits: u64,
+    /// Reads that had to go to the underlying storage.
+    pub cache_misses: u64,
+}
+
+impl CacheWarmupStats {
+    fn from_perf_counters(ctx: &CoreContext) -> Self {
+        let counters = ctx.perf_counters();
+        let get = |counter| counters.get_counter(counter).max(0) as u64;
+
+        // Every read goes through cachelib first, and cachelib misses then go to memcache.
+        let reads = get(PerfCounterType::CachelibHits) + get(PerfCounterType::CachelibMisses);
+        let cache_hits = get(PerfCounterType::CachelibHits) + get(PerfCounterType::MemcacheHits);
+
+        Self {
+            cache_hits,
+            cache_misses: reads.saturating_sub(cache_hits),
+        }
+    }
+
+    /// Fraction of reads that were served from cache, if there were any reads.
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        let reads = self.cache_hits + self.cache_misses;
+        if reads == 0 {
+            return None;
+        }
+        Some(self.cache_hits as f64 / reads as f64)
+    }
+}
+
+// The hg changeset for this changeset, only deriving it if `only_missing` is unset or it has not
+// been derived yet.
+async fn hg_changeset(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    bcs_id: ChangesetId,
+    only_missing: bool,
+) -> Result<HgChangesetId, Error> {
+    if only_missing {
+        if let Some(mapped) = MappedHgChangesetId::fetch_derived(ctx, repo, &bcs_id).await? {
+            return Ok(mapped.hg_changeset_id());
+        }
+    }
+    Ok(repo.derive_hg_changeset(ctx, bcs_id).await?)
+}
+
+// Ensure filenodes are derived for this changeset, only deriving them if `only_missing` is unset
+// or they have not been derived yet.
+async fn derive_filenodes(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    bcs_id: ChangesetId,
+    only_missing: bool,
+) -> Result<(), Error> {
+    if only_missing && FilenodesOnlyPublic::is_derived(ctx, repo, &bcs_id).await? {
+        return Ok(());
+    }
+    FilenodesOnlyPublic::derive(ctx, repo, bcs_id).await?;
+    Ok(())
+}
+
 // Fetches all the manifest entries and their linknodes. Do not fetching files because
 // there can be too many of them.
 async fn blobstore_and_filenodes_warmup(
@@ -85,16 +175,13 @@ async fn blobstore_and_filenodes_warmup(
     repo: &BlobRepo,
     bcs_id: ChangesetId,
     hg_cs_id: HgChangesetId,
+    concurrency: NonZeroUsize,
+    only_missing_derived_data: bool,
 ) -> Result<(), Error> {
-    // TODO(stash): Arbitrary number. Tweak somehow?
-    let buffer_size = 100usize;
-
     // Ensure filenodes are derived for this, and load the changeset.
     let (cs, ()) = future::try_join(
         hg_cs_id.load(ctx, repo.blobstore()).map_err(Error::from),
-        FilenodesOnlyPublic::derive(ctx, repo, bcs_id)
-            .map_err(Error::from)
-            .map_ok(|_| ()),
+        derive_filenodes(ctx, repo, bcs_id, only_missing_derived_data),
     )
     .await?;
 
@@ -129,7 +216,7 @@ async fn blobstore_and_filenodes_warmup(
 
             future::ready(Ok(item))
         })
-        .try_buffer_unordered(buffer_size)
+        .try_buffer_unordered(concurrency.get())
         .try_fold(0u64, {
             let mut i = 0;
             move |mut null_linknodes, linknode| {
@@ -157,6 +244,90 @@ async fn blobstore_and_filenodes_warmup(
     Ok(())
 }
 
+// Like `blobstore_and_filenodes_warmup`, but only descends into trees whose linknode is not
+// older than `since`. A tree that has not changed since then has no changes below it either.
+async fn recent_blobstore_and_filenodes_warmup(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    bcs_id: ChangesetId,
+    hg_cs_id: HgChangesetId,
+    since: DateTime,
+    concurrency: NonZeroUsize,
+    only_missing_derived_data: bool,
+) -> Result<(), Error> {
+    let (cs, ()) = future::try_join(
+        hg_cs_id.load(ctx, repo.blobstore()).map_err(Error::from),
+        derive_filenodes(ctx, repo, bcs_id, only_missing_derived_data),
+    )
+    .await?;
+
+    let (visited, skipped, null_linknodes) = bounded_traversal_stream(
+        concurrency.get(),
+        Some((cs.manifestid(), RepoPath::RootPath)),
+        |(manifest_id, path): (HgManifestId, RepoPath)| {
+            cloned!(ctx, repo);
+            async move {
+                let hash = HgFileNodeId::new(manifest_id.into_nodehash());
+                let linknode = match repo.get_filenode_opt(ctx.clone(), &path, hash).await? {
+                    FilenodeResult::Present(filenode) => filenode.map(|filenode| filenode.linknode),
+                    FilenodeResult::Disabled => None,
+                };
+
+                // Without a linknode we can't tell how old this tree is, so keep going.
+                let null_linknode = linknode.is_none();
+                match linknode {
+                    Some(linknode) => {
+                        let linknode = linknode.load(&ctx, repo.blobstore()).await?;
+                        if linknode.time().timestamp_secs() < since.timestamp_secs() {
+                            return Ok(((false, false), Vec::new()));
+                        }
+                    }
+                    None => {
+                        ctx.perf_counters()
+                            .increment_counter(PerfCounterType::NullLinknode);
+                    }
+                }
+
+                let manifest = manifest_id.load(&ctx, repo.blobstore()).await?;
+                let children = Manifest::list(&manifest)
+                    .filter_map(|(name, entry)| match entry {
+                        Entry::Tree(manifest_id) => {
+                            let path = MPath::join_opt_element(path.mpath(), &name);
+                            Some((manifest_id, RepoPath::DirectoryPath(path)))
+                        }
+                        Entry::Leaf(_) => None,
+                    })
+                    .collect::<Vec<_>>();
+
+                Ok::<_, Error>(((true, null_linknode), children))
+            }
+            .boxed()
+        },
+    )
+    .try_fold(
+        (0u64, 0u64, 0u64),
+        |(visited, skipped, null_linknodes), (recent, null_linknode)| {
+            let null_linknodes = null_linknodes + u64::from(null_linknode);
+            future::ready(Ok(if recent {
+                (visited + 1, skipped, null_linknodes)
+            } else {
+                (visited, skipped + 1, null_linknodes)
+            }))
+        },
+    )
+    .await?;
+
+    if null_linknodes > 0 {
+        warn!(ctx.logger(), "{} linknodes are missing!", null_linknodes);
+    }
+    debug!(
+        ctx.logger(),
+        "finished manifests warmup: {} recent trees, {} unchanged trees skipped", visited, skipped
+    );
+
+    Ok(())
+}
+
 // Iterate over first parents, and fetch them
 async fn changesets_warmup(
     ctx: &CoreContext,
@@ -191,26 +362,91 @@ async fn do_cache_warmup(
     repo: &BlobRepo,
     target: CacheWarmupTarget,
     commit_limit: usize,
-) -> Result<(), Error> {
+    since: Option<DateTime>,
+    concurrency: NonZeroUsize,
+    only_missing_derived_data: bool,
+) -> Result<CacheWarmupStats, Error> {
     let ctx = ctx.clone_and_reset();
 
-    let bcs_id = match target {
-        CacheWarmupTarget::Bookmark(bookmark) => repo
+    let bcs_ids = match target {
+        CacheWarmupTarget::Bookmark(bookmark) => vec![repo
             .bookmarks()
             .get(ctx.clone(), &bookmark)
             .await?
-            .ok_or(errors::ErrorKind::BookmarkNotFound(bookmark))?,
-        CacheWarmupTarget::Changeset(bcs_id) => bcs_id,
+            .ok_or(errors::ErrorKind::BookmarkNotFound(bookmark))?],
+        CacheWarmupTarget::Changeset(bcs_id) => vec![bcs_id],
+        CacheWarmupTarget::Changesets(bcs_ids) => bcs_ids,
     };
 
-    let hg_cs_id = repo.derive_hg_changeset(&ctx, bcs_id).await?;
+    let (stats, res) = async {
+        for bcs_id in bcs_ids {
+            changeset_warmup(
+                &ctx,
+                repo,
+                bcs_id,
+                commit_limit,
+                since,
+                concurrency,
+                only_missing_derived_data,
+            )
+            .await
+            .with_context(|| format!("While warming up from {}", bcs_id))?;
+        }
+        Result::<_, Error>::Ok(())
+    }
+    .timed()
+    .await;
+    res?;
+
+    info!(ctx.logger(), "finished initial warmup");
+
+    let mut scuba = ctx.scuba().clone();
+    scuba.add_future_stats(&stats);
+    ctx.perf_counters().insert_perf_counters(&mut scuba);
+    scuba.log_with_msg("Cache warmup complete", None);
+    Ok(CacheWarmupStats::from_perf_counters(&ctx))
+}
+
+async fn changeset_warmup(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    bcs_id: ChangesetId,
+    commit_limit: usize,
+    since: Option<DateTime>,
+    concurrency: NonZeroUsize,
+    only_missing_derived_data: bool,
+) -> Result<(), Error> {
+    let hg_cs_id = hg_changeset(ctx, repo, bcs_id, only_missing_derived_data).await?;
 
     let blobstore_warmup = task::spawn({
         cloned!(ctx, repo);
         async move {
-            blobstore_and_filenodes_warmup(&ctx, &repo, bcs_id, hg_cs_id)
-                .await
-                .context("While warming up blobstore and filenodes")
+            match since {
+                Some(since) => {
+                    recent_blobstore_and_filenodes_warmup(
+                        &ctx,
+                        &repo,
+                        bcs_id,
+                        hg_cs_id,
+                        since,
+                        concurrency,
+                        only_missing_derived_data,
+                    )
+                    .await
+                }
+                None => {
+                    blobstore_and_filenodes_warmup(
+                        &ctx,
+                        &repo,
+                        bcs_id,
+                        hg_cs_id,
+                        concurrency,
+                        only_missing_derived_data,
+                    )
+                    .await
+                }
+            }
+            .context("While warming up blobstore and filenodes")
         }
     });
 
@@ -223,23 +459,22 @@ async fn do_cache_warmup(
         }
     });
 
-    let (stats, res) = future::try_join(blobstore_warmup, cs_warmup).timed().await;
-    let (blobstore_warmup, cs_warmup) = res?;
+    let (blobstore_warmup, cs_warmup) = future::try_join(blobstore_warmup, cs_warmup).await?;
     blobstore_warmup?;
     cs_warmup?;
 
-    info!(ctx.logger(), "finished initial warmup");
-
-    let mut scuba = ctx.scuba().clone();
-    scuba.add_future_stats(&stats);
-    ctx.perf_counters().insert_perf_counters(&mut scuba);
-    scuba.log_with_msg("Cache warmup complete", None);
     Ok(())
 }
 
-async fn microwave_preload(ctx: &CoreContext, repo: &BlobRepo, req: &CacheWarmupRequest) {
+async fn microwave_preload(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    req: &CacheWarmupRequest,
+    snapshot_location: SnapshotLocation<'_>,
+    load_options: LoadOptions,
+) {
     if req.microwave_preload {
-        match microwave::prime_cache(ctx, repo, SnapshotLocation::Blobstore).await {
+        match microwave::prime_cache(ctx, repo, snapshot_location, load_options).await {
             Ok(_) => {
                 warn!(ctx.logger(), "microwave: successfully primed cache");
             }
@@ -251,21 +486,48 @@ async fn microwave_preload(ctx: &CoreContext, repo: &BlobRepo, req: &CacheWarmup
 }
 
 /// Fetch all manifest entries for a bookmark, and fetches up to `commit_warmup_limit`
-/// ancestors of the bookmark.
+/// ancestors of the bookmark. Returns how many of those reads were served from cache.
 pub async fn cache_warmup<T: Into<CacheWarmupRequest>>(
     ctx: &CoreContext,
     repo: &BlobRepo,
     cache_warmup: Option<T>,
-) -> Result<(), Error> {
+) -> Result<CacheWarmupStats, Error> {
+    cache_warmup_from_location(
+        ctx,
+        repo,
+        cache_warmup,
+        SnapshotLocation::Blobstore { key_prefix: None },
+        LoadOptions::default(),
+    )
+    .await
+}
+
+/// Like `cache_warmup`, but preloads the microwave snapshot from this location instead of the
+/// repository blobstore, and loads it with these options.
+pub async fn cache_warmup_from_location<T: Into<CacheWarmupRequest>>(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    cache_warmup: Option<T>,
+    snapshot_location: SnapshotLocation<'_>,
+    load_options: LoadOptions,
+) -> Result<CacheWarmupStats, Error> {
     if let Some(req) = cache_warmup {
         let req = req.into();
 
-        microwave_preload(ctx, repo, &req).await;
-
-        do_cache_warmup(ctx, repo, req.target, req.commit_limit)
-            .await
-            .with_context(|| format!("while warming up repo {}", repo.get_repoid()))?;
+        microwave_preload(ctx, repo, &req, snapshot_location, load_options).await;
+
+        return do_cache_warmup(
+            ctx,
+            repo,
+            req.target,
+            req.commit_limit,
+            req.since,
+            req.concurrency,
+            req.only_missing_derived_data,
+        )
+        .await
+        .with_context(|| format!("while warming up repo {}", repo.get_repoid()));
     }
 
-    Ok(())
+    Ok(CacheWarmupStats::default())
 }
@@ -9,8 +9,8 @@ use clap::Args;
 
 #[derive(Args, Debug)]
 pub struct CachelibArgs {
-    /// Do not initialize cachelib and disable caches (useful for tests)
-    #[clap(long)]
+    /// Do not initialize cachelib and disable caches (useful for tests and quick inspection runs)
+    #[clap(long, alias = "no-cachelib")]
     pub skip_caching: bool,
 
     /// Run the blobstore with cachelib only (i.e., without memcache)
@@ -22,6 +22,7 @@ use scuba_ext::MononokeScubaSampleBuilder;
 use slog::Logger;
 use sql_ext::facebook::MysqlOptions;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum Caching {
@@ -66,4 +67,7 @@ pub struct MononokeEnvironment {
     pub warm_bookmarks_cache_derived_data: Option<WarmBookmarksCacheDerivedData>,
     /// Function determining whether given repo (identified by name) should be loaded
     pub filter_repos: Option<Arc<dyn Fn(&str) -> bool + Send + Sync + 'static>>,
+    /// Cancelled when the app should shut down, e.g. by app extensions. Apps that support this
+    /// should stop their work once it is cancelled.
+    pub shutdown: CancellationToken,
 }
@@ -11,13 +11,17 @@ use std::collections::HashSet;
 use std::fs;
 use std::future::Future;
 use std::path::Path;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 use std::time::Instant;
 
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
+use anyhow::Error;
 use anyhow::Result;
 use base_app::BaseApp;
 use blobstore::Blobstore;
@@ -106,14 +110,8 @@ impl MononokeApp {
         extension_args: HashMap<TypeId, Box<dyn BoxedAppExtensionArgs>>,
     ) -> Result<Self> {
         let env = Arc::new(env);
-        let config_path = ConfigArgs::from_arg_matches(&args)?.config_path();
-        let config_store = &env.as_ref().config_store;
-        let configs = Arc::new(MononokeConfigs::new(
-            config_path,
-            config_store,
-            env.runtime.handle().clone(),
-            env.logger.clone(),
-        )?);
+        let config_args = ConfigArgs::from_arg_matches(&args)?;
+        let configs = Arc::new(Self::load_configs(&env, &config_args)?);
 
         let repo_factory = Arc::new(RepoFactory::new(env.clone()));
 
@@ -128,6 +126,63 @@ impl MononokeApp {
         })
     }
 
+    fn load_configs(
+        env: &MononokeEnvironment,
+        config_args: &ConfigArgs,
+    ) -> Result<MononokeConfigs> {
+        config_args.check_local_config_path()?;
+        let config_path = config_args.config_path();
+        let skip_bad_configs = config_args.skip_bad_configs;
+        let new_configs = move |config_path: String,
+                                config_store: &ConfigStore,
+                                handle: Handle,
+                                logger: Logger| {
+            if skip_bad_configs {
+                MononokeConfigs::new_skipping_invalid_repos(
+                    config_path,
+                    config_store,
+                    handle,
+                    logger,
+                )
+            } else {
+                MononokeConfigs::new(config_path, config_store, handle, logger)
+            }
+        };
+        let timeout = match config_args.config_timeout_secs {
+            Some(secs) => Duration::from_secs(secs),
+            None => {
+                return new_configs(
+                    config_path,
+                    &env.config_store,
+                    env.runtime.handle().clone(),
+                    env.logger.clone(),
+                );
+            }
+        };
+
+        let config_store = env.config_store.clone();
+        let handle = env.runtime.handle().clone();
+        let logger = env.logger.clone();
+        let load = run_with_timeout(timeout, {
+            let config_path = config_path.clone();
+            move || {
+                let _guard = handle.enter();
+                new_configs(config_path, &config_store, handle.clone(), logger)
+            }
+        });
+        match load {
+            Ok(configs) => configs,
+            Err(RecvTimeoutError::Timeout) => Err(anyhow!(
+                "Timed out reading config from {} after {}s",
+                config_path,
+                timeout.as_secs()
+            )),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(anyhow!("Reading config from {} panicked", config_path))
+            }
+        }
+    }
+
     pub fn extension_args<Ext>(&self) -> Result<&Ext::Args>
     where
         Ext: AppExtension + 'static,
@@ -329,6 +384,12 @@ impl MononokeApp {
         self.configs.repo_configs()
     }
 
+    /// The repos left out of `repo_configs` because their config is invalid, along with the
+    /// reason. Repos are only left out with `--skip-bad-configs`.
+    pub fn skipped_repo_configs(&self) -> &[(String, Error)] {
+        self.configs.skipped_repo_configs()
+    }
+
     /// The storage configs for this app.
     pub fn storage_configs(&self) -> Arc<StorageConfigs> {
         self.configs.storage_configs()
@@ -782,3 +843,46 @@ pub fn setup_repo_dir<P: AsRef<Path>>(data_dir: P, create: CreateStorage) -> Res
     }
     Ok(())
 }
+
+/// Run `f` on a thread of its own, and wait up to `timeout` for it to return. The thread is
+/// detached rather than run as a blocking task, as dropping a runtime waits for its blocking
+/// tasks, so one that never returns would keep the process from ever exiting.
+fn run_with_timeout<T, F>(timeout: Duration, f: F) -> Result<T, RecvTimeoutError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // Nobody is listening any more if we gave up waiting.
+        let _ = sender.send(f());
+    });
+    receiver.recv_timeout(timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_with_timeout() {
+        assert_eq!(run_with_timeout(Duration::from_secs(60), || 42), Ok(42));
+    }
+
+    #[test]
+    fn test_run_with_timeout_never_returns() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let handle = runtime.handle().clone();
+        // Like a config source that never returns.
+        let res = run_with_timeout(Duration::from_millis(100), move || {
+            let _guard = handle.enter();
+            loop {
+                thread::park();
+            }
+        });
+        assert_eq!(res, Err::<(), _>(RecvTimeoutError::Timeout));
+        // This would wait forever if the read was still tied to the runtime.
+        drop(runtime);
+        Ok(())
+    }
+}
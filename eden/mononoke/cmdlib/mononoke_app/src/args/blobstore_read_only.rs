@@ -0,0 +1,38 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::Result;
+use blobstore_factory::ReadOnlyStorage;
+use clap::Args;
+use environment::MononokeEnvironment;
+
+use crate::AppExtension;
+
+/// Command line argument to guard against writes to storage
+#[derive(Args, Debug)]
+pub struct ReadOnlyBlobstoreArgs {
+    /// Same as --with-readonly-storage: reject all writes to storage opened by this process
+    #[clap(long)]
+    pub blobstore_read_only: bool,
+}
+
+/// Adds `--blobstore-read-only` as an alias for `--with-readonly-storage`, which sets the same
+/// `readonly_storage` of the environment. This makes both blobstores and SQL stores read-only,
+/// and has no effect on components that don't open storage, so it can be combined freely with
+/// other extensions.
+pub struct ReadOnlyBlobstoreAppExtension;
+
+impl AppExtension for ReadOnlyBlobstoreAppExtension {
+    type Args = ReadOnlyBlobstoreArgs;
+
+    fn environment_hook(&self, args: &Self::Args, env: &mut MononokeEnvironment) -> Result<()> {
+        if args.blobstore_read_only {
+            env.readonly_storage = ReadOnlyStorage(true);
+        }
+        Ok(())
+    }
+}
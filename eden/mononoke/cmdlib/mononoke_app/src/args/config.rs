@@ -5,8 +5,11 @@
  * GNU General Public License version 2.
  */
 
+use std::fs;
 use std::path::PathBuf;
 
+use anyhow::Context;
+use anyhow::Result;
 use clap::ArgGroup;
 use clap::Args;
 
@@ -33,8 +36,17 @@ pub struct ConfigArgs {
     /// Mononoke's crypto project
     #[clap(long)]
     pub crypto_path_regex: Option<Vec<String>>,
+
+    /// Fail if reading the Mononoke config takes longer than this many seconds
+    #[clap(long)]
+    pub config_timeout_secs: Option<u64>,
+
+    /// Leave out repos whose config is invalid, with a warning, instead of failing
+    #[clap(long)]
+    pub skip_bad_configs: bool,
 }
 
+const CONFIGERATOR_PREFIX: &str = "configerator://";
 const PRODUCTION_PREFIX: &str = "configerator://scm/mononoke/repos/tiers/";
 
 fn configerator_config_path(tier: &str) -> String {
@@ -54,6 +66,22 @@ impl ConfigArgs {
         }
     }
 
+    /// Check that `--config-path` can be read if it is on the local filesystem, so that a wrong
+    /// path is reported along with where it was resolved to before any config is read.
+    pub fn check_local_config_path(&self) -> Result<()> {
+        let config_path = match &self.config_path {
+            Some(config_path) if !config_path.starts_with(CONFIGERATOR_PREFIX) => config_path,
+            _ => return Ok(()),
+        };
+        let path = std::env::current_dir()?.join(config_path);
+        let readable = if path.is_dir() {
+            fs::read_dir(&path).map(|_| ())
+        } else {
+            fs::File::open(&path).map(|_| ())
+        };
+        readable.with_context(|| format!("Cannot read config path {}", path.display()))
+    }
+
     pub fn mode(&self) -> ConfigMode {
         if let Some(config_path) = &self.config_path {
             // Any configuration that matches the production prefix is prod.
@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::Args;
+use environment::MononokeEnvironment;
+use slog::error;
+
+use crate::AppExtension;
+
+/// Exit code for apps that were shut down for running longer than `--max-runtime-secs`. This is
+/// the same as `timeout(1)` uses.
+pub const EXIT_CODE_DEADLINE_EXCEEDED: i32 = 124;
+
+/// Command line argument to limit how long the app may run for
+#[derive(Args, Debug)]
+pub struct DeadlineArgs {
+    /// Shut down if still running after this many seconds
+    #[clap(long)]
+    pub max_runtime_secs: Option<u64>,
+}
+
+/// Shuts the app down once `--max-runtime-secs` have passed, by cancelling the `shutdown` token
+/// of its environment. Apps using this extension must stop their work once it is cancelled. Keep
+/// a clone of the extension to check afterwards whether the deadline was exceeded, and exit
+/// with `EXIT_CODE_DEADLINE_EXCEEDED` if so.
+#[derive(Clone, Default)]
+pub struct DeadlineAppExtension {
+    exceeded: Arc<AtomicBool>,
+}
+
+impl DeadlineAppExtension {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the app was shut down because it exceeded its deadline.
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::SeqCst)
+    }
+}
+
+impl AppExtension for DeadlineAppExtension {
+    type Args = DeadlineArgs;
+
+    fn environment_hook(&self, args: &Self::Args, env: &mut MononokeEnvironment) -> Result<()> {
+        if let Some(secs) = args.max_runtime_secs {
+            let exceeded = self.exceeded.clone();
+            let logger = env.logger.clone();
+            let shutdown = env.shutdown.clone();
+            env.runtime.spawn(async move {
+                tokio::time::sleep(Duration::from_secs(secs)).await;
+                error!(
+                    logger,
+                    "Exceeded the maximum runtime of {}s, shutting down", secs
+                );
+                exceeded.store(true, Ordering::SeqCst);
+                shutdown.cancel();
+            });
+        }
+        Ok(())
+    }
+}
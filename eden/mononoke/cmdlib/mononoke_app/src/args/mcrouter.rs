@@ -5,6 +5,7 @@
  * GNU General Public License version 2.
  */
 
+use anyhow::bail;
 use anyhow::Result;
 use clap::Args;
 use environment::MononokeEnvironment;
@@ -17,6 +18,15 @@ pub struct McrouterArgs {
     /// Use local McRouter for rate limits
     #[clap(long)]
     pub enable_mcrouter: bool,
+
+    /// Port of the local McRouter proxy. Only the default port is supported for now, as ratelim
+    /// cannot be pointed at another one, so passing this is an error
+    #[clap(long)]
+    pub mcrouter_port: Option<u16>,
+
+    /// Continue without rate limiting if McRouter is not supported by this build
+    #[clap(long)]
+    pub mcrouter_allow_missing: bool,
 }
 
 pub struct McrouterAppExtension;
@@ -26,8 +36,17 @@ impl AppExtension for McrouterAppExtension {
 
     fn environment_hook(&self, args: &McrouterArgs, env: &mut MononokeEnvironment) -> Result<()> {
         if !args.enable_mcrouter {
+            if let Some(port) = args.mcrouter_port {
+                bail!("Passed --mcrouter-port {} without --enable-mcrouter", port);
+            }
             return Ok(());
         }
+        if let Some(port) = args.mcrouter_port {
+            bail!(
+                "Passed --mcrouter-port {} but only McRouter on its default port is supported",
+                port
+            );
+        }
 
         #[cfg(fbcode_build)]
         {
@@ -37,8 +56,14 @@ impl AppExtension for McrouterAppExtension {
 
         #[cfg(not(fbcode_build))]
         {
-            let _ = env;
-            unimplemented!("Passed --enable-mcrouter but it is supported only for fbcode builds",);
+            if args.mcrouter_allow_missing {
+                slog::warn!(
+                    env.logger,
+                    "Passed --enable-mcrouter but it is supported only for fbcode builds, continuing without rate limiting"
+                );
+                return Ok(());
+            }
+            bail!("Passed --enable-mcrouter but it is supported only for fbcode builds");
         }
     }
 }
@@ -9,8 +9,10 @@ use anyhow::format_err;
 use anyhow::Result;
 
 mod acl;
+mod blobstore_read_only;
 mod changeset;
 mod config;
+mod deadline;
 mod hooks;
 mod mcrouter;
 mod mysql;
@@ -18,15 +20,22 @@ mod readonly;
 mod repo;
 mod repo_blobstore;
 mod repo_filter;
+mod run_id;
 mod runtime;
+mod scuba_sampling;
 mod shutdown_timeout;
 mod tls;
 mod tunables;
 
 pub use acl::AclArgs;
+pub use blobstore_read_only::ReadOnlyBlobstoreAppExtension;
+pub use blobstore_read_only::ReadOnlyBlobstoreArgs;
 pub use changeset::ChangesetArgs;
 pub use config::ConfigArgs;
 pub use config::ConfigMode;
+pub use deadline::DeadlineAppExtension;
+pub use deadline::DeadlineArgs;
+pub use deadline::EXIT_CODE_DEADLINE_EXCEEDED;
 pub use hooks::HooksAppExtension;
 pub use mcrouter::McrouterAppExtension;
 pub use mcrouter::McrouterArgs;
@@ -39,7 +48,11 @@ pub use repo::RepoArgs;
 pub use repo::SourceAndTargetRepoArgs;
 pub use repo_blobstore::RepoBlobstoreArgs;
 pub use repo_filter::RepoFilterAppExtension;
+pub use run_id::RunIdAppExtension;
+pub use run_id::RunIdArgs;
 pub use runtime::RuntimeArgs;
+pub use scuba_sampling::ScubaSamplingAppExtension;
+pub use scuba_sampling::ScubaSamplingArgs;
 pub use shutdown_timeout::ShutdownTimeoutArgs;
 pub use tls::TLSArgs;
 
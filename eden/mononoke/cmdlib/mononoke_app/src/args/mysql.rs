@@ -5,7 +5,12 @@
  * GNU General Public License version 2.
  */
 
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
 use clap::Args;
+use serde::Deserialize;
 
 /// Command line arguments for controlling MySql
 // Defaults are derived from `sql_ext::facebook::mysql`
@@ -64,4 +69,85 @@ pub struct MysqlArgs {
     /// Mysql query time limit in millisecs
     #[clap(long, default_value = "10000", alias = "mysql-max-query-time")]
     pub mysql_query_time_limit: u64,
+
+    /// Path to a JSON file with MySql options, which override those given on the command line.
+    /// Keys are the names of the options above, with underscores (e.g. `mysql_pool_limit`).
+    #[clap(long)]
+    pub mysql_options_file: Option<PathBuf>,
+}
+
+/// Options that can be set in `--mysql-options-file`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MysqlOptionsFile {
+    mysql_master_only: Option<bool>,
+    mysql_pool_limit: Option<usize>,
+    mysql_pool_per_key_limit: Option<u64>,
+    mysql_pool_threads_num: Option<i32>,
+    mysql_pool_age_timeout: Option<u64>,
+    mysql_pool_idle_timeout: Option<u64>,
+    mysql_sqlblob_pool_limit: Option<usize>,
+    mysql_sqlblob_pool_per_key_limit: Option<u64>,
+    mysql_sqlblob_pool_threads_num: Option<i32>,
+    mysql_sqlblob_pool_age_timeout: Option<u64>,
+    mysql_sqlblob_pool_idle_timeout: Option<u64>,
+    mysql_conn_open_timeout: Option<u64>,
+    mysql_query_time_limit: Option<u64>,
+}
+
+impl MysqlArgs {
+    /// Apply the options from `--mysql-options-file`, if it is set, on top of those given on the
+    /// command line.
+    pub fn with_options_file(mut self) -> Result<Self> {
+        let path = match &self.mysql_options_file {
+            Some(path) => path,
+            None => return Ok(self),
+        };
+        let file: MysqlOptionsFile = std::fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(serde_json::from_slice(&contents)?))
+            .with_context(|| format!("Failed to read MySql options from {}", path.display()))?;
+
+        if let Some(mysql_master_only) = file.mysql_master_only {
+            self.mysql_master_only = mysql_master_only;
+        }
+        if let Some(mysql_pool_limit) = file.mysql_pool_limit {
+            self.mysql_pool_limit = mysql_pool_limit;
+        }
+        if let Some(mysql_pool_per_key_limit) = file.mysql_pool_per_key_limit {
+            self.mysql_pool_per_key_limit = mysql_pool_per_key_limit;
+        }
+        if let Some(mysql_pool_threads_num) = file.mysql_pool_threads_num {
+            self.mysql_pool_threads_num = mysql_pool_threads_num;
+        }
+        if let Some(mysql_pool_age_timeout) = file.mysql_pool_age_timeout {
+            self.mysql_pool_age_timeout = mysql_pool_age_timeout;
+        }
+        if let Some(mysql_pool_idle_timeout) = file.mysql_pool_idle_timeout {
+            self.mysql_pool_idle_timeout = mysql_pool_idle_timeout;
+        }
+        if let Some(mysql_sqlblob_pool_limit) = file.mysql_sqlblob_pool_limit {
+            self.mysql_sqlblob_pool_limit = mysql_sqlblob_pool_limit;
+        }
+        if let Some(mysql_sqlblob_pool_per_key_limit) = file.mysql_sqlblob_pool_per_key_limit {
+            self.mysql_sqlblob_pool_per_key_limit = mysql_sqlblob_pool_per_key_limit;
+        }
+        if let Some(mysql_sqlblob_pool_threads_num) = file.mysql_sqlblob_pool_threads_num {
+            self.mysql_sqlblob_pool_threads_num = mysql_sqlblob_pool_threads_num;
+        }
+        if let Some(mysql_sqlblob_pool_age_timeout) = file.mysql_sqlblob_pool_age_timeout {
+            self.mysql_sqlblob_pool_age_timeout = mysql_sqlblob_pool_age_timeout;
+        }
+        if let Some(mysql_sqlblob_pool_idle_timeout) = file.mysql_sqlblob_pool_idle_timeout {
+            self.mysql_sqlblob_pool_idle_timeout = mysql_sqlblob_pool_idle_timeout;
+        }
+        if let Some(mysql_conn_open_timeout) = file.mysql_conn_open_timeout {
+            self.mysql_conn_open_timeout = mysql_conn_open_timeout;
+        }
+        if let Some(mysql_query_time_limit) = file.mysql_query_time_limit {
+            self.mysql_query_time_limit = mysql_query_time_limit;
+        }
+
+        Ok(self)
+    }
 }
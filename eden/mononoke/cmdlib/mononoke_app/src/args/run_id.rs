@@ -0,0 +1,37 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::Result;
+use clap::Args;
+use environment::MononokeEnvironment;
+use slog::info;
+use uuid::Uuid;
+
+use crate::AppExtension;
+
+/// Command line argument to identify a run of the app in scuba
+#[derive(Args, Debug)]
+pub struct RunIdArgs {
+    /// Id to tag every scuba sample of this run with (a random one is generated if not set)
+    #[clap(long)]
+    pub run_id: Option<Uuid>,
+}
+
+/// Adds a `run_id` column to the scuba sample builder in the environment, so that all the
+/// samples logged by one run of the app can be told apart from those of other runs.
+pub struct RunIdAppExtension;
+
+impl AppExtension for RunIdAppExtension {
+    type Args = RunIdArgs;
+
+    fn environment_hook(&self, args: &Self::Args, env: &mut MononokeEnvironment) -> Result<()> {
+        let run_id = args.run_id.unwrap_or_else(Uuid::new_v4);
+        info!(env.logger, "Run id: {}", run_id);
+        env.scuba_sample_builder.add("run_id", run_id.to_string());
+        Ok(())
+    }
+}
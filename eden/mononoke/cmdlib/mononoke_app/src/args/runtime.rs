@@ -12,7 +12,7 @@ use clap::Args;
 // https://fburl.com/diffusion/n5isd68j, last synced on 17/12/2020
 #[derive(Args, Debug)]
 pub struct RuntimeArgs {
-    /// Number of threads to use in the Tokio runtime
-    #[clap(long)]
+    /// Number of threads to use in the Tokio runtime. 0 uses the default of one thread per CPU
+    #[clap(long, alias = "worker-threads")]
     pub runtime_threads: Option<usize>,
 }
@@ -0,0 +1,37 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::num::NonZeroU64;
+
+use anyhow::Result;
+use clap::Args;
+use environment::MononokeEnvironment;
+
+use crate::AppExtension;
+
+/// Command line argument to down-sample scuba logging
+#[derive(Args, Debug)]
+pub struct ScubaSamplingArgs {
+    /// Only log 1 in this many scuba samples (1 logs everything)
+    #[clap(long, default_value = "1")]
+    pub scuba_sample_rate: NonZeroU64,
+}
+
+/// Applies `--scuba-sample-rate` to the scuba sample builder in the environment, and so to every
+/// sample built from it.
+pub struct ScubaSamplingAppExtension;
+
+impl AppExtension for ScubaSamplingAppExtension {
+    type Args = ScubaSamplingArgs;
+
+    fn environment_hook(&self, args: &Self::Args, env: &mut MononokeEnvironment) -> Result<()> {
+        if args.scuba_sample_rate.get() > 1 {
+            env.scuba_sample_builder.sampled(args.scuba_sample_rate);
+        }
+        Ok(())
+    }
+}
@@ -10,6 +10,7 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use arg_extensions::ArgDefaults;
@@ -57,6 +58,7 @@ use sql_ext::facebook::ReadConnectionType;
 use sql_ext::facebook::SharedConnectionPool;
 use tokio::runtime::Handle;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 
 use crate::app::MononokeApp;
 use crate::args::parse_config_spec_to_path;
@@ -236,6 +238,22 @@ impl MononokeAppBuilder {
 
         let args = app.get_matches();
 
+        for (_type_id, ext) in self.extensions.iter() {
+            for conflict in ext.conflicts_with() {
+                if let Some((_type_id, other)) = self
+                    .extensions
+                    .iter()
+                    .find(|(_type_id, other)| other.name() == *conflict)
+                {
+                    bail!(
+                        "App extension {} cannot be used together with app extension {}",
+                        ext.name(),
+                        other.name()
+                    );
+                }
+            }
+        }
+
         let extension_args = self
             .extensions
             .iter()
@@ -249,9 +267,13 @@ impl MononokeAppBuilder {
             extension_args.iter().map(|(_type_id, ext)| ext.as_ref()),
         )?;
 
-        for (_type_id, ext) in extension_args.iter() {
-            ext.environment_hook(&mut env)?;
-        }
+        let runtime = env.runtime.handle().clone();
+        runtime.block_on(async {
+            for (_type_id, ext) in extension_args.iter() {
+                ext.environment_hook(&mut env).await?;
+            }
+            anyhow::Ok(())
+        })?;
 
         MononokeApp::new(
             self.fb,
@@ -303,10 +325,25 @@ impl MononokeAppBuilder {
             .create_observability_context(&config_store, log_level)
             .context("Failed to initialize observability context")?;
 
+        // Extensions may lower the level that records reach their drains at, to filter some of
+        // them more finely themselves. Only the drain is filtered at that level, everything else
+        // keeps the level that was asked for.
+        let drain_level = extension_args
+            .clone()
+            .into_iter()
+            .fold(log_level, |level, ext| ext.log_level_hook(level));
+        let drain_observability_context = if drain_level == log_level {
+            observability_context.clone()
+        } else {
+            logging_args
+                .create_observability_context(&config_store, drain_level)
+                .context("Failed to initialize log drain observability context")?
+        };
+
         let mut root_log_drain: Arc<dyn SendSyncRefUnwindSafeDrain<Ok = (), Err = Never>> =
             Arc::new(DynamicLevelDrain::new(
                 root_log_drain,
-                observability_context.clone(),
+                drain_observability_context,
             ));
         for ext in extension_args {
             root_log_drain = ext.log_drain_hook(root_log_drain)?;
@@ -329,6 +366,7 @@ impl MononokeAppBuilder {
 
         let runtime = create_runtime(&runtime_args)?;
 
+        let mysql_args = mysql_args.with_options_file()?;
         let mysql_options =
             create_mysql_options(&mysql_args, create_mysql_pool_config(&mysql_args));
 
@@ -381,6 +419,7 @@ impl MononokeAppBuilder {
             skiplist_enabled: self.skiplist_enabled,
             warm_bookmarks_cache_derived_data: self.warm_bookmarks_cache_derived_data,
             filter_repos: None,
+            shutdown: CancellationToken::new(),
         })
     }
 }
@@ -428,7 +467,7 @@ fn create_runtime(runtime_args: &RuntimeArgs) -> Result<Runtime> {
     let mut builder = tokio::runtime::Builder::new_multi_thread();
     builder.enable_all();
     builder.thread_name("tk");
-    if let Some(threads) = runtime_args.runtime_threads {
+    if let Some(threads) = runtime_args.runtime_threads.filter(|threads| *threads > 0) {
         builder.worker_threads(threads);
     }
     let runtime = builder.build()?;
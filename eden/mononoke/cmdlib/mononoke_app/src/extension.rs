@@ -9,21 +9,37 @@ use std::any::Any;
 use std::sync::Arc;
 
 use anyhow::Result;
+use async_trait::async_trait;
 use clap::ArgMatches;
 use clap::Args;
 use clap::Command;
 use clap::FromArgMatches;
 use environment::MononokeEnvironment;
+use slog::Level;
 use slog::Never;
 use slog::SendSyncRefUnwindSafeDrain;
 
 /// Trait implemented by things that need to extend the app building process,
 /// including adding additional arguments and modifying the environment before
 /// it is used to start Mononoke.
+#[async_trait]
 pub trait AppExtension: Send + Sync + 'static {
     /// Argument type to extend Mononoke arguments with.
     type Args: clap::Args + Send + Sync + 'static;
 
+    /// Name of this extension, as other extensions refer to it in `conflicts_with`. Defaults to
+    /// the name of the type, without its path.
+    fn name(&self) -> &'static str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
+    /// Names of extensions that make no sense together with this one. The app fails to build if
+    /// any of them is also in use.
+    fn conflicts_with(&self) -> &[&str] {
+        &[]
+    }
+
     /// Obtain default values for these arguments.
     fn arg_defaults(&self) -> Vec<(&'static str, String)> {
         Vec::new()
@@ -34,6 +50,25 @@ pub trait AppExtension: Send + Sync + 'static {
         Ok(())
     }
 
+    /// Async version of `environment_hook`, for extensions that need to wait on some
+    /// initialization before the app proceeds. This is what the app builder runs, and by default
+    /// it just runs `environment_hook`.
+    async fn environment_hook_async(
+        &self,
+        args: &Self::Args,
+        env: &mut MononokeEnvironment,
+    ) -> Result<()> {
+        self.environment_hook(args, env)
+    }
+
+    /// Hook executed before creating the log drain, returning the level to filter it at. An
+    /// extension that filters some records more finely in `log_drain_hook` can lower it, so that
+    /// those records reach its drain. This only applies to the log drain: glog, the config store
+    /// and the observability context of the environment keep the level from the arguments.
+    fn log_level_hook(&self, _args: &Self::Args, level: Level) -> Level {
+        level
+    }
+
     /// Hook executed after creating the log drain allowing for augmenting the logging.
     fn log_drain_hook(
         &self,
@@ -46,6 +81,8 @@ pub trait AppExtension: Send + Sync + 'static {
 
 // Internal trait to hide the concrete extension type.
 pub(crate) trait BoxedAppExtension: Send + Sync + 'static {
+    fn name(&self) -> &'static str;
+    fn conflicts_with(&self) -> &[&str];
     fn augment_args<'help>(&self, app: Command<'help>) -> Command<'help>;
     fn arg_defaults(&self) -> Vec<(&'static str, String)>;
     fn parse_args(&self, args: &ArgMatches) -> Result<Box<dyn BoxedAppExtensionArgs>>;
@@ -64,6 +101,14 @@ impl<Ext: AppExtension> AppExtensionBox<Ext> {
 }
 
 impl<Ext: AppExtension> BoxedAppExtension for AppExtensionBox<Ext> {
+    fn name(&self) -> &'static str {
+        self.ext.name()
+    }
+
+    fn conflicts_with(&self) -> &[&str] {
+        self.ext.conflicts_with()
+    }
+
     fn augment_args<'help>(&self, command: Command<'help>) -> Command<'help> {
         Ext::Args::augment_args_for_update(command)
     }
@@ -92,8 +137,10 @@ impl<T: Any> Downcast for T {
 }
 
 // Internal trait to hide the concrete extension args type.
+#[async_trait]
 pub(crate) trait BoxedAppExtensionArgs: Downcast + Send + Sync + 'static {
-    fn environment_hook(&self, env: &mut MononokeEnvironment) -> Result<()>;
+    async fn environment_hook(&self, env: &mut MononokeEnvironment) -> Result<()>;
+    fn log_level_hook(&self, level: Level) -> Level;
     fn log_drain_hook(
         &self,
         drain: Arc<dyn SendSyncRefUnwindSafeDrain<Ok = (), Err = Never>>,
@@ -112,9 +159,14 @@ impl<Ext: AppExtension> AppExtensionArgsBox<Ext> {
     }
 }
 
+#[async_trait]
 impl<Ext: AppExtension> BoxedAppExtensionArgs for AppExtensionArgsBox<Ext> {
-    fn environment_hook(&self, env: &mut MononokeEnvironment) -> Result<()> {
-        self.ext.environment_hook(&self.args, env)
+    async fn environment_hook(&self, env: &mut MononokeEnvironment) -> Result<()> {
+        self.ext.environment_hook_async(&self.args, env).await
+    }
+
+    fn log_level_hook(&self, level: Level) -> Level {
+        self.ext.log_level_hook(&self.args, level)
     }
 
     fn log_drain_hook(
@@ -74,6 +74,7 @@ use sql_ext::facebook::PoolConfig;
 use sql_ext::facebook::ReadConnectionType;
 use tokio::runtime::Handle;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 use tunables::init_tunables_worker;
 use tunables::tunables;
 
@@ -237,6 +238,7 @@ impl<'a> MononokeMatches<'a> {
                 skiplist_enabled: true,
                 warm_bookmarks_cache_derived_data: None,
                 filter_repos: None,
+                shutdown: CancellationToken::new(),
             }),
             app_data,
         })
@@ -14,6 +14,7 @@ use std::str;
 
 use anyhow::anyhow;
 use anyhow::Context;
+use anyhow::Error;
 use anyhow::Result;
 use cached_config::ConfigHandle;
 use cached_config::ConfigStore;
@@ -87,10 +88,37 @@ pub fn load_repo_configs(
     load_configs_from_raw(raw_config).map(|(repo_configs, _)| repo_configs)
 }
 
+/// Load configuration for repositories and storage, leaving out the repos whose config is
+/// invalid. Those are returned along with the reason they were left out.
+pub fn load_repo_configs_skipping_invalid(
+    config_path: impl AsRef<Path>,
+    config_store: &ConfigStore,
+) -> Result<(RepoConfigs, Vec<(String, Error)>)> {
+    let raw_config = crate::raw::read_raw_configs(config_path.as_ref(), config_store)?;
+    load_configs_from_raw_skipping_invalid(raw_config)
+        .map(|(repo_configs, _, skipped)| (repo_configs, skipped))
+}
+
 /// Load configuration based on the provided raw configs.
 pub fn load_configs_from_raw(
     raw_repo_configs: RawRepoConfigs,
 ) -> Result<(RepoConfigs, StorageConfigs)> {
+    load_configs_from_raw_impl(raw_repo_configs, false)
+        .map(|(repo_configs, storage_configs, _)| (repo_configs, storage_configs))
+}
+
+/// Load configuration based on the provided raw configs, leaving out the repos whose config is
+/// invalid. Those are returned along with the reason they were left out.
+pub fn load_configs_from_raw_skipping_invalid(
+    raw_repo_configs: RawRepoConfigs,
+) -> Result<(RepoConfigs, StorageConfigs, Vec<(String, Error)>)> {
+    load_configs_from_raw_impl(raw_repo_configs, true)
+}
+
+fn load_configs_from_raw_impl(
+    raw_repo_configs: RawRepoConfigs,
+    skip_invalid: bool,
+) -> Result<(RepoConfigs, StorageConfigs, Vec<(String, Error)>)> {
     let RawRepoConfigs {
         commit_sync: _,
         common,
@@ -105,14 +133,22 @@ pub fn load_configs_from_raw(
 
     let mut resolved_repo_configs = HashMap::new();
     let mut repoids = HashSet::new();
+    let mut skipped = Vec::new();
 
     for (reponame, raw_repo_definition) in repo_definitions.into_iter() {
-        let repo_config = parse_with_repo_definition(
+        let repo_config = match parse_with_repo_definition(
             raw_repo_definition,
             &repo_configs,
             &storage_configs,
             &acl_region_configs,
-        )?;
+        ) {
+            Ok(repo_config) => repo_config,
+            Err(e) if skip_invalid => {
+                skipped.push((reponame, e));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
 
         if !repoids.insert(repo_config.repoid) {
             return Err(ConfigurationError::DuplicatedRepoId(repo_config.repoid).into());
@@ -132,6 +168,7 @@ pub fn load_configs_from_raw(
             common,
         },
         StorageConfigs { storage },
+        skipped,
     ))
 }
 
@@ -771,6 +808,69 @@ mod test {
         assert!(msg.contains("DuplicatedRepoId"));
     }
 
+    #[test]
+    fn test_skipping_invalid_repo_configs() {
+        let storage_content = r#"
+            [files.metadata.local]
+            local_db_path = "/tmp/www"
+
+            [files.blobstore.blob_files]
+            path = "/tmp/www"
+        "#;
+        let common_content = r#"
+            [redaction_config]
+            blobstore = "files"
+            redaction_sets_location = "loc"
+
+            [internal_identity]
+            identity_type = "SERVICE_IDENTITY"
+            identity_data = "internal"
+        "#;
+        let www_content = r#"
+            storage_config="files"
+        "#;
+        let broken_content = r#"
+            storage_config="missing"
+        "#;
+
+        let www_repo_def = r#"
+            repo_id=1
+            repo_name="www"
+            repo_config="www"
+        "#;
+
+        let broken_repo_def = r#"
+            repo_id=2
+            repo_name="broken"
+            repo_config="broken"
+        "#;
+
+        let paths = btreemap! {
+            "common/storage.toml" => storage_content,
+            "common/common.toml" => common_content,
+            "common/commitsyncmap.toml" => "",
+            "repos/www/server.toml" => www_content,
+            "repos/broken/server.toml" => broken_content,
+            "repo_definitions/www/server.toml" => www_repo_def,
+            "repo_definitions/broken/server.toml" => broken_repo_def,
+        };
+
+        let config_store = ConfigStore::new(Arc::new(TestSource::new()), None, None);
+        let tmp_dir = write_files(&paths);
+        assert!(load_repo_configs(tmp_dir.path(), &config_store).is_err());
+
+        let (repo_configs, skipped) =
+            load_repo_configs_skipping_invalid(tmp_dir.path(), &config_store)
+                .expect("Read configs failed");
+        assert_eq!(
+            repo_configs.repos.keys().collect::<Vec<_>>(),
+            vec![&"www".to_string()]
+        );
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, "broken");
+        assert!(format!("{:#}", skipped[0].1).contains("Storage \"missing\" not defined"));
+    }
+
     #[test]
     fn test_read_manifest() {
         let fbsource_content = r#"
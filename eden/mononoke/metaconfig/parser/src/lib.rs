@@ -18,6 +18,7 @@ pub use convert::Convert;
 
 pub use crate::config::load_common_config;
 pub use crate::config::load_repo_configs;
+pub use crate::config::load_repo_configs_skipping_invalid;
 pub use crate::config::load_storage_configs;
 pub use crate::config::RepoConfigs;
 pub use crate::config::StorageConfigs;
@@ -0,0 +1,153 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fmt;
+use std::num::NonZeroU32;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use blobstore::BlobstoreIsPresent;
+use bytes::Bytes;
+use context::CoreContext;
+use futures::channel::mpsc::Sender;
+use futures::sink::SinkExt;
+use governor::clock::DefaultClock;
+use governor::state::direct::NotKeyed;
+use governor::state::InMemoryState;
+use governor::Quota;
+use governor::RateLimiter;
+use microwave::is_redacted;
+use mononoke_types::BlobstoreBytes;
+
+pub type ReadLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+
+pub fn new_read_limiter(qps: NonZeroU32) -> Arc<ReadLimiter> {
+    Arc::new(RateLimiter::direct(Quota::per_second(qps)))
+}
+
+/// A Blobstore that can limit the rate of reads, using a limiter that may be shared with other
+/// blobstores, and record the keys of the blobs that are read. Both apply to all reads made
+/// through it, including those served from cache. Reads of redacted blobs still fail, but their
+/// keys are recorded separately. The size of the blobs that are read is added up as well.
+#[derive(Debug)]
+pub struct MicrowaveBlobstore {
+    limiter: Option<Arc<ReadLimiter>>,
+    bytes_read: Option<Arc<AtomicU64>>,
+    recorder: Option<Sender<(String, Option<Bytes>)>>,
+    redacted_recorder: Option<Sender<String>>,
+    capture_bytes: bool,
+    inner: Arc<dyn Blobstore>,
+}
+
+impl MicrowaveBlobstore {
+    pub fn new(inner: Arc<dyn Blobstore>) -> Self {
+        Self {
+            limiter: None,
+            bytes_read: None,
+            recorder: None,
+            redacted_recorder: None,
+            capture_bytes: false,
+            inner,
+        }
+    }
+
+    pub fn with_limiter(self, limiter: Arc<ReadLimiter>) -> Self {
+        Self {
+            limiter: Some(limiter),
+            ..self
+        }
+    }
+
+    /// Add the size of each blob that is found to `bytes_read`.
+    pub fn with_bytes_read(self, bytes_read: Arc<AtomicU64>) -> Self {
+        Self {
+            bytes_read: Some(bytes_read),
+            ..self
+        }
+    }
+
+    /// Send the key of each blob that is found to `recorder`, along with its contents if
+    /// `capture_bytes` is set. The keys of redacted blobs are sent to `redacted_recorder`.
+    pub fn with_recorder(
+        self,
+        recorder: Sender<(String, Option<Bytes>)>,
+        redacted_recorder: Sender<String>,
+        capture_bytes: bool,
+    ) -> Self {
+        Self {
+            recorder: Some(recorder),
+            redacted_recorder: Some(redacted_recorder),
+            capture_bytes,
+            ..self
+        }
+    }
+
+    async fn wait_for_limiter(&self) {
+        if let Some(limiter) = self.limiter.as_ref() {
+            limiter.until_ready().await;
+        }
+    }
+}
+
+impl fmt::Display for MicrowaveBlobstore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MicrowaveBlobstore<{}>", &self.inner)
+    }
+}
+
+#[async_trait]
+impl Blobstore for MicrowaveBlobstore {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        self.wait_for_limiter().await;
+        let data = match self.inner.get(ctx, key).await {
+            Ok(data) => data,
+            Err(e) => {
+                if let (Some(recorder), true) = (self.redacted_recorder.as_ref(), is_redacted(&e)) {
+                    recorder.clone().send(key.to_string()).await?;
+                }
+                return Err(e);
+            }
+        };
+
+        if let (Some(bytes_read), Some(data)) = (self.bytes_read.as_ref(), data.as_ref()) {
+            bytes_read.fetch_add(data.as_raw_bytes().len() as u64, Ordering::Relaxed);
+        }
+        if let (Some(recorder), Some(data)) = (self.recorder.as_ref(), data.as_ref()) {
+            let bytes = self.capture_bytes.then(|| data.as_raw_bytes().clone());
+            recorder.clone().send((key.to_string(), bytes)).await?;
+        }
+
+        Ok(data)
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        self.inner.put(ctx, key, value).await
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        self.wait_for_limiter().await;
+        self.inner.is_present(ctx, key).await
+    }
+}
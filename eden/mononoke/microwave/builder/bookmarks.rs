@@ -0,0 +1,97 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bookmarks::Bookmark;
+use bookmarks::BookmarkKind;
+use bookmarks::BookmarkName;
+use bookmarks::BookmarkPagination;
+use bookmarks::BookmarkPrefix;
+use bookmarks::BookmarkTransaction;
+use bookmarks::Bookmarks;
+use bookmarks::BookmarksSubscription;
+use bookmarks::Freshness;
+use context::CoreContext;
+use futures::channel::mpsc::Sender;
+use futures::future::BoxFuture;
+use futures::sink::SinkExt;
+use futures::stream::BoxStream;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use mononoke_types::ChangesetId;
+
+#[derive(Clone)]
+pub struct MicrowaveBookmarks {
+    recorder: Sender<(Bookmark, ChangesetId)>,
+    inner: Arc<dyn Bookmarks>,
+}
+
+impl MicrowaveBookmarks {
+    pub fn new(recorder: Sender<(Bookmark, ChangesetId)>, inner: Arc<dyn Bookmarks>) -> Self {
+        Self { recorder, inner }
+    }
+}
+
+#[async_trait]
+impl Bookmarks for MicrowaveBookmarks {
+    fn get(
+        &self,
+        ctx: CoreContext,
+        name: &BookmarkName,
+    ) -> BoxFuture<'static, Result<Option<ChangesetId>>> {
+        // We don't learn the kind of the bookmark here, so there is nothing we can record.
+        self.inner.get(ctx, name)
+    }
+
+    fn list(
+        &self,
+        ctx: CoreContext,
+        freshness: Freshness,
+        prefix: &BookmarkPrefix,
+        kinds: &[BookmarkKind],
+        pagination: &BookmarkPagination,
+        limit: u64,
+    ) -> BoxStream<'static, Result<(Bookmark, ChangesetId)>> {
+        let recorder = self.recorder.clone();
+
+        self.inner
+            .list(ctx, freshness, prefix, kinds, pagination, limit)
+            .and_then(move |(bookmark, cs_id)| {
+                let mut recorder = recorder.clone();
+                async move {
+                    recorder.send((bookmark.clone(), cs_id)).await?;
+                    Ok((bookmark, cs_id))
+                }
+            })
+            .boxed()
+    }
+
+    fn create_transaction(&self, _ctx: CoreContext) -> Box<dyn BookmarkTransaction> {
+        // See rationale in filenodes.rs for why we error out on unexpected calls under
+        // MicrowaveFilenodes.
+        unimplemented!("MicrowaveBookmarks: unexpected create_transaction")
+    }
+
+    async fn create_subscription(
+        &self,
+        ctx: &CoreContext,
+        freshness: Freshness,
+    ) -> Result<Box<dyn BookmarksSubscription>> {
+        self.inner.create_subscription(ctx, freshness).await
+    }
+
+    fn drop_caches(&self) {
+        self.inner.drop_caches()
+    }
+
+    fn prime_cache(&self, ctx: &CoreContext, bookmarks: &[(Bookmark, ChangesetId)]) {
+        self.inner.prime_cache(ctx, bookmarks)
+    }
+}
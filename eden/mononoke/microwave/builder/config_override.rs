@@ -0,0 +1,165 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::str::FromStr;
+
+use anyhow::bail;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use bookmarks::BookmarkName;
+use metaconfig_types::CacheWarmupParams;
+use metaconfig_types::RepoConfig;
+
+use crate::DEFAULT_WARMUP_COMMIT_LIMIT;
+
+/// The fields that `--repo-config-override` accepts. Only fields that affect warmup are listed,
+/// so that a typo cannot change something unrelated.
+const OVERRIDABLE_FIELDS: &[&str] = &[
+    "cache_warmup.bookmark",
+    "cache_warmup.commit_limit",
+    "cache_warmup.microwave_preload",
+    "generation_cache_size",
+];
+
+/// A change to a field of the repo configs, made in memory to try out a config change before it
+/// lands, parsed from `KEY=VALUE`.
+#[derive(Debug, Clone)]
+pub enum RepoConfigOverride {
+    CacheWarmupBookmark(BookmarkName),
+    CacheWarmupCommitLimit(usize),
+    CacheWarmupMicrowavePreload(bool),
+    GenerationCacheSize(usize),
+}
+
+impl FromStr for RepoConfigOverride {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format_err!("Expected KEY=VALUE, got {}", s))?;
+        let invalid = || format!("Invalid value {} for {}", value, key);
+        Ok(match key {
+            "cache_warmup.bookmark" => {
+                Self::CacheWarmupBookmark(value.parse().with_context(invalid)?)
+            }
+            "cache_warmup.commit_limit" => {
+                Self::CacheWarmupCommitLimit(value.parse().with_context(invalid)?)
+            }
+            "cache_warmup.microwave_preload" => {
+                Self::CacheWarmupMicrowavePreload(value.parse().with_context(invalid)?)
+            }
+            "generation_cache_size" => {
+                Self::GenerationCacheSize(value.parse().with_context(invalid)?)
+            }
+            _ => bail!(
+                "{} cannot be overridden, expected one of: {}",
+                key,
+                OVERRIDABLE_FIELDS.join(", ")
+            ),
+        })
+    }
+}
+
+fn cache_warmup_mut(config: &mut RepoConfig) -> Result<&mut CacheWarmupParams, Error> {
+    config.cache_warmup.as_mut().ok_or_else(|| {
+        Error::msg("The repo has no cache warmup config, override cache_warmup.bookmark first")
+    })
+}
+
+impl RepoConfigOverride {
+    /// Apply this override to `config`, and describe the value it now has.
+    pub fn apply(&self, config: &mut RepoConfig) -> Result<String, Error> {
+        Ok(match self {
+            Self::CacheWarmupBookmark(bookmark) => {
+                match config.cache_warmup.as_mut() {
+                    Some(params) => params.bookmark = bookmark.clone(),
+                    None => {
+                        config.cache_warmup = Some(CacheWarmupParams {
+                            bookmark: bookmark.clone(),
+                            commit_limit: DEFAULT_WARMUP_COMMIT_LIMIT,
+                            microwave_preload: false,
+                        })
+                    }
+                }
+                format!("cache_warmup.bookmark={}", bookmark)
+            }
+            Self::CacheWarmupCommitLimit(commit_limit) => {
+                cache_warmup_mut(config)?.commit_limit = *commit_limit;
+                format!("cache_warmup.commit_limit={}", commit_limit)
+            }
+            Self::CacheWarmupMicrowavePreload(microwave_preload) => {
+                cache_warmup_mut(config)?.microwave_preload = *microwave_preload;
+                format!("cache_warmup.microwave_preload={}", microwave_preload)
+            }
+            Self::GenerationCacheSize(generation_cache_size) => {
+                config.generation_cache_size = *generation_cache_size;
+                format!("generation_cache_size={}", generation_cache_size)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_str() -> Result<(), Error> {
+        assert!(matches!(
+            "cache_warmup.bookmark=main".parse::<RepoConfigOverride>()?,
+            RepoConfigOverride::CacheWarmupBookmark(bookmark) if bookmark.as_str() == "main"
+        ));
+        assert!(matches!(
+            "cache_warmup.commit_limit=10".parse::<RepoConfigOverride>()?,
+            RepoConfigOverride::CacheWarmupCommitLimit(10)
+        ));
+        assert!(matches!(
+            "cache_warmup.microwave_preload=true".parse::<RepoConfigOverride>()?,
+            RepoConfigOverride::CacheWarmupMicrowavePreload(true)
+        ));
+        assert!(matches!(
+            "generation_cache_size=5".parse::<RepoConfigOverride>()?,
+            RepoConfigOverride::GenerationCacheSize(5)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_str_invalid() {
+        assert!("cache_warmup.bookmark"
+            .parse::<RepoConfigOverride>()
+            .is_err());
+        assert!("cache_warmup.commit_limit=many"
+            .parse::<RepoConfigOverride>()
+            .is_err());
+        assert!("cache_warmup.microwave_preload=yes"
+            .parse::<RepoConfigOverride>()
+            .is_err());
+        assert!("repoid=1".parse::<RepoConfigOverride>().is_err());
+    }
+
+    #[test]
+    fn test_apply() -> Result<(), Error> {
+        let mut config = RepoConfig::default();
+        assert!(RepoConfigOverride::CacheWarmupCommitLimit(10)
+            .apply(&mut config)
+            .is_err());
+
+        let bookmark = "cache_warmup.bookmark=main".parse::<RepoConfigOverride>()?;
+        assert_eq!(bookmark.apply(&mut config)?, "cache_warmup.bookmark=main");
+        let limit = "cache_warmup.commit_limit=10".parse::<RepoConfigOverride>()?;
+        assert_eq!(limit.apply(&mut config)?, "cache_warmup.commit_limit=10");
+        let params = config.cache_warmup.unwrap();
+        assert_eq!(params.bookmark.as_str(), "main");
+        assert_eq!(params.commit_limit, 10);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use mercurial_types::HgFileNodeId;
+use microwave::Snapshot;
+use mononoke_app::MononokeApp;
+use mononoke_types::RepoPath;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct DiffEntry {
+    /// `file`, `directory` or `root`
+    kind: &'static str,
+    path: String,
+    filenode: String,
+}
+
+impl DiffEntry {
+    fn new((path, filenode): &(RepoPath, HgFileNodeId)) -> Self {
+        let kind = match path {
+            RepoPath::RootPath => "root",
+            RepoPath::DirectoryPath(_) => "directory",
+            RepoPath::FilePath(_) => "file",
+        };
+        Self {
+            kind,
+            path: path.mpath().map_or_else(String::new, |p| p.to_string()),
+            filenode: filenode.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SnapshotDiff {
+    filenodes_a: usize,
+    filenodes_b: usize,
+    paths_a: usize,
+    paths_b: usize,
+    only_in_a: Vec<DiffEntry>,
+    only_in_b: Vec<DiffEntry>,
+}
+
+async fn filenode_set(
+    app: &MononokeApp,
+    path: &Path,
+) -> Result<HashSet<(RepoPath, HgFileNodeId)>, Error> {
+    let snapshot = Snapshot::read_file(&app.new_basic_context(), path)
+        .await
+        .with_context(|| format!("Failed to read snapshot {}", path.display()))?
+        .ok_or_else(|| format_err!("{} has no header, rebuild it to compare it", path.display()))?;
+    if snapshot.is_delta() {
+        // A delta only holds what changed since its base, so comparing it would be misleading.
+        bail!("{} is a delta snapshot", path.display());
+    }
+    Ok(snapshot
+        .filenodes()?
+        .into_iter()
+        .map(|filenode| (filenode.path, filenode.info.filenode))
+        .collect())
+}
+
+/// Compare the filenodes in the snapshots at `a` and `b`, and print the filenodes that are only
+/// in one of them along with summary counts, as JSON if `json` is set.
+pub async fn diff_snapshots(
+    app: &MononokeApp,
+    a: &Path,
+    b: &Path,
+    json: bool,
+) -> Result<(), Error> {
+    let (filenodes_a, filenodes_b) =
+        futures::try_join!(filenode_set(app, a), filenode_set(app, b))?;
+    let count_paths = |filenodes: &HashSet<(RepoPath, HgFileNodeId)>| {
+        filenodes
+            .iter()
+            .map(|(path, _)| path)
+            .collect::<HashSet<_>>()
+            .len()
+    };
+    let only_in = |this: &HashSet<_>, other: &HashSet<_>| {
+        let mut entries = this
+            .difference(other)
+            .map(DiffEntry::new)
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by(|a, b| {
+            (&a.path, a.kind, &a.filenode).cmp(&(&b.path, b.kind, &b.filenode))
+        });
+        entries
+    };
+
+    let diff = SnapshotDiff {
+        filenodes_a: filenodes_a.len(),
+        filenodes_b: filenodes_b.len(),
+        paths_a: count_paths(&filenodes_a),
+        paths_b: count_paths(&filenodes_b),
+        only_in_a: only_in(&filenodes_a, &filenodes_b),
+        only_in_b: only_in(&filenodes_b, &filenodes_a),
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+
+    for entry in &diff.only_in_a {
+        println!("- {} {} {}", entry.kind, entry.path, entry.filenode);
+    }
+    for entry in &diff.only_in_b {
+        println!("+ {} {} {}", entry.kind, entry.path, entry.filenode);
+    }
+    println!(
+        "{}: {} filenodes across {} paths, {} only in this snapshot",
+        a.display(),
+        diff.filenodes_a,
+        diff.paths_a,
+        diff.only_in_a.len()
+    );
+    println!(
+        "{}: {} filenodes across {} paths, {} only in this snapshot",
+        b.display(),
+        diff.filenodes_b,
+        diff.paths_b,
+        diff.only_in_b.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,193 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::time::Duration;
+
+use anyhow::Error;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MicrowaveError {
+    #[error("Failed to read configs")]
+    ConfigRead(#[source] Error),
+    #[error("Failed to open repo {repo}")]
+    BlobrepoOpen {
+        repo: String,
+        #[source]
+        source: Error,
+    },
+    #[error("Cache warmup failed for repo {repo}")]
+    Warmup {
+        repo: String,
+        #[source]
+        source: Error,
+    },
+    #[error("Failed to commit snapshot for repo {repo}")]
+    SnapshotCommit {
+        repo: String,
+        #[source]
+        source: Error,
+    },
+    #[error("Building snapshot for repo {repo} timed out after {elapsed:?}")]
+    Timeout { repo: String, elapsed: Duration },
+    #[error("Cache warmup stalled for repo {repo}: no filenodes were recorded for {timeout:?}")]
+    Stalled { repo: String, timeout: Duration },
+    #[error("Snapshot verification failed")]
+    Verify(#[source] Error),
+    #[error("Self-test failed")]
+    Selftest(#[source] Error),
+    #[error("Snapshot garbage collection failed")]
+    Gc(#[source] Error),
+    #[error("Failed to merge snapshots")]
+    Merge(#[source] Error),
+    #[error("Failed to diff snapshots")]
+    Diff(#[source] Error),
+    #[error("Failed to compute snapshot stats")]
+    Stats(#[source] Error),
+    #[error("Failed to touch snapshots")]
+    Touch(#[source] Error),
+    #[error("Cancelled")]
+    Cancelled,
+    #[error("Failed to write run summary")]
+    Output(#[source] Error),
+    #[error(
+        "Failed to build snapshots for {} repos: {}",
+        .0.len(),
+        .0.iter().map(|(repo, _)| repo.as_str()).collect::<Vec<_>>().join(", ")
+    )]
+    RepoFailures(Vec<(String, MicrowaveError)>),
+    #[error(
+        "Circuit open after {consecutive} repos failed in a row, {} repos were not tried: {}",
+        .untried.len(),
+        .untried.join(", ")
+    )]
+    CircuitOpen {
+        consecutive: usize,
+        untried: Vec<String>,
+    },
+}
+
+/// Returned by the build when no filenodes were recorded for this long, so that it can be told
+/// apart from other warmup failures.
+#[derive(Debug, Error)]
+#[error("Warmup stalled: no filenodes were recorded for {0:?}")]
+pub(crate) struct WarmupStalled(pub(crate) Duration);
+
+const EXIT_CODE_FAILURE: i32 = 1;
+const EXIT_CODE_CONFIG: i32 = 3;
+const EXIT_CODE_BLOBREPO_OPEN: i32 = 4;
+const EXIT_CODE_WARMUP: i32 = 5;
+const EXIT_CODE_SNAPSHOT_COMMIT: i32 = 6;
+const EXIT_CODE_TIMEOUT: i32 = 7;
+const EXIT_CODE_CIRCUIT_OPEN: i32 = 8;
+const EXIT_CODE_CANCELLED: i32 = 130;
+
+impl MicrowaveError {
+    /// The process exit code for this failure, so that monitoring can tell failures apart:
+    ///
+    /// | Code | Failure                                           |
+    /// |------|---------------------------------------------------|
+    /// | 0    | None                                              |
+    /// | 1    | Anything else, or repos failing in different ways |
+    /// | 3    | Invalid configs or arguments                      |
+    /// | 4    | A repo could not be opened                        |
+    /// | 5    | Cache warmup failed                               |
+    /// | 6    | A snapshot could not be committed                 |
+    /// | 7    | Building a snapshot timed out or stalled          |
+    /// | 8    | The `--failure-threshold` circuit breaker tripped |
+    /// | 124  | The `--max-runtime-secs` deadline was exceeded    |
+    /// | 130  | Cancelled by a signal                             |
+    ///
+    /// When several repos fail, this is the code for the way they all failed, if they all
+    /// failed the same way. The deadline is not a `MicrowaveError`, and is handled in `main`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::ConfigRead(_) => EXIT_CODE_CONFIG,
+            Self::BlobrepoOpen { .. } => EXIT_CODE_BLOBREPO_OPEN,
+            Self::Warmup { .. } => EXIT_CODE_WARMUP,
+            Self::SnapshotCommit { .. } => EXIT_CODE_SNAPSHOT_COMMIT,
+            Self::Timeout { .. } | Self::Stalled { .. } => EXIT_CODE_TIMEOUT,
+            Self::CircuitOpen { .. } => EXIT_CODE_CIRCUIT_OPEN,
+            Self::Cancelled => EXIT_CODE_CANCELLED,
+            Self::RepoFailures(failures) => {
+                let mut codes = failures.iter().map(|(_, e)| e.exit_code());
+                match codes.next() {
+                    Some(code) if codes.all(|other| other == code) => code,
+                    _ => EXIT_CODE_FAILURE,
+                }
+            }
+            Self::Verify(_)
+            | Self::Selftest(_)
+            | Self::Gc(_)
+            | Self::Merge(_)
+            | Self::Diff(_)
+            | Self::Stats(_)
+            | Self::Touch(_)
+            | Self::Output(_) => EXIT_CODE_FAILURE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warmup(repo: &str) -> MicrowaveError {
+        MicrowaveError::Warmup {
+            repo: repo.to_string(),
+            source: Error::msg("failed"),
+        }
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(
+            MicrowaveError::ConfigRead(Error::msg("failed")).exit_code(),
+            EXIT_CODE_CONFIG
+        );
+        assert_eq!(warmup("repo").exit_code(), EXIT_CODE_WARMUP);
+        assert_eq!(
+            MicrowaveError::Stalled {
+                repo: "repo".to_string(),
+                timeout: Duration::from_secs(1),
+            }
+            .exit_code(),
+            EXIT_CODE_TIMEOUT
+        );
+        assert_eq!(MicrowaveError::Cancelled.exit_code(), EXIT_CODE_CANCELLED);
+        assert_eq!(
+            MicrowaveError::Gc(Error::msg("failed")).exit_code(),
+            EXIT_CODE_FAILURE
+        );
+    }
+
+    #[test]
+    fn test_exit_code_repo_failures() {
+        let same = MicrowaveError::RepoFailures(vec![
+            ("a".to_string(), warmup("a")),
+            ("b".to_string(), warmup("b")),
+        ]);
+        assert_eq!(same.exit_code(), EXIT_CODE_WARMUP);
+
+        let different = MicrowaveError::RepoFailures(vec![
+            ("a".to_string(), warmup("a")),
+            (
+                "b".to_string(),
+                MicrowaveError::Timeout {
+                    repo: "b".to_string(),
+                    elapsed: Duration::from_secs(1),
+                },
+            ),
+        ]);
+        assert_eq!(different.exit_code(), EXIT_CODE_FAILURE);
+
+        assert_eq!(
+            MicrowaveError::RepoFailures(vec![]).exit_code(),
+            EXIT_CODE_FAILURE
+        );
+    }
+}
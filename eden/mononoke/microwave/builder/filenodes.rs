@@ -5,6 +5,10 @@
  * GNU General Public License version 2.
  */
 
+use std::hash::Hasher;
+use std::num::NonZeroU64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use anyhow::Result;
@@ -17,18 +21,134 @@ use filenodes::Filenodes;
 use filenodes::PreparedFilenode;
 use futures::channel::mpsc::Sender;
 use futures::sink::SinkExt;
+use globset::GlobSet;
 use mercurial_types::HgFileNodeId;
 use mononoke_types::RepoPath;
+use slog::debug;
+use twox_hash::XxHash64;
+
+/// Filenodes that were counted rather than recorded, along with the size they would take up in a
+/// snapshot.
+#[derive(Default)]
+pub struct FilenodeCounts {
+    pub count: AtomicU64,
+    pub bytes: AtomicU64,
+}
+
+/// A hasher that has been fed `path`. Unlike `DefaultHasher`, this hashes the same way across
+/// runs and builds, so that sampling the same repo state always records the same filenodes.
+fn path_hasher(path: &RepoPath) -> XxHash64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write_u8(match path {
+        RepoPath::RootPath => 0,
+        RepoPath::DirectoryPath(_) => 1,
+        RepoPath::FilePath(_) => 2,
+    });
+    if let Some(mpath) = path.mpath() {
+        hasher.write(&mpath.to_vec());
+    }
+    hasher
+}
 
 #[derive(Clone)]
 pub struct MicrowaveFilenodes {
-    recorder: Sender<PreparedFilenode>,
+    recorders: Vec<Sender<PreparedFilenode>>,
+    counts: Option<Arc<FilenodeCounts>>,
     inner: Arc<dyn Filenodes>,
+    trace: bool,
+    sample_rate: Option<NonZeroU64>,
+    exclude: Option<Arc<GlobSet>>,
+    excluded: Arc<AtomicU64>,
+    sizes: Option<Sender<HgFileNodeId>>,
 }
 
 impl MicrowaveFilenodes {
-    pub fn new(recorder: Sender<PreparedFilenode>, inner: Arc<dyn Filenodes>) -> Self {
-        Self { recorder, inner }
+    /// Filenodes are sharded across `recorders` by path, so that they can be consumed in
+    /// parallel. There must be at least one recorder.
+    pub fn new(recorders: Vec<Sender<PreparedFilenode>>, inner: Arc<dyn Filenodes>) -> Self {
+        assert!(!recorders.is_empty(), "MicrowaveFilenodes: no recorders");
+        Self {
+            recorders,
+            counts: None,
+            inner,
+            trace: false,
+            sample_rate: None,
+            exclude: None,
+            excluded: Arc::new(AtomicU64::new(0)),
+            sizes: None,
+        }
+    }
+
+    /// Only count the filenodes in `counts` instead of recording them, so that nothing is held
+    /// on to however many there are.
+    pub fn counting(counts: Arc<FilenodeCounts>, inner: Arc<dyn Filenodes>) -> Self {
+        Self {
+            recorders: Vec::new(),
+            counts: Some(counts),
+            inner,
+            trace: false,
+            sample_rate: None,
+            exclude: None,
+            excluded: Arc::new(AtomicU64::new(0)),
+            sizes: None,
+        }
+    }
+
+    /// Log each filenode as it is recorded, at debug level.
+    pub fn with_trace(self, trace: bool) -> Self {
+        Self { trace, ..self }
+    }
+
+    /// Only record 1 in `sample_rate` filenodes. Which ones are recorded only depends on their
+    /// path and hash, so sampling the same repo state twice records the same filenodes.
+    pub fn with_sample_rate(self, sample_rate: Option<NonZeroU64>) -> Self {
+        Self {
+            sample_rate,
+            ..self
+        }
+    }
+
+    /// Do not record the filenodes whose path matches `exclude`, and count them in `excluded`.
+    pub fn with_exclude(self, exclude: Option<Arc<GlobSet>>, excluded: Arc<AtomicU64>) -> Self {
+        Self {
+            exclude,
+            excluded,
+            ..self
+        }
+    }
+
+    /// For each file filenode that is recorded, also send its id to `recorder`, so that the size
+    /// of its contents can be resolved without holding up warmup.
+    pub fn with_sizes(self, recorder: Sender<HgFileNodeId>) -> Self {
+        Self {
+            sizes: Some(recorder),
+            ..self
+        }
+    }
+
+    fn is_excluded(&self, path: &RepoPath) -> bool {
+        let (exclude, mpath) = match (&self.exclude, path.mpath()) {
+            (Some(exclude), Some(mpath)) => (exclude, mpath),
+            _ => return false,
+        };
+        let excluded = exclude.is_match(&*String::from_utf8_lossy(&mpath.to_vec()));
+        if excluded {
+            self.excluded.fetch_add(1, Ordering::Relaxed);
+        }
+        excluded
+    }
+
+    fn is_sampled(&self, path: &RepoPath, filenode_id: HgFileNodeId) -> bool {
+        self.sample_rate.map_or(true, |rate| {
+            let mut hasher = path_hasher(path);
+            hasher.write(filenode_id.as_bytes());
+            hasher.finish() % rate.get() == 0
+        })
+    }
+
+    fn recorder(&self, path: &RepoPath) -> Sender<PreparedFilenode> {
+        let shard = (path_hasher(path).finish() % self.recorders.len() as u64) as usize;
+        self.recorders[shard].clone()
     }
 }
 
@@ -79,14 +199,34 @@ impl Filenodes for MicrowaveFilenodes {
             .await?
             .do_not_handle_disabled_filenodes()?;
 
-        if let Some(ref info) = info {
-            self.recorder
-                .clone()
-                .send(PreparedFilenode {
-                    path: path.clone(),
-                    info: info.clone(),
-                })
-                .await?;
+        let record =
+            info.is_some() && self.is_sampled(path, filenode_id) && !self.is_excluded(path);
+        if let (Some(info), true) = (info.as_ref(), record) {
+            if self.trace {
+                debug!(
+                    ctx.logger(),
+                    "Recording filenode {} for {}", filenode_id, path
+                );
+            }
+            let filenode = PreparedFilenode {
+                path: path.clone(),
+                info: info.clone(),
+            };
+            match &self.counts {
+                Some(counts) => {
+                    counts.count.fetch_add(1, Ordering::Relaxed);
+                    counts.bytes.fetch_add(
+                        microwave::filenode_serialized_size(&filenode),
+                        Ordering::Relaxed,
+                    );
+                }
+                None => {
+                    self.recorder(path).send(filenode).await?;
+                    if let (Some(recorder), RepoPath::FilePath(_)) = (&self.sizes, path) {
+                        recorder.clone().send(filenode_id).await?;
+                    }
+                }
+            }
         }
 
         Ok(FilenodeResult::Present(info))
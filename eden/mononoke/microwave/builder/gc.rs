@@ -0,0 +1,242 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use anyhow::Error;
+use blobstore::Blobstore;
+use blobstore::BlobstoreUnlinkOps;
+use blobstore_factory::make_blobstore_enumerable_with_unlink;
+use futures::future;
+use futures::stream;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use metaconfig_types::RepoConfig;
+use microwave::SnapshotMetadata;
+use mononoke_api_types::InnerRepo;
+use mononoke_app::MononokeApp;
+use prefixblob::PrefixBlobstore;
+use slog::info;
+use slog::warn;
+use slog::Logger;
+
+use crate::multiplex::physical_blobstore_configs;
+use crate::repo_context;
+
+/// Delete the snapshots stored in the repository blobstore with any of these key prefixes whose
+/// metadata is older than `older_than`. Snapshots without metadata are kept, and so are bases of
+/// deltas that are kept. Only the physical blobstores are modified (each inner store of a
+/// multiplex), so caches may still serve deleted snapshots until they expire.
+pub async fn gc_repos(
+    app: &MononokeApp,
+    repos: Vec<(String, RepoConfig)>,
+    key_prefixes: &[Option<&str>],
+    older_than: Duration,
+    dry_run: bool,
+    max_concurrent_repos: NonZeroUsize,
+    client_identity: &str,
+) -> Result<(), Error> {
+    let common_config = &app.repo_configs().common;
+    let cutoff = SystemTime::now()
+        .checked_sub(older_than)
+        .unwrap_or(UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+
+    let deleted = stream::iter(repos)
+        .map(|(name, config)| async move {
+            let ctx = repo_context(app, &name, client_identity);
+            let blobconfig = config.storage_config.blobstore.clone();
+            async {
+                let repo: InnerRepo = app
+                    .repo_factory()
+                    .build(name.clone(), config, common_config.clone())
+                    .await?;
+
+                let mut stale = Vec::new();
+                for key_prefix in key_prefixes {
+                    let snapshots =
+                        microwave::find_blobstore_snapshots(&ctx, &repo.blob_repo, *key_prefix)
+                            .await?;
+                    stale.extend(stale_snapshots(ctx.logger(), snapshots, cutoff));
+                }
+
+                if dry_run {
+                    for key in &stale {
+                        info!(ctx.logger(), "Would delete {}", key);
+                    }
+                    return Result::<_, Error>::Ok(stale.len());
+                }
+                if stale.is_empty() {
+                    return Ok(0);
+                }
+
+                // The repository blobstore does not support unlinking, so go through the physical
+                // blobstores, under the same prefix that the repository blobstore uses. Writes to
+                // a multiplex may have failed on some of its stores, and unlinking a missing key
+                // is an error, so only unlink keys that a store has.
+                for blobconfig in physical_blobstore_configs(blobconfig) {
+                    let blobstore = make_blobstore_enumerable_with_unlink(
+                        app.fb,
+                        blobconfig,
+                        &app.environment().blobstore_options,
+                        app.logger(),
+                    )
+                    .await?;
+                    let blobstore =
+                        PrefixBlobstore::new(blobstore, repo.blob_repo.get_repoid().prefix());
+                    for key in &stale {
+                        if blobstore
+                            .is_present(&ctx, key)
+                            .await?
+                            .assume_not_found_if_unsure()
+                        {
+                            blobstore.unlink(&ctx, key).await?;
+                        }
+                    }
+                }
+                for key in &stale {
+                    info!(ctx.logger(), "Deleted {}", key);
+                }
+
+                Ok(stale.len())
+            }
+            .await
+            .with_context(|| format!("repo {}", name))
+        })
+        .buffered(max_concurrent_repos.get())
+        .try_fold(0, |total, deleted| future::ready(Ok(total + deleted)))
+        .await?;
+
+    if dry_run {
+        info!(app.logger(), "Would delete {} stale snapshots", deleted);
+    } else {
+        info!(app.logger(), "Deleted {} stale snapshots", deleted);
+    }
+
+    Ok(())
+}
+
+/// Pick which of the snapshots stored under a single key prefix to delete. A delta can only be
+/// loaded on top of the base it was built on, so a stale base is kept while a delta built on it is
+/// kept, and a delta is deleted along with its base. Deltas come first, so that a gc interrupted
+/// part way never leaves a delta without its base.
+fn stale_snapshots(
+    logger: &Logger,
+    snapshots: Vec<(String, Option<SnapshotMetadata>)>,
+    cutoff: i64,
+) -> Vec<String> {
+    let (deltas, bases): (Vec<_>, Vec<_>) = snapshots.into_iter().partition(
+        |(_, metadata)| matches!(metadata, Some(metadata) if metadata.base_timestamp.is_some()),
+    );
+    let kept_delta_bases = deltas
+        .iter()
+        .filter_map(|(_, metadata)| metadata.as_ref())
+        .filter(|metadata| metadata.timestamp >= cutoff)
+        .filter_map(|metadata| metadata.base_timestamp)
+        .collect::<HashSet<_>>();
+
+    let mut stale_bases = Vec::new();
+    for (key, metadata) in bases {
+        match metadata {
+            Some(metadata) if metadata.timestamp >= cutoff => {}
+            Some(metadata) if kept_delta_bases.contains(&metadata.timestamp) => {
+                info!(
+                    logger,
+                    "Keeping {}: a delta that is kept is built on it", key
+                )
+            }
+            Some(_) => stale_bases.push(key),
+            None => warn!(logger, "Keeping {}: it has no metadata", key),
+        }
+    }
+
+    let mut stale = deltas
+        .into_iter()
+        .filter(|(_, metadata)| {
+            !stale_bases.is_empty()
+                || matches!(metadata, Some(metadata) if metadata.timestamp < cutoff)
+        })
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    stale.extend(stale_bases);
+    stale
+}
+
+#[cfg(test)]
+mod tests {
+    use mononoke_types::RepositoryId;
+    use slog::o;
+
+    use super::*;
+
+    fn metadata(timestamp: i64, base_timestamp: Option<i64>) -> Option<SnapshotMetadata> {
+        Some(SnapshotMetadata {
+            repo_id: RepositoryId::new(1),
+            timestamp,
+            filenodes_count: 0,
+            cache_hit_ratio: None,
+            base_timestamp,
+            truncated: false,
+            sample_rate: None,
+            builder_version: None,
+            hg_mapping_count: None,
+        })
+    }
+
+    fn stale(snapshots: Vec<(&str, Option<SnapshotMetadata>)>) -> Vec<String> {
+        let logger = Logger::root(slog::Discard, o!());
+        let snapshots = snapshots
+            .into_iter()
+            .map(|(key, metadata)| (key.to_string(), metadata))
+            .collect();
+        stale_snapshots(&logger, snapshots, 100)
+    }
+
+    #[test]
+    fn test_stale_snapshots() {
+        // Only snapshots older than the cutoff are deleted.
+        assert_eq!(
+            stale(vec![
+                ("base", metadata(50, None)),
+                ("other", metadata(150, None))
+            ]),
+            ["base"]
+        );
+        assert!(stale(vec![("base", None)]).is_empty());
+
+        // A stale delta can go on its own.
+        assert_eq!(
+            stale(vec![
+                ("base", metadata(150, None)),
+                ("delta", metadata(90, Some(150)))
+            ]),
+            ["delta"]
+        );
+
+        // A stale base is kept for a newer delta built on it.
+        assert!(stale(vec![
+            ("base", metadata(50, None)),
+            ("delta", metadata(150, Some(50)))
+        ])
+        .is_empty());
+
+        // A delta built on another base cannot be loaded once its base is gone.
+        assert_eq!(
+            stale(vec![
+                ("base", metadata(50, None)),
+                ("delta", metadata(150, Some(40)))
+            ]),
+            ["delta", "base"]
+        );
+    }
+}
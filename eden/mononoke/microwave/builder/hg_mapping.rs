@@ -0,0 +1,83 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::Arc;
+
+use anyhow::Error;
+use async_trait::async_trait;
+use bonsai_hg_mapping::BonsaiHgMapping;
+use bonsai_hg_mapping::BonsaiHgMappingEntry;
+use bonsai_hg_mapping::BonsaiOrHgChangesetIds;
+use cloned::cloned;
+use context::CoreContext;
+use futures::channel::mpsc::Sender;
+use futures::sink::SinkExt;
+use mercurial_types::HgChangesetId;
+use mononoke_types::RepositoryId;
+
+#[derive(Clone)]
+pub struct MicrowaveBonsaiHgMapping {
+    repo_id: RepositoryId,
+    recorder: Sender<BonsaiHgMappingEntry>,
+    inner: Arc<dyn BonsaiHgMapping>,
+}
+
+impl MicrowaveBonsaiHgMapping {
+    pub fn new(recorder: Sender<BonsaiHgMappingEntry>, inner: Arc<dyn BonsaiHgMapping>) -> Self {
+        Self {
+            repo_id: inner.repo_id(),
+            recorder,
+            inner,
+        }
+    }
+}
+
+#[async_trait]
+impl BonsaiHgMapping for MicrowaveBonsaiHgMapping {
+    fn repo_id(&self) -> RepositoryId {
+        self.repo_id
+    }
+
+    async fn add(&self, ctx: &CoreContext, entry: BonsaiHgMappingEntry) -> Result<bool, Error> {
+        // Unlike changesets, mappings are added when warmup derives hg changesets, and the
+        // server will look those up too.
+        cloned!(mut self.recorder);
+        let added = self.inner.add(ctx, entry.clone()).await?;
+        recorder.send(entry).await?;
+        Ok(added)
+    }
+
+    async fn get(
+        &self,
+        ctx: &CoreContext,
+        cs_id: BonsaiOrHgChangesetIds,
+    ) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
+        cloned!(mut self.recorder);
+
+        let entries = self.inner.get(ctx, cs_id).await?;
+
+        for entry in &entries {
+            recorder.send(entry.clone()).await?;
+        }
+
+        Ok(entries)
+    }
+
+    async fn get_hg_in_range(
+        &self,
+        ctx: &CoreContext,
+        low: HgChangesetId,
+        high: HgChangesetId,
+        limit: usize,
+    ) -> Result<Vec<HgChangesetId>, Error> {
+        self.inner.get_hg_in_range(ctx, low, high, limit).await
+    }
+
+    fn prime_cache(&self, ctx: &CoreContext, entries: &[BonsaiHgMappingEntry]) {
+        self.inner.prime_cache(ctx, entries)
+    }
+}
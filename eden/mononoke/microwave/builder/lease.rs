@@ -0,0 +1,76 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use cacheblob::LeaseOps;
+use context::CoreContext;
+use futures::future::BoxFuture;
+use slog::info;
+use slog::Logger;
+
+/// Wraps the derived data lease used during warmup, to log when data that warmup needs is being
+/// derived elsewhere. Derivation then backs off until the lease is released (and checks whether
+/// the data has been derived in the meantime) rather than deriving it a second time.
+#[derive(Debug)]
+pub struct MicrowaveLease {
+    inner: Arc<dyn LeaseOps>,
+    logger: Logger,
+    /// Keys whose lease is held elsewhere and that have already been logged.
+    waiting: Mutex<HashSet<String>>,
+}
+
+impl MicrowaveLease {
+    pub fn new(inner: Arc<dyn LeaseOps>, logger: Logger) -> Self {
+        Self {
+            inner,
+            logger,
+            waiting: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl fmt::Display for MicrowaveLease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MicrowaveLease({})", self.inner)
+    }
+}
+
+#[async_trait]
+impl LeaseOps for MicrowaveLease {
+    async fn try_add_put_lease(&self, key: &str) -> Result<bool> {
+        let acquired = self.inner.try_add_put_lease(key).await?;
+        let mut waiting = self.waiting.lock().expect("lock poisoned");
+        if acquired {
+            waiting.remove(key);
+        } else if waiting.insert(key.to_string()) {
+            // Derivation retries until the lease is released, so only log the first attempt.
+            info!(
+                self.logger,
+                "Derived data lease {} is held elsewhere, waiting for it instead of deriving", key
+            );
+        }
+        Ok(acquired)
+    }
+
+    fn renew_lease_until(&self, ctx: CoreContext, key: &str, done: BoxFuture<'static, ()>) {
+        self.inner.renew_lease_until(ctx, key, done)
+    }
+
+    async fn wait_for_other_leases(&self, key: &str) {
+        self.inner.wait_for_other_leases(key).await
+    }
+
+    async fn release_lease(&self, key: &str) {
+        self.inner.release_lease(key).await
+    }
+}
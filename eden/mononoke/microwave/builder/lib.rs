@@ -0,0 +1,684 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Build microwave snapshots by recording what a cache warmup reads. This is what the builder
+//! binary runs for each repo, and can be used directly for a repo that is already open.
+
+mod blobstore;
+mod bookmarks;
+mod changesets;
+mod errors;
+mod filenodes;
+mod hg_mapping;
+mod lease;
+
+use std::num::NonZeroU64;
+use std::num::NonZeroUsize;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use ::blobstore::Blobstore;
+use ::blobstore::Loadable;
+use ::bookmarks::ArcBookmarks;
+use ::bookmarks::BookmarkName;
+use ::bookmarks::BookmarksMaybeStaleExt;
+use ::bookmarks::BookmarksRef;
+use ::changesets::ArcChangesets;
+use ::filenodes::ArcFilenodes;
+use anyhow::Error;
+use blobrepo::BlobRepo;
+use blobrepo_override::DangerousOverride;
+use bonsai_hg_mapping::ArcBonsaiHgMapping;
+use cache_warmup::CacheWarmupRequest;
+use cacheblob::LeaseOps;
+use context::CoreContext;
+use futures::channel::mpsc;
+use futures::future;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use globset::GlobSet;
+use mercurial_types::HgFileNodeId;
+use microwave::BuildOptions;
+use microwave::Snapshot;
+use microwave::SnapshotFormat;
+use microwave::SnapshotLocation;
+use microwave::SnapshotObserver;
+use slog::info;
+use slog::warn;
+use tokio::task::JoinHandle;
+
+pub use crate::blobstore::new_read_limiter;
+use crate::blobstore::MicrowaveBlobstore;
+pub use crate::blobstore::ReadLimiter;
+use crate::bookmarks::MicrowaveBookmarks;
+use crate::changesets::MicrowaveChangesets;
+pub use crate::errors::MicrowaveError;
+use crate::errors::WarmupStalled;
+use crate::filenodes::FilenodeCounts;
+use crate::filenodes::MicrowaveFilenodes;
+use crate::hg_mapping::MicrowaveBonsaiHgMapping;
+use crate::lease::MicrowaveLease;
+
+/// Number of envelopes read at once to resolve the content sizes of the recorded filenodes.
+const SIZES_CONCURRENCY: usize = 100;
+
+/// Options for `build_snapshot`.
+pub struct SnapshotOptions {
+    /// The cache warmup to record. If unset, the snapshot will be empty.
+    pub warmup: Option<CacheWarmupRequest>,
+    /// Number of filenodes that can be buffered while waiting to be added to the snapshot
+    pub filenodes_buffer_size: NonZeroUsize,
+    /// Number of channels to split filenodes across while they are being recorded
+    pub filenodes_shards: NonZeroUsize,
+    /// Also capture the changesets read during warmup
+    pub capture_changesets: bool,
+    /// Also capture the publishing bookmarks
+    pub capture_bookmarks: bool,
+    /// Also capture the bonsai to hg mappings read during warmup
+    pub capture_hg_mapping: bool,
+    /// How often to log progress while cache warmup is running
+    pub progress_interval: Option<Duration>,
+    /// Build a delta on top of the existing snapshot in the location, if there is one
+    pub incremental: bool,
+    /// Limit the blobstore reads made during warmup
+    pub read_limiter: Option<Arc<ReadLimiter>>,
+    /// Also capture the keys of the blobs read during warmup
+    pub capture_content: bool,
+    /// Capture the contents of those blobs along with their keys
+    pub capture_content_bytes: bool,
+    /// Also capture the content size of each file filenode that is recorded
+    pub capture_sizes: bool,
+    /// Only store each filenode once, at the cost of keeping track of all of them while building
+    pub dedupe: bool,
+    /// Log every filenode that is recorded, at debug level
+    pub trace_filenodes: bool,
+    /// Stop recording filenodes once this many have been recorded
+    pub max_filenodes: Option<usize>,
+    /// Stop recording once the recorded entries take up about this many bytes in memory
+    pub max_bytes: Option<u64>,
+    /// Only record 1 in this many filenodes, to estimate what a full snapshot would hold
+    pub sample_rate: Option<NonZeroU64>,
+    /// Do not record the filenodes whose path matches any of these globs
+    pub exclude_paths: Option<Arc<GlobSet>>,
+    /// Sort what is recorded, so that runs against the same repo state produce the same snapshot
+    pub deterministic: bool,
+    /// How the snapshot is serialized when it is committed
+    pub format: SnapshotFormat,
+    /// Derive data during warmup under this lease rather than the repo's own, so that warmups
+    /// on other hosts that share it do not derive the same data
+    pub derived_data_lease: Option<Arc<dyn LeaseOps>>,
+    /// Incremented with the size of each blob read during warmup, including reads that are
+    /// served from cache
+    pub blobstore_bytes_read: Arc<AtomicU64>,
+    /// If this bookmark has not moved since it was captured in the existing snapshot in the
+    /// location, skip warmup and reuse that whole snapshot instead, cache hit ratio included.
+    /// Otherwise warm up in full
+    pub reuse_if_unchanged: Option<BookmarkName>,
+    /// Told about the filenodes recorded while building the snapshot
+    pub observer: Option<Arc<dyn SnapshotObserver>>,
+    /// Abort the build if no filenode is recorded for this long while warmup is running
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        Self {
+            warmup: None,
+            filenodes_buffer_size: NonZeroUsize::new(1000).unwrap(),
+            filenodes_shards: NonZeroUsize::new(1).unwrap(),
+            capture_changesets: false,
+            capture_bookmarks: false,
+            capture_hg_mapping: false,
+            progress_interval: None,
+            incremental: false,
+            read_limiter: None,
+            capture_content: false,
+            capture_content_bytes: false,
+            capture_sizes: false,
+            dedupe: false,
+            trace_filenodes: false,
+            max_filenodes: None,
+            max_bytes: None,
+            sample_rate: None,
+            exclude_paths: None,
+            deterministic: false,
+            format: SnapshotFormat::default(),
+            derived_data_lease: None,
+            blobstore_bytes_read: Arc::new(AtomicU64::new(0)),
+            reuse_if_unchanged: None,
+            observer: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+/// How large the snapshot of a repo would be, as counted by `estimate_snapshot`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SnapshotEstimate {
+    /// Number of filenodes read during warmup. Filenodes that were read more than once are
+    /// counted each time, as they would be in a snapshot built without dedupe.
+    pub filenodes_count: u64,
+    /// Approximate size of those filenodes once serialized, before compression.
+    pub filenodes_bytes: u64,
+}
+
+pub fn log_phase_duration(ctx: &CoreContext, phase: &str, start: Instant) {
+    let mut scuba = ctx.scuba().clone();
+    scuba.add("phase", phase);
+    scuba.add("duration_ms", start.elapsed().as_millis() as i64);
+    scuba.log_with_msg("Microwave phase completed", None);
+}
+
+/// Periodically log how many filenodes have been recorded so far. This never returns.
+async fn log_warmup_progress(ctx: &CoreContext, interval: Duration, filenodes: &AtomicU64) {
+    let mut interval = tokio::time::interval(interval);
+    // The first tick completes immediately, and there is nothing to report yet.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        info!(
+            ctx.logger(),
+            "Cache warmup in progress: {} filenodes recorded so far",
+            filenodes.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Resolve once the number of recorded filenodes has not changed for `timeout`. This never
+/// resolves as long as filenodes keep being recorded.
+async fn detect_stall(filenodes: &AtomicU64, timeout: Duration) {
+    let mut interval = tokio::time::interval((timeout / 4).max(Duration::from_millis(100)));
+    let mut last = (filenodes.load(Ordering::Relaxed), Instant::now());
+    loop {
+        interval.tick().await;
+        let recorded = filenodes.load(Ordering::Relaxed);
+        if recorded != last.0 {
+            last = (recorded, Instant::now());
+        } else if last.1.elapsed() >= timeout {
+            return;
+        }
+    }
+}
+
+/// Read the size of the contents of each file filenode in `filenodes` from its envelope, a few at a
+/// time. Envelopes are read from the repo blobstore itself, so that these reads are not captured
+/// or counted as part of warmup.
+async fn resolve_sizes(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    filenodes: impl Stream<Item = HgFileNodeId>,
+) -> Result<Vec<(HgFileNodeId, u64)>, Error> {
+    let blobstore = repo.get_blobstore();
+    filenodes
+        .map(|filenode_id| {
+            let blobstore = &blobstore;
+            async move {
+                let envelope = filenode_id.load(ctx, blobstore).await?;
+                Result::<_, Error>::Ok((filenode_id, envelope.content_size()))
+            }
+        })
+        .buffer_unordered(SIZES_CONCURRENCY)
+        .try_collect()
+        .await
+}
+
+/// Whether warmup failed because what it read could no longer be recorded, as happens once the
+/// build stops recording filenodes.
+fn is_recording_stopped(e: &Error) -> bool {
+    e.chain()
+        .any(|cause| cause.downcast_ref::<mpsc::SendError>().is_some())
+}
+
+/// Aborts the task when dropped, so that the warmup does not outlive a build that gets dropped,
+/// e.g. because it timed out or was cancelled.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Load the existing snapshot in the location if `bookmark` still points to the changeset that
+/// was captured in it, so that it can be reused rather than warming up again.
+async fn unchanged_base(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+    bookmark: &BookmarkName,
+) -> Result<Option<Snapshot>, Error> {
+    let base = match Snapshot::load(ctx, repo, location).await? {
+        Some(base) => base,
+        None => {
+            info!(ctx.logger(), "No usable base snapshot found, warming up");
+            return Ok(None);
+        }
+    };
+    if base.metadata().repo_id != repo.get_repoid() {
+        warn!(
+            ctx.logger(),
+            "Base snapshot was built for repo {}, warming up",
+            base.metadata().repo_id
+        );
+        return Ok(None);
+    }
+
+    // A base whose bookmarks cannot be read is treated like one where the bookmark has moved.
+    let captured = match base.bookmarks() {
+        Ok(bookmarks) => bookmarks,
+        Err(e) => {
+            warn!(
+                ctx.logger(),
+                "Failed to read the bookmarks of the base snapshot, warming up: {:#}", e
+            );
+            return Ok(None);
+        }
+    };
+    let captured = captured
+        .into_iter()
+        .find(|(captured, _)| captured.name() == bookmark)
+        .map(|(_, cs_id)| cs_id);
+    let captured = match captured {
+        Some(cs_id) => cs_id,
+        None => {
+            info!(
+                ctx.logger(),
+                "Bookmark {} was not captured in the base snapshot, warming up", bookmark
+            );
+            return Ok(None);
+        }
+    };
+
+    let current = repo.bookmarks().get(ctx.clone(), bookmark).await?;
+    if current != Some(captured) {
+        info!(
+            ctx.logger(),
+            "Bookmark {} has moved from {} since the base snapshot, warming up", bookmark, captured
+        );
+        return Ok(None);
+    }
+
+    info!(
+        ctx.logger(),
+        "Bookmark {} has not moved since the base snapshot, reusing its {} filenodes",
+        bookmark,
+        base.filenodes_count()
+    );
+    Ok(Some(base))
+}
+
+/// Run cache warmup for this repo and build a snapshot of what it read. The snapshot is not
+/// committed. `location` is only used to find the base snapshot when building incrementally.
+pub async fn build_snapshot(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+    options: SnapshotOptions,
+) -> Result<Snapshot, MicrowaveError> {
+    do_build_snapshot(ctx, repo, location, options)
+        .await
+        .map_err(|source| match source.downcast_ref::<WarmupStalled>() {
+            Some(WarmupStalled(timeout)) => MicrowaveError::Stalled {
+                repo: repo.name().clone(),
+                timeout: *timeout,
+            },
+            None => MicrowaveError::Warmup {
+                repo: repo.name().clone(),
+                source,
+            },
+        })
+}
+
+async fn do_build_snapshot(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+    options: SnapshotOptions,
+) -> Result<Snapshot, Error> {
+    let SnapshotOptions {
+        warmup: req,
+        filenodes_buffer_size,
+        filenodes_shards,
+        capture_changesets,
+        capture_bookmarks,
+        capture_hg_mapping,
+        progress_interval,
+        incremental,
+        read_limiter,
+        capture_content,
+        capture_content_bytes,
+        capture_sizes,
+        dedupe,
+        trace_filenodes,
+        max_filenodes,
+        max_bytes,
+        sample_rate,
+        exclude_paths,
+        deterministic,
+        format,
+        derived_data_lease,
+        blobstore_bytes_read,
+        reuse_if_unchanged,
+        observer,
+        stall_timeout,
+    } = options;
+
+    if let Some(bookmark) = &reuse_if_unchanged {
+        if let Some(mut snapshot) = unchanged_base(ctx, repo, location, bookmark).await? {
+            // This replaces any delta on top of the base, which then no longer applies.
+            snapshot.reset_timestamp();
+            snapshot.set_format(format);
+            return Ok(snapshot);
+        }
+    }
+
+    let (filenodes_senders, filenodes_receivers): (Vec<_>, Vec<_>) = (0..filenodes_shards.get())
+        .map(|_| mpsc::channel(filenodes_buffer_size.get()))
+        .unzip();
+    let (changesets_sender, changesets_receiver) = mpsc::channel(1000);
+    let (bookmarks_sender, bookmarks_receiver) = mpsc::channel(1000);
+    let (hg_mapping_sender, hg_mapping_receiver) = mpsc::channel(1000);
+    let (blobs_sender, blobs_receiver) = mpsc::channel(1000);
+    let (redacted_sender, redacted_receiver) = mpsc::channel(1000);
+    let (sizes_sender, sizes_receiver) = mpsc::channel(1000);
+    let filenodes_recorded = Arc::new(AtomicU64::new(0));
+    let filenodes_excluded = Arc::new(AtomicU64::new(0));
+    let warmup_ctx = ctx.clone();
+    let warmup_filenodes_recorded = filenodes_recorded.clone();
+    let warmup_blobstore_bytes_read = blobstore_bytes_read.clone();
+
+    let warmup_repo = repo.dangerous_override(|inner| -> ArcFilenodes {
+        let mut filenodes = MicrowaveFilenodes::new(filenodes_senders, inner)
+            .with_trace(trace_filenodes)
+            .with_sample_rate(sample_rate)
+            .with_exclude(exclude_paths.clone(), filenodes_excluded.clone());
+        if capture_sizes {
+            filenodes = filenodes.with_sizes(sizes_sender);
+        } else {
+            drop(sizes_sender);
+        }
+        Arc::new(filenodes)
+    });
+
+    let warmup_repo = if capture_changesets {
+        warmup_repo.dangerous_override(|inner| -> ArcChangesets {
+            Arc::new(MicrowaveChangesets::new(changesets_sender, inner))
+        })
+    } else {
+        drop(changesets_sender);
+        warmup_repo
+    };
+
+    let warmup_repo = warmup_repo.dangerous_override(|inner| -> Arc<dyn Blobstore> {
+        let mut blobstore =
+            MicrowaveBlobstore::new(inner).with_bytes_read(blobstore_bytes_read.clone());
+        if let Some(read_limiter) = read_limiter {
+            blobstore = blobstore.with_limiter(read_limiter);
+        }
+        if capture_content {
+            blobstore =
+                blobstore.with_recorder(blobs_sender, redacted_sender, capture_content_bytes);
+        } else {
+            drop(blobs_sender);
+            drop(redacted_sender);
+        }
+        Arc::new(blobstore)
+    });
+
+    let warmup_repo = match derived_data_lease {
+        Some(lease) => warmup_repo.dangerous_override(|_| -> Arc<dyn LeaseOps> {
+            Arc::new(MicrowaveLease::new(lease, ctx.logger().clone()))
+        }),
+        None => warmup_repo,
+    };
+
+    let warmup_repo = if capture_bookmarks {
+        warmup_repo.dangerous_override(|inner| -> ArcBookmarks {
+            Arc::new(MicrowaveBookmarks::new(bookmarks_sender, inner))
+        })
+    } else {
+        // Nothing will be recorded, so end the bookmarks stream right away.
+        drop(bookmarks_sender);
+        warmup_repo
+    };
+
+    let warmup_repo = if capture_hg_mapping {
+        warmup_repo.dangerous_override(|inner| -> ArcBonsaiHgMapping {
+            Arc::new(MicrowaveBonsaiHgMapping::new(hg_mapping_sender, inner))
+        })
+    } else {
+        drop(hg_mapping_sender);
+        warmup_repo
+    };
+
+    // The recorders are dropped along with warmup_repo once warmup is done, which ends the
+    // streams the snapshot is built from.
+    let warmup = async move {
+        let start = Instant::now();
+        let warmup = cache_warmup::cache_warmup(&warmup_ctx, &warmup_repo, req);
+        let stats = match progress_interval {
+            Some(interval) => {
+                let progress =
+                    log_warmup_progress(&warmup_ctx, interval, &warmup_filenodes_recorded);
+                tokio::select! {
+                    res = warmup => res?,
+                    _ = progress => unreachable!(),
+                }
+            }
+            None => warmup.await?,
+        };
+        log_phase_duration(&warmup_ctx, "cache_warmup", start);
+        let bytes_read = warmup_blobstore_bytes_read.load(Ordering::Relaxed);
+        info!(
+            warmup_ctx.logger(),
+            "Read {} bytes from the blobstore during warmup", bytes_read
+        );
+        let mut scuba = warmup_ctx.scuba().clone();
+        scuba.add("blobstore_bytes_read", bytes_read as i64);
+        scuba.log_with_msg("Microwave warmup blobstore reads", None);
+
+        if capture_bookmarks {
+            // This is the same scan a server performs when it starts, so recording it here is
+            // what allows the server to skip it.
+            warmup_repo
+                .bookmarks()
+                .get_publishing_bookmarks_maybe_stale(warmup_ctx.clone())
+                .try_for_each(|_| future::ready(Ok(())))
+                .await?;
+        }
+
+        Result::<_, Error>::Ok(stats)
+    };
+
+    let mut handle = AbortOnDrop(tokio::task::spawn(warmup));
+    let start = Instant::now();
+    let build = future::join(
+        Snapshot::build(
+            repo.get_repoid(),
+            filenodes_receivers.into_iter().map(|receiver| {
+                receiver.inspect(|_| {
+                    filenodes_recorded.fetch_add(1, Ordering::Relaxed);
+                })
+            }),
+            changesets_receiver,
+            bookmarks_receiver,
+            BuildOptions {
+                dedupe,
+                max_filenodes,
+                max_bytes,
+                deterministic,
+                observer,
+            },
+        ),
+        future::join4(
+            blobs_receiver.collect::<Vec<_>>(),
+            redacted_receiver.collect::<Vec<_>>(),
+            hg_mapping_receiver.collect::<Vec<_>>(),
+            resolve_sizes(ctx, repo, sizes_receiver),
+        ),
+    );
+    let (mut snapshot, (blobs, redacted, hg_mapping, sizes)) = match stall_timeout {
+        // Filenodes are counted as the build consumes them, so this catches both warmup and the
+        // build no longer making progress.
+        Some(timeout) => tokio::select! {
+            res = build => res,
+            _ = detect_stall(&filenodes_recorded, timeout) => {
+                return Err(WarmupStalled(timeout).into());
+            }
+        },
+        None => build.await,
+    };
+    snapshot.set_format(format);
+    snapshot.set_sample_rate(sample_rate.map(NonZeroU64::get));
+    if !capture_changesets {
+        snapshot.clear_changesets();
+    }
+    if capture_content {
+        snapshot.set_blobs(blobs);
+        snapshot.set_redacted_blobs(redacted);
+        let redacted = snapshot.redacted_blobs_count();
+        if redacted > 0 {
+            info!(
+                ctx.logger(),
+                "{} blobs read during warmup are redacted, their contents were not captured",
+                redacted
+            );
+        }
+    }
+    if capture_sizes {
+        snapshot.set_content_sizes(sizes?)?;
+        info!(
+            ctx.logger(),
+            "Captured the content sizes of {} filenodes",
+            snapshot.content_sizes_count()
+        );
+    }
+    if capture_hg_mapping {
+        snapshot.set_hg_mapping(hg_mapping);
+        info!(
+            ctx.logger(),
+            "Captured {} bonsai hg mappings",
+            snapshot.hg_mapping_count()
+        );
+    }
+    log_phase_duration(ctx, "snapshot_build", start);
+    if let Some(rate) = sample_rate {
+        info!(
+            ctx.logger(),
+            "Recorded 1 in {} filenodes ({}), a full snapshot would hold about {}",
+            rate,
+            snapshot.filenodes_count(),
+            snapshot.filenodes_count() as u64 * rate.get()
+        );
+    }
+    if exclude_paths.is_some() {
+        info!(
+            ctx.logger(),
+            "Excluded {} filenodes matching --exclude-path",
+            filenodes_excluded.load(Ordering::Relaxed)
+        );
+    }
+    if dedupe {
+        info!(
+            ctx.logger(),
+            "Dropped {} duplicate filenodes",
+            snapshot.duplicate_filenodes()
+        );
+    }
+
+    if snapshot.is_truncated() {
+        if max_bytes.map_or(false, |max| snapshot.recorded_bytes() > max) {
+            warn!(
+                ctx.logger(),
+                "Stopped recording filenodes after {} of them, which took up about {} bytes, \
+                 this snapshot is partial",
+                snapshot.filenodes_count(),
+                snapshot.recorded_bytes(),
+            );
+        } else {
+            warn!(
+                ctx.logger(),
+                "Stopped recording filenodes after {} of them, this snapshot is partial",
+                max_filenodes.unwrap_or_default()
+            );
+        }
+        // Warmup fails once filenodes can no longer be recorded, which is expected here. Any
+        // other failure still fails the build.
+        match (&mut handle.0).await? {
+            Ok(_) => {}
+            Err(e) if is_recording_stopped(&e) => {}
+            Err(e) => return Err(e),
+        }
+    } else {
+        // Make sure cache warmup has succeeded before returning this snapshot.
+        let stats = (&mut handle.0).await??;
+        snapshot.set_cache_hit_ratio(stats.cache_hit_ratio());
+    }
+
+    if !incremental {
+        return Ok(snapshot);
+    }
+
+    match Snapshot::load(ctx, repo, location).await? {
+        Some(base) => match base.check_base_of(&snapshot) {
+            Ok(()) => snapshot.into_delta(&base),
+            Err(e) => {
+                warn!(
+                    ctx.logger(),
+                    "Existing snapshot cannot be built upon, building a full snapshot: {:#}", e
+                );
+                Ok(snapshot)
+            }
+        },
+        None => {
+            info!(
+                ctx.logger(),
+                "No base snapshot found, building a full snapshot"
+            );
+            Ok(snapshot)
+        }
+    }
+}
+
+/// Run cache warmup for this repo and count the filenodes it reads without recording them, to
+/// estimate how large its snapshot would be. Unlike a sampled snapshot, every filenode is counted,
+/// but memory use does not grow with the number of filenodes.
+pub async fn estimate_snapshot(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    warmup: Option<CacheWarmupRequest>,
+    read_limiter: Option<Arc<ReadLimiter>>,
+) -> Result<SnapshotEstimate, MicrowaveError> {
+    let counts = Arc::new(FilenodeCounts::default());
+    let warmup_repo = repo.dangerous_override(|inner| -> ArcFilenodes {
+        Arc::new(MicrowaveFilenodes::counting(counts.clone(), inner))
+    });
+    let warmup_repo = match read_limiter {
+        Some(read_limiter) => warmup_repo.dangerous_override(|inner| -> Arc<dyn Blobstore> {
+            Arc::new(MicrowaveBlobstore::new(inner).with_limiter(read_limiter))
+        }),
+        None => warmup_repo,
+    };
+
+    let start = Instant::now();
+    cache_warmup::cache_warmup(ctx, &warmup_repo, warmup)
+        .await
+        .map_err(|source| MicrowaveError::Warmup {
+            repo: repo.name().clone(),
+            source,
+        })?;
+    log_phase_duration(ctx, "cache_warmup", start);
+
+    Ok(SnapshotEstimate {
+        filenodes_count: counts.count.load(Ordering::Relaxed),
+        filenodes_bytes: counts.bytes.load(Ordering::Relaxed),
+    })
+}
@@ -0,0 +1,121 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fmt::Arguments;
+use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::format_err;
+use anyhow::Error;
+use anyhow::Result;
+use clap::Args;
+use mononoke_app::AppExtension;
+use slog::Drain;
+use slog::Key;
+use slog::Level;
+use slog::Never;
+use slog::OwnedKVList;
+use slog::Record;
+use slog::SendSyncRefUnwindSafeDrain;
+use slog::Serializer;
+use slog::KV;
+
+/// The key `MononokeApp::repo_logger` tags the loggers of each repo with.
+const REPO_KEY: &str = "repo";
+
+fn level_from_str(s: &str) -> Result<Level> {
+    Level::from_str(s).map_err(|_| format_err!("Unknown log level: {}", s))
+}
+
+#[derive(Args, Debug)]
+pub struct WarmupLogLevelArgs {
+    /// Log level for the per-repo loggers that warmup and snapshot building log through,
+    /// independently of --log-level, which then only applies to everything else
+    #[clap(long, parse(try_from_str = level_from_str))]
+    pub warmup_log_level: Option<Level>,
+}
+
+/// Filters the records logged through a repo logger at `--warmup-log-level`. The root log drain
+/// is lowered to the more verbose of the two levels, and the other records are filtered here at
+/// the level it would otherwise have had. With dynamic observability, the root log drain follows
+/// the configured level instead, so repo loggers cannot be more verbose than that.
+#[derive(Default)]
+pub struct WarmupLogLevelAppExtension {
+    /// What the root log drain would have been filtered at, as `Level::as_usize`.
+    log_level: AtomicUsize,
+}
+
+impl AppExtension for WarmupLogLevelAppExtension {
+    type Args = WarmupLogLevelArgs;
+
+    fn log_level_hook(&self, args: &Self::Args, level: Level) -> Level {
+        self.log_level.store(level.as_usize(), Ordering::Relaxed);
+        match args.warmup_log_level {
+            Some(warmup_level) if warmup_level.is_at_least(level) => level,
+            Some(warmup_level) => warmup_level,
+            None => level,
+        }
+    }
+
+    fn log_drain_hook(
+        &self,
+        args: &Self::Args,
+        drain: Arc<dyn SendSyncRefUnwindSafeDrain<Ok = (), Err = Never>>,
+    ) -> Result<Arc<dyn SendSyncRefUnwindSafeDrain<Ok = (), Err = Never>>> {
+        let warmup_level = match args.warmup_log_level {
+            Some(warmup_level) => warmup_level,
+            None => return Ok(drain),
+        };
+        let level = Level::from_usize(self.log_level.load(Ordering::Relaxed))
+            .ok_or_else(|| Error::msg("Log level was not set before creating the log drain"))?;
+        Ok(Arc::new(WarmupLevelDrain {
+            inner: drain,
+            warmup_level,
+            level,
+        }))
+    }
+}
+
+struct WarmupLevelDrain<D> {
+    inner: D,
+    warmup_level: Level,
+    level: Level,
+}
+
+/// Checks whether a logger's values include the repo key.
+#[derive(Default)]
+struct HasRepoKey(bool);
+
+impl Serializer for HasRepoKey {
+    fn emit_arguments(&mut self, key: Key, _val: &Arguments) -> slog::Result {
+        self.0 |= key == REPO_KEY;
+        Ok(())
+    }
+}
+
+impl<D: Drain<Ok = (), Err = Never>> Drain for WarmupLevelDrain<D> {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let mut has_repo_key = HasRepoKey::default();
+        // Serializing into HasRepoKey cannot fail.
+        let _ = values.serialize(record, &mut has_repo_key);
+        let level = if has_repo_key.0 {
+            self.warmup_level
+        } else {
+            self.level
+        };
+        if record.level().is_at_least(level) {
+            self.inner.log(record, values)
+        } else {
+            Ok(())
+        }
+    }
+}
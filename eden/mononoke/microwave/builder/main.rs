@@ -5,62 +5,152 @@
  * GNU General Public License version 2.
  */
 
-mod changesets;
-mod filenodes;
+mod config_override;
+mod diff;
+mod gc;
+mod log_level;
+mod merge;
+mod multiplex;
+mod selftest;
+mod show_config;
+mod stats;
+mod touch;
+mod verify;
 
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::num::NonZeroU32;
+use std::num::NonZeroU64;
+use std::num::NonZeroUsize;
+use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
-use ::changesets::ArcChangesets;
-use ::filenodes::ArcFilenodes;
 use anyhow::format_err;
+use anyhow::Context;
 use anyhow::Error;
-use blobrepo_override::DangerousOverride;
+use blobrepo::BlobRepo;
 use blobstore_factory::BlobstoreArgDefaults;
 use blobstore_factory::PutBehaviour;
+use bonsai_hg_mapping::BonsaiHgMappingRef;
 use bookmarks::BookmarkName;
 use bookmarks::BookmarkUpdateLogRef;
 use bookmarks::BookmarksRef;
 use cache_warmup::CacheWarmupRequest;
 use cache_warmup::CacheWarmupTarget;
+use cacheblob::LeaseOps;
+use cacheblob::MemcacheOps;
+use changesets::ChangesetsRef;
+use clap::ArgAction;
+use clap::ArgEnum;
 use clap::Args;
 use clap::Parser;
 use clap::Subcommand;
-use cloned::cloned;
 use context::CoreContext;
 use context::SessionContainer;
 use derived_data_filenodes::FilenodesOnlyPublic;
+use derived_data_manager::BonsaiDerivable;
 use fbinit::FacebookInit;
-use futures::channel::mpsc;
-use futures::future;
+use futures::stream;
+use futures::stream::StreamExt;
+use globset::Glob;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
 use mercurial_derived_data::MappedHgChangesetId;
+use mercurial_types::HgChangesetId;
+use metaconfig_types::BlobstoreId;
 use metaconfig_types::CacheWarmupParams;
-use microwave::Snapshot;
+use metaconfig_types::RepoConfig;
+use metadata::Metadata;
+use microwave::EncryptionKey;
+use microwave::MemorySnapshots;
+use microwave::SnapshotFormat;
 use microwave::SnapshotLocation;
+use microwave_builder::build_snapshot;
+use microwave_builder::estimate_snapshot;
+use microwave_builder::log_phase_duration;
+use microwave_builder::new_read_limiter;
+use microwave_builder::MicrowaveError;
+use microwave_builder::ReadLimiter;
+use microwave_builder::SnapshotEstimate;
+use microwave_builder::SnapshotOptions;
 use mononoke_api_types::InnerRepo;
+use mononoke_app::args::DeadlineAppExtension;
+use mononoke_app::args::RunIdAppExtension;
+use mononoke_app::args::ScubaSamplingAppExtension;
+use mononoke_app::args::EXIT_CODE_DEADLINE_EXCEEDED;
 use mononoke_app::fb303::AliveService;
 use mononoke_app::fb303::Fb303AppExtension;
 use mononoke_app::MononokeApp;
 use mononoke_app::MononokeAppBuilder;
+use mononoke_types::ChangesetId;
+use mononoke_types::DateTime;
+use permission_checker::MononokeIdentity;
+use permission_checker::MononokeIdentitySet;
 use repo_derived_data::RepoDerivedDataArc;
+use retry::retry;
+use retry::RetryLogic;
+use serde::Serialize;
+use slog::error;
 use slog::info;
-use slog::o;
+use slog::warn;
+use slog::Logger;
+use sql::rusqlite;
+use tokio::signal::unix::signal;
+use tokio::signal::unix::SignalKind;
+use tokio_util::sync::CancellationToken;
 use warm_bookmarks_cache::create_derived_data_warmer;
 use warm_bookmarks_cache::find_all_underived_and_latest_derived;
 use warm_bookmarks_cache::LatestDerivedBookmarkEntry;
 
-use crate::changesets::MicrowaveChangesets;
-use crate::filenodes::MicrowaveFilenodes;
+use crate::config_override::RepoConfigOverride;
+use crate::log_level::WarmupLogLevelAppExtension;
+
+/// Number of commits warmed up from `--warmup-bookmark` for repos without a cache warmup config.
+/// This matches the default used when the config does not set it.
+const DEFAULT_WARMUP_COMMIT_LIMIT: usize = 200000;
+
+/// The lease type servers derive data under, so that `--use-derived-data-lease` also coordinates
+/// with them.
+const DERIVED_DATA_LEASE: &str = "derived-data-lease";
+
+/// Delay before the first retry of opening a repo, doubled for each further retry.
+const OPEN_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Type of the identity that `--client-identity` is attached as.
+const CLIENT_IDENTITY_TYPE: &str = "SERVICE_IDENTITY";
+
+/// Derived data types that the warmup target can be chosen by.
+const WARMABLE_DERIVED_DATA_TYPES: &[&str] =
+    &[MappedHgChangesetId::NAME, FilenodesOnlyPublic::NAME];
 
 async fn cache_warmup_target(
     ctx: &CoreContext,
     repo: &InnerRepo,
     bookmark: &BookmarkName,
+    derived_data_types: &[String],
 ) -> Result<CacheWarmupTarget, Error> {
-    let warmers = vec![
-        create_derived_data_warmer::<MappedHgChangesetId>(ctx, repo.repo_derived_data_arc()),
-        create_derived_data_warmer::<FilenodesOnlyPublic>(ctx, repo.repo_derived_data_arc()),
-    ];
+    let warmers = derived_data_types
+        .iter()
+        .map(|ty| match ty.as_str() {
+            MappedHgChangesetId::NAME => Ok(create_derived_data_warmer::<MappedHgChangesetId>(
+                ctx,
+                repo.repo_derived_data_arc(),
+            )),
+            FilenodesOnlyPublic::NAME => Ok(create_derived_data_warmer::<FilenodesOnlyPublic>(
+                ctx,
+                repo.repo_derived_data_arc(),
+            )),
+            ty => Err(format_err!("Derived data type {} cannot be warmed", ty)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
     match find_all_underived_and_latest_derived(
         ctx,
@@ -85,106 +175,1583 @@ async fn cache_warmup_target(
     }
 }
 
-async fn async_main(app: MononokeApp) -> Result<(), Error> {
-    let env = app.environment();
-    let logger = app.logger();
-    let args: MononokeMicrowaveArgs = app.args()?;
+/// Resolve the `--warmup-commit` hashes, which may be bonsai or hg changeset ids. Fails listing
+/// every hash that is invalid or not in this repo.
+async fn resolve_warmup_commits(
+    ctx: &CoreContext,
+    repo: &InnerRepo,
+    hashes: &[String],
+) -> Result<Vec<ChangesetId>, Error> {
+    let mut cs_ids = Vec::new();
+    let mut errors = Vec::new();
+    for hash in hashes {
+        let cs_id = if let Ok(cs_id) = ChangesetId::from_str(hash) {
+            repo.blob_repo
+                .changesets()
+                .exists(ctx, cs_id)
+                .await?
+                .then_some(cs_id)
+        } else if let Ok(hg_cs_id) = HgChangesetId::from_str(hash) {
+            repo.blob_repo
+                .bonsai_hg_mapping()
+                .get_bonsai_from_hg(ctx, hg_cs_id)
+                .await?
+        } else {
+            errors.push(format!("{} is not a valid changeset id", hash));
+            continue;
+        };
+        match cs_id {
+            Some(cs_id) => cs_ids.push(cs_id),
+            None => errors.push(format!("{} does not exist in this repo", hash)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(format_err!(
+            "Cannot warm up from the given commits: {}",
+            errors.join(", ")
+        ));
+    }
+    Ok(cs_ids)
+}
+
+/// Read repo names from a file with one repo name per line. Blank lines and lines starting with
+/// `#` are ignored.
+async fn read_repo_list_file(
+    path: &Path,
+    repos: &HashMap<String, RepoConfig>,
+) -> Result<Vec<String>, Error> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read repo list file {}", path.display()))?;
+
+    let mut repo_names = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if !repos.contains_key(line) {
+            return Err(format_err!(
+                "Repo {} on line {} of {} is not present in the configs",
+                line,
+                idx + 1,
+                path.display()
+            ));
+        }
+
+        repo_names.push(line.to_string());
+    }
+
+    Ok(repo_names)
+}
+
+fn select_repos(
+    repos: HashMap<String, RepoConfig>,
+    repo_names: &[String],
+    repo_ids: &[i32],
+) -> Result<Vec<(String, RepoConfig)>, Error> {
+    if repo_names.is_empty() && repo_ids.is_empty() {
+        return Ok(repos.into_iter().collect());
+    }
+
+    for name in repo_names {
+        if !repos.contains_key(name) {
+            return Err(format_err!("Repo {} is not present in the configs", name));
+        }
+    }
+
+    for id in repo_ids {
+        if !repos.values().any(|config| config.repoid.id() == *id) {
+            return Err(format_err!("Repo id {} is not present in the configs", id));
+        }
+    }
+
+    Ok(repos
+        .into_iter()
+        .filter(|(name, config)| {
+            repo_names.contains(name) || repo_ids.contains(&config.repoid.id())
+        })
+        .collect())
+}
+
+fn exclude_paths_glob_set(patterns: &[String]) -> Result<Option<GlobSet>, Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(
+            Glob::new(pattern).with_context(|| format!("Invalid --exclude-path {}", pattern))?,
+        );
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Whether a repo has something to warm up: a cache warmup config with a bookmark, or a target
+/// given on the command line.
+fn has_warmup_target(args: &MononokeMicrowaveArgs, config: &RepoConfig) -> bool {
+    args.warmup_bookmark.is_some()
+        || !args.warmup_commits.is_empty()
+        || config
+            .cache_warmup
+            .as_ref()
+            .map_or(false, |params| !params.bookmark.as_str().is_empty())
+}
+
+/// Whether a failure to open a repo looks like the storage it needs is briefly unavailable, so
+/// that opening it again may work. Anything else, such as a bad config, fails the same way again.
+fn is_transient_open_error(e: &Error) -> bool {
+    e.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::TimedOut
+            );
+        }
+        if cause
+            .downcast_ref::<tokio::time::error::Elapsed>()
+            .is_some()
+        {
+            return true;
+        }
+        if let Some(rusqlite::Error::SqliteFailure(e, _)) = cause.downcast_ref::<rusqlite::Error>()
+        {
+            return matches!(
+                e.code,
+                rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+            );
+        }
+        is_transient_mysql_error(cause)
+    })
+}
+
+#[cfg(fbcode_build)]
+fn is_transient_mysql_error(cause: &(dyn std::error::Error + 'static)) -> bool {
+    use mysql_client::MysqlError;
+    matches!(
+        cause.downcast_ref::<MysqlError>(),
+        Some(MysqlError::ConnectionOperationError { .. })
+    )
+}
+
+#[cfg(not(fbcode_build))]
+fn is_transient_mysql_error(_cause: &(dyn std::error::Error + 'static)) -> bool {
+    false
+}
+
+fn repo_context(app: &MononokeApp, name: &str, client_identity: &str) -> CoreContext {
+    let mut scuba = app.environment().scuba_sample_builder.clone();
+    scuba.add("reponame", name);
+    let identities =
+        MononokeIdentitySet::from([MononokeIdentity::new(CLIENT_IDENTITY_TYPE, client_identity)]);
+    let session = SessionContainer::builder(app.fb)
+        .metadata(Arc::new(Metadata::default().set_identities(identities)))
+        .build();
+    session.new_context(app.repo_logger(name), scuba)
+}
+
+/// How long ago the newest snapshot stored in the repository blobstore with this key prefix was
+/// built, if that is within `window`.
+async fn fresh_snapshot_age(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    key_prefix: Option<&str>,
+    window: Duration,
+) -> Result<Option<Duration>, Error> {
+    let now = DateTime::now().timestamp_secs();
+    let newest = microwave::find_blobstore_snapshots(ctx, repo, key_prefix)
+        .await?
+        .into_iter()
+        .filter_map(|(_key, metadata)| metadata)
+        .map(|metadata| metadata.timestamp)
+        .max();
+    Ok(newest
+        .map(|timestamp| Duration::from_secs(now.saturating_sub(timestamp).max(0) as u64))
+        .filter(|age| *age <= window))
+}
+
+/// What was done for a repo that did not fail.
+enum RepoOutcome {
+    Built {
+        filenodes_count: usize,
+        hg_mapping_count: usize,
+        blobstore_bytes_read: u64,
+    },
+    /// With `--resume`, a recent enough snapshot already existed.
+    Resumed { age: Duration },
+    /// With `estimate`, how large the snapshot would have been.
+    Estimated(SnapshotEstimate),
+    /// With `--soft-deadline-secs`, the deadline had passed before the repo could be started.
+    NotStarted,
+    /// There was nothing to warm up for the repo.
+    Skipped { reason: String },
+}
 
+/// What all the repos in a run share, set up once from the arguments.
+struct RunResources {
+    /// Shared so that the limit applies to the warmup as a whole.
+    read_limiter: Option<Arc<ReadLimiter>>,
+    exclude_paths: Option<Arc<GlobSet>>,
+    derived_data_lease: Option<Arc<dyn LeaseOps>>,
+}
+
+async fn build_repo_snapshot(
+    app: &MononokeApp,
+    args: &MononokeMicrowaveArgs,
+    resources: &RunResources,
+    name: String,
+    mut config: RepoConfig,
+    location: SnapshotLocation<'_>,
+) -> Result<RepoOutcome, MicrowaveError> {
     let repo_factory = Arc::clone(app.repo_factory());
-    let scuba = env.scuba_sample_builder.clone();
+    let common_config = app.repo_configs().common.clone();
+    let ctx = repo_context(app, &name, &args.client_identity);
+
+    // This only changes the config used for this run, and is never persisted.
+    if !args.enable_derived_data_types.is_empty() {
+        let derived_data_config = &mut config.derived_data_config;
+        let active_config = derived_data_config
+            .available_configs
+            .entry(derived_data_config.enabled_config_name.clone())
+            .or_default();
+        active_config
+            .types
+            .extend(args.enable_derived_data_types.iter().cloned());
+    }
 
-    let repos = app.repo_configs().repos.clone();
+    if !args.skip_derived_data_types.is_empty() {
+        let derived_data_config = &mut config.derived_data_config;
+        let mut skipped = Vec::new();
+        if let Some(active_config) = derived_data_config
+            .available_configs
+            .get_mut(&derived_data_config.enabled_config_name)
+        {
+            for ty in &args.skip_derived_data_types {
+                if active_config.types.remove(ty) {
+                    skipped.push(ty.as_str());
+                }
+            }
+        }
+        // The same flags may be shared across repos that do not all have the same types.
+        for ty in &args.skip_derived_data_types {
+            if !skipped.contains(&ty.as_str()) {
+                warn!(
+                    ctx.logger(),
+                    "Derived data type {} is not enabled for this repo, it cannot be skipped", ty
+                );
+            }
+        }
+        if !skipped.is_empty() {
+            info!(
+                ctx.logger(),
+                "Skipping derived data types: {}",
+                skipped.join(", ")
+            );
+        }
+    }
 
+    let derived_data_types = if args.derived_data_types.is_empty() {
+        WARMABLE_DERIVED_DATA_TYPES
+            .iter()
+            .filter(|ty| !args.skip_derived_data_types.iter().any(|skip| skip == *ty))
+            .map(|ty| ty.to_string())
+            .collect()
+    } else {
+        let mut derived_data_types = Vec::new();
+        for ty in &args.derived_data_types {
+            if config.derived_data_config.is_enabled(ty) {
+                derived_data_types.push(ty.clone());
+            } else {
+                warn!(
+                    ctx.logger(),
+                    "Derived data type {} is not enabled for this repo, skipping it", ty
+                );
+            }
+        }
+        derived_data_types
+    };
+
+    let warmup_error = |source| MicrowaveError::Warmup {
+        repo: name.clone(),
+        source,
+    };
+
+    if derived_data_types.is_empty() {
+        let reason = "none of the requested derived data types are enabled";
+        // The snapshot would hold no filenodes.
+        if args.fail_on_empty {
+            return Err(warmup_error(format_err!(
+                "Nothing to warm up: {}, check the derived data config of the repo",
+                reason
+            )));
+        }
+        warn!(ctx.logger(), "Skipping this repo: {}", reason);
+        return Ok(RepoOutcome::Skipped {
+            reason: reason.to_string(),
+        });
+    }
+
+    let since = match args.warmup_since {
+        Some(since) => Some(
+            DateTime::from_timestamp(DateTime::now().timestamp_secs() - since.as_secs() as i64, 0)
+                .map_err(warmup_error)?,
+        ),
+        None => None,
+    };
+
+    let cache_warmup = match (&args.warmup_bookmark, config.cache_warmup.clone()) {
+        (Some(bookmark), Some(params)) => Some(CacheWarmupParams {
+            bookmark: bookmark.clone(),
+            ..params
+        }),
+        (Some(bookmark), None) => Some(CacheWarmupParams {
+            bookmark: bookmark.clone(),
+            commit_limit: DEFAULT_WARMUP_COMMIT_LIMIT,
+            microwave_preload: false,
+        }),
+        (None, params) => params,
+    };
+
+    // Check these before warming up, so that a typo does not waste a whole warmup.
+    let inner_blobstores = match &args.command {
+        Commands::Blobstore(blobstore_args) if !blobstore_args.blobstore_ids.is_empty() => {
+            let ids = blobstore_args
+                .blobstore_ids
+                .iter()
+                .copied()
+                .map(BlobstoreId::new)
+                .collect::<Vec<_>>();
+            multiplex::inner_blobstore_configs(config.storage_config.blobstore.clone(), &ids)
+                .map_err(|e| {
+                    MicrowaveError::ConfigRead(
+                        e.context(format!("Invalid blobstore ids for {}", name)),
+                    )
+                })?
+        }
+        _ => Vec::new(),
+    };
+
+    let attempts = args.open_retries + 1;
+    let (repo, _): (InnerRepo, _) = retry(
+        None,
+        |attempt| {
+            let (repo_factory, name, config, common_config) = (
+                &repo_factory,
+                name.clone(),
+                config.clone(),
+                common_config.clone(),
+            );
+            let logger = ctx.logger();
+            async move {
+                repo_factory
+                    .build(name, config, common_config)
+                    .await
+                    .map_err(|e| {
+                        warn!(
+                            logger,
+                            "Failed to open repo (attempt {} of {}): {:#}", attempt, attempts, e
+                        );
+                        e
+                    })
+            }
+        },
+        is_transient_open_error,
+        RetryLogic::Exponential {
+            base: OPEN_RETRY_BASE_DELAY,
+            factor: 2.0,
+        },
+        attempts,
+    )
+    .await
+    .map_err(|source| MicrowaveError::BlobrepoOpen {
+        repo: name.clone(),
+        source,
+    })?;
+
+    if args.preflight {
+        selftest::ping_blobstore(&ctx, &repo)
+            .await
+            .map_err(|e| MicrowaveError::BlobrepoOpen {
+                repo: name.clone(),
+                source: e.context("Preflight read from the blobstore failed"),
+            })?;
+    }
+
+    if let Commands::Blobstore(blobstore_args) = &args.command {
+        if blobstore_args.resume {
+            let key_prefix = blobstore_args.blobstore_key_prefix.as_deref();
+            // This only saves work, so if the check fails, just build the snapshot again.
+            match fresh_snapshot_age(
+                &ctx,
+                &repo.blob_repo,
+                key_prefix,
+                blobstore_args.resume_window,
+            )
+            .await
+            {
+                Ok(Some(age)) => return Ok(RepoOutcome::Resumed { age }),
+                Ok(None) => {}
+                Err(e) => warn!(
+                    ctx.logger(),
+                    "Failed to check for an existing snapshot, building it again: {:#}", e
+                ),
+            }
+        }
+    }
+
+    let reuse_if_unchanged = match &cache_warmup {
+        Some(params) if args.reuse_unchanged_snapshot => Some(params.bookmark.clone()),
+        _ => None,
+    };
+
+    // Rewind bookmarks to the point where we have derived data. Cache
+    // warmup requires filenodes and hg changesets to be present.
+    let warmup = if !args.warmup_commits.is_empty() {
+        let cs_ids = resolve_warmup_commits(&ctx, &repo, &args.warmup_commits)
+            .await
+            .map_err(warmup_error)?;
+        Some(CacheWarmupRequest {
+            target: CacheWarmupTarget::Changesets(cs_ids),
+            commit_limit: cache_warmup
+                .as_ref()
+                .map_or(DEFAULT_WARMUP_COMMIT_LIMIT, |params| params.commit_limit),
+            microwave_preload: cache_warmup
+                .as_ref()
+                .map_or(false, |params| params.microwave_preload),
+            since,
+            concurrency: args.warmup_concurrency,
+            only_missing_derived_data: args.only_missing_derived_data,
+        })
+    } else {
+        match cache_warmup {
+            Some(params) => {
+                let CacheWarmupParams {
+                    bookmark,
+                    commit_limit,
+                    microwave_preload,
+                } = params;
+
+                if args.warmup_bookmark.is_some()
+                    && repo
+                        .bookmarks()
+                        .get(ctx.clone(), &bookmark)
+                        .await
+                        .map_err(warmup_error)?
+                        .is_none()
+                {
+                    return Err(warmup_error(format_err!(
+                        "Bookmark {} does not exist in this repo",
+                        bookmark
+                    )));
+                }
+
+                let target = cache_warmup_target(&ctx, &repo, &bookmark, &derived_data_types)
+                    .await
+                    .map_err(warmup_error)?;
+
+                Some(CacheWarmupRequest {
+                    target,
+                    commit_limit,
+                    microwave_preload,
+                    since,
+                    concurrency: args.warmup_concurrency,
+                    only_missing_derived_data: args.only_missing_derived_data,
+                })
+            }
+            None => None,
+        }
+    };
+
+    if let Commands::Estimate = &args.command {
+        let estimate = estimate_snapshot(
+            &ctx,
+            &repo.blob_repo,
+            warmup,
+            resources.read_limiter.clone(),
+        )
+        .await?;
+        info!(
+            ctx.logger(),
+            "Snapshot would hold {} filenodes, taking up about {} bytes",
+            estimate.filenodes_count,
+            estimate.filenodes_bytes
+        );
+        return Ok(RepoOutcome::Estimated(estimate));
+    }
+
+    let blobstore_bytes_read = Arc::new(AtomicU64::new(0));
+    let options = SnapshotOptions {
+        warmup,
+        filenodes_buffer_size: args.filenodes_buffer_size,
+        filenodes_shards: args.filenodes_shards,
+        capture_changesets: args.capture_changesets,
+        capture_bookmarks: args.capture_bookmarks,
+        capture_hg_mapping: args.capture_hg_mapping,
+        progress_interval: match args.progress_interval_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        incremental: args.incremental,
+        read_limiter: resources.read_limiter.clone(),
+        capture_content: args.capture_content,
+        capture_content_bytes: args.capture_content_bytes,
+        capture_sizes: args.capture_sizes,
+        dedupe: args.dedupe,
+        trace_filenodes: args.trace_filenodes,
+        max_filenodes: args.max_filenodes,
+        max_bytes: args.per_repo_memory_cap,
+        sample_rate: args.sample_rate,
+        exclude_paths: resources.exclude_paths.clone(),
+        deterministic: args.deterministic,
+        format: args.snapshot_format,
+        derived_data_lease: resources.derived_data_lease.clone(),
+        blobstore_bytes_read: blobstore_bytes_read.clone(),
+        reuse_if_unchanged,
+        observer: None,
+        stall_timeout: match args.stall_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+    };
+
+    let snapshot = build_snapshot(&ctx, &repo.blob_repo, location, options).await?;
+
+    // A delta holds nothing when nothing has changed since its base, so only full snapshots can
+    // be told to be empty by mistake. This is checked before committing, so that an existing
+    // snapshot is not replaced with an empty one.
+    if args.fail_on_empty && !snapshot.is_delta() && snapshot.filenodes_count() == 0 {
+        return Err(warmup_error(format_err!(
+            "Warmup recorded no filenodes, check the warmup bookmark and config of the repo"
+        )));
+    }
+
+    if args.dry_run {
+        info!(
+            ctx.logger(),
+            "Dry run: snapshot contains {} filenodes ({} bytes), not committing",
+            snapshot.filenodes_count(),
+            snapshot.serialized_size(),
+        );
+    } else {
+        let start = Instant::now();
+        let commit_error = |source| MicrowaveError::SnapshotCommit {
+            repo: name.clone(),
+            source,
+        };
+        let (key_prefix, update_latest) = match (location, &args.command) {
+            (SnapshotLocation::Blobstore { key_prefix }, Commands::Blobstore(blobstore_args)) => {
+                (key_prefix, blobstore_args.update_latest)
+            }
+            _ => (None, false),
+        };
+        if inner_blobstores.is_empty() {
+            snapshot
+                .commit(&ctx, &repo.blob_repo, location, args.commit_retries)
+                .await
+                .map_err(commit_error)?;
+            if args.verify_after_commit {
+                snapshot
+                    .verify_commit(&ctx, &repo.blob_repo, location)
+                    .await
+                    .map_err(commit_error)?;
+                info!(ctx.logger(), "Verified committed snapshot");
+            }
+            if update_latest {
+                microwave::update_latest_pointer(
+                    &ctx,
+                    repo.blob_repo.blobstore(),
+                    key_prefix,
+                    args.commit_retries,
+                )
+                .await
+                .map_err(commit_error)?;
+            }
+        } else {
+            for (id, blobconfig) in inner_blobstores {
+                let blobstore =
+                    multiplex::open_inner_blobstore(app, repo.blob_repo.get_repoid(), blobconfig)
+                        .await
+                        .map_err(commit_error)?;
+                snapshot
+                    .commit_to_blobstore(&ctx, &blobstore, key_prefix, args.commit_retries)
+                    .await
+                    .map_err(commit_error)?;
+                if args.verify_after_commit {
+                    snapshot
+                        .verify_commit_to_blobstore(&ctx, &blobstore, key_prefix)
+                        .await
+                        .map_err(commit_error)?;
+                }
+                if update_latest {
+                    microwave::update_latest_pointer(
+                        &ctx,
+                        &blobstore,
+                        key_prefix,
+                        args.commit_retries,
+                    )
+                    .await
+                    .map_err(commit_error)?;
+                }
+                info!(ctx.logger(), "Committed snapshot to blobstore {}", id);
+            }
+        }
+        log_phase_duration(&ctx, "snapshot_commit", start);
+    }
+
+    Ok(RepoOutcome::Built {
+        filenodes_count: snapshot.filenodes_count(),
+        hg_mapping_count: snapshot.hg_mapping_count(),
+        blobstore_bytes_read: blobstore_bytes_read.load(Ordering::Relaxed),
+    })
+}
+
+/// The outcome of building the snapshot for a single repo, as reported by `--output-format json`.
+#[derive(Serialize)]
+struct RepoSummary {
+    repo_name: String,
+    repo_id: i32,
+    success: bool,
+    filenodes_count: usize,
+    /// Number of bonsai to hg mappings captured, 0 unless `--capture-hg-mapping` is set.
+    hg_mapping_count: usize,
+    /// Size of the blobs read during warmup, including those served from cache.
+    blobstore_bytes_read: u64,
+    /// With `estimate`, about how many bytes the filenodes would take up in the snapshot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimated_bytes: Option<u64>,
+    elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A repo that was left out of the run because of its config with `--skip-bad-configs`, because
+/// it has nothing to warm up, because it already had a recent snapshot with `--resume`, because
+/// `--soft-deadline-secs` passed before it was started, or because `--failure-threshold` tripped
+/// before it was done.
+#[derive(Serialize)]
+struct SkippedRepo {
+    repo_name: String,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct RunSummary {
+    repos: Vec<RepoSummary>,
+    /// Total of `blobstore_bytes_read` across repos.
+    blobstore_bytes_read: u64,
+    /// Total of `estimated_bytes` across repos, with `estimate`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimated_bytes: Option<u64>,
+    /// Whether `--failure-threshold` repos failed in a row, so that the remaining ones were not
+    /// tried. Those are listed in `skipped`.
+    circuit_open: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    skipped: Vec<SkippedRepo>,
+}
+
+/// Format an error along with its sources, like anyhow's alternate formatting does.
+fn error_chain(e: &MicrowaveError) -> String {
+    let mut chain = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        chain.push_str(&format!(": {}", e));
+        source = e.source();
+    }
+    chain
+}
+
+/// Drop the positions that were kept along with the results of repos, sorting them by position.
+fn in_submission_order<T>(mut items: Vec<(usize, T)>) -> Vec<T> {
+    items.sort_by_key(|(index, _)| *index);
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+/// Cancel `cancellation` when the process is asked to terminate.
+fn cancel_on_signal(logger: Logger, cancellation: CancellationToken) -> Result<(), Error> {
+    // Tokio chains to whatever handler was installed before its own, and Folly's would then kill
+    // the process before the cancellation gets handled. Resetting it drops any SIGTERM handler
+    // installed so far, on purpose, as `running::run_until_terminated` does for servers.
+    //
+    // SAFETY: this runs before tokio installs its handler, and SIG_DFL is always a valid
+    // disposition. Nothing else in this process relies on a SIGTERM handler of its own.
+    unsafe {
+        libc::signal(libc::SIGTERM, libc::SIG_DFL);
+    }
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+        info!(logger, "Signalled! Cancelling...");
+        cancellation.cancel();
+    });
+
+    Ok(())
+}
+
+async fn async_main(
+    app: MononokeApp,
+    cancellation: CancellationToken,
+) -> Result<(), MicrowaveError> {
+    let logger = app.logger();
+    let args: MononokeMicrowaveArgs = app.args().map_err(MicrowaveError::ConfigRead)?;
+
+    let mut repo_names = args.repo_names.clone();
+    if let Some(path) = &args.repo_list_file {
+        repo_names.extend(
+            read_repo_list_file(path, &app.repo_configs().repos)
+                .await
+                .map_err(MicrowaveError::ConfigRead)?,
+        );
+    }
+
+    let mut repos = select_repos(
+        app.repo_configs().repos.clone(),
+        &repo_names,
+        &args.repo_ids,
+    )
+    .map_err(MicrowaveError::ConfigRead)?;
+
+    if let (Some(min), Some(max)) = (args.repo_id_min, args.repo_id_max) {
+        if min > max {
+            return Err(MicrowaveError::ConfigRead(format_err!(
+                "--repo-id-min ({}) must not be greater than --repo-id-max ({})",
+                min,
+                max
+            )));
+        }
+    }
+    repos.retain(|(_name, config)| {
+        let id = config.repoid.id();
+        args.repo_id_min.map_or(true, |min| id >= min)
+            && args.repo_id_max.map_or(true, |max| id <= max)
+    });
+
+    for (name, config) in &mut repos {
+        for config_override in &args.repo_config_overrides {
+            let value = config_override
+                .apply(config)
+                .with_context(|| format!("Failed to override the config of repo {}", name))
+                .map_err(MicrowaveError::ConfigRead)?;
+            info!(logger, "Overriding config for repo {}: {}", name, value);
+        }
+    }
+
+    for ty in &args.derived_data_types {
+        if !WARMABLE_DERIVED_DATA_TYPES.contains(&ty.as_str()) {
+            return Err(MicrowaveError::ConfigRead(format_err!(
+                "Derived data type {} cannot be warmed, expected one of: {}",
+                ty,
+                WARMABLE_DERIVED_DATA_TYPES.join(", ")
+            )));
+        }
+    }
+
+    if let Commands::ShowConfig = &args.command {
+        return show_config::show_config(&repos, args.output_format == OutputFormat::Json)
+            .map_err(MicrowaveError::Output);
+    }
+
+    let encrypt_key_file = match &args.command {
+        Commands::LocalPath(local_path_args) => local_path_args.encrypt_key_file.as_deref(),
+        Commands::Verify(verify_args) => verify_args.encrypt_key_file.as_deref(),
+        Commands::Touch(touch_args) => touch_args.encrypt_key_file.as_deref(),
+        _ => None,
+    };
+    let encryption_key = match encrypt_key_file {
+        Some(path) => Some(
+            EncryptionKey::read_from_file(path)
+                .await
+                .map_err(MicrowaveError::ConfigRead)?,
+        ),
+        None => None,
+    };
+
+    // Nothing is written when estimating, so this location is never used.
+    let estimate_snapshots = MemorySnapshots::new();
     let location = match &args.command {
+        Commands::Estimate => SnapshotLocation::Memory(&estimate_snapshots),
         Commands::LocalPath(local_path_args) => {
             let path = &local_path_args.local_path;
             info!(logger, "Writing to path {}", path.display());
-            SnapshotLocation::SharedLocalPath(path.as_path())
+            SnapshotLocation::SharedLocalPath {
+                path: path.as_path(),
+                compress: local_path_args.compress,
+                manifest: local_path_args.write_manifest,
+                encryption_key: encryption_key.as_ref(),
+            }
+        }
+        Commands::Blobstore(blobstore_args) => SnapshotLocation::Blobstore {
+            key_prefix: blobstore_args.blobstore_key_prefix.as_deref(),
+        },
+        Commands::ObjectStore(object_store_args) => {
+            info!(
+                logger,
+                "Writing to bucket {} with key prefix {}",
+                object_store_args.bucket,
+                object_store_args.key_prefix,
+            );
+            SnapshotLocation::ObjectStore {
+                bucket: &object_store_args.bucket,
+                key_prefix: &object_store_args.key_prefix,
+            }
+        }
+        Commands::Stdout(stdout_args) => {
+            if repos.len() != 1 {
+                return Err(MicrowaveError::ConfigRead(format_err!(
+                    "stdout can only hold the snapshot of a single repo, but {} repos were selected",
+                    repos.len()
+                )));
+            }
+            // Logs already go to stderr, so only the run summary could get mixed in.
+            if args.output_format == OutputFormat::Json {
+                return Err(MicrowaveError::ConfigRead(format_err!(
+                    "--output-format json cannot be used with stdout, which holds the snapshot"
+                )));
+            }
+            if args.verify_after_commit {
+                return Err(MicrowaveError::ConfigRead(format_err!(
+                    "--verify-after-commit cannot be used with stdout, which cannot be read back"
+                )));
+            }
+            SnapshotLocation::Stdout {
+                compress: stdout_args.compress,
+            }
+        }
+        Commands::Verify(verify_args) => {
+            let location = match &verify_args.local_path {
+                Some(path) => SnapshotLocation::SharedLocalPath {
+                    path: path.as_path(),
+                    compress: false,
+                    manifest: false,
+                    encryption_key: encryption_key.as_ref(),
+                },
+                None => SnapshotLocation::Blobstore {
+                    key_prefix: verify_args.blobstore_key_prefix.as_deref(),
+                },
+            };
+            return verify::verify_repos(
+                &app,
+                repos,
+                location,
+                args.max_concurrent_repos,
+                &args.client_identity,
+            )
+            .await
+            .map_err(MicrowaveError::Verify);
+        }
+        Commands::Touch(touch_args) => {
+            let location = match &touch_args.local_path {
+                Some(path) => SnapshotLocation::SharedLocalPath {
+                    path: path.as_path(),
+                    compress: false,
+                    manifest: false,
+                    encryption_key: encryption_key.as_ref(),
+                },
+                None => SnapshotLocation::Blobstore {
+                    key_prefix: touch_args.blobstore_key_prefix.as_deref(),
+                },
+            };
+            return touch::touch_repos(
+                &app,
+                repos,
+                location,
+                args.max_concurrent_repos,
+                &args.client_identity,
+            )
+            .await
+            .map_err(MicrowaveError::Touch);
+        }
+        Commands::Selftest => {
+            return selftest::selftest_repos(
+                &app,
+                repos,
+                args.max_concurrent_repos,
+                &args.client_identity,
+            )
+            .await
+            .map_err(MicrowaveError::Selftest);
+        }
+        Commands::Gc(gc_args) => {
+            let key_prefixes = if gc_args.blobstore_key_prefixes.is_empty() {
+                vec![None]
+            } else {
+                gc_args
+                    .blobstore_key_prefixes
+                    .iter()
+                    .map(|prefix| Some(prefix.as_str()))
+                    .collect()
+            };
+            return gc::gc_repos(
+                &app,
+                repos,
+                &key_prefixes,
+                gc_args.older_than,
+                gc_args.dry_run,
+                args.max_concurrent_repos,
+                &args.client_identity,
+            )
+            .await
+            .map_err(MicrowaveError::Gc);
+        }
+        Commands::Merge(merge_args) => {
+            return merge::merge_snapshots(
+                &app,
+                &merge_args.inputs,
+                &merge_args.output,
+                merge_args.compress,
+            )
+            .await
+            .map_err(MicrowaveError::Merge);
+        }
+        Commands::ShowConfig => unreachable!("show-config has already been handled"),
+        Commands::Stats(stats_args) => {
+            return stats::snapshot_stats(
+                &stats_args.snapshot,
+                stats_args.depth,
+                args.output_format == OutputFormat::Json,
+            )
+            .await
+            .map_err(MicrowaveError::Stats);
+        }
+        Commands::Diff(diff_args) => {
+            return diff::diff_snapshots(
+                &app,
+                &diff_args.a,
+                &diff_args.b,
+                args.output_format == OutputFormat::Json,
+            )
+            .await
+            .map_err(MicrowaveError::Diff);
         }
-        Commands::Blobstore => SnapshotLocation::Blobstore,
     };
-    let common_config = &app.repo_configs().common;
-    let futs = repos
-        .into_iter()
-        .map(|(name, config)| {
-            cloned!(repo_factory, mut scuba, common_config);
+
+    // Without a warmup target the snapshot would be empty, and servers would load it without
+    // warming anything up.
+    let mut unconfigured = Vec::new();
+    let mut configured = Vec::with_capacity(repos.len());
+    for (name, config) in repos {
+        if has_warmup_target(&args, &config) {
+            configured.push((name, config));
+        } else if args.skip_unconfigured {
+            let reason = "it has no cache_warmup config".to_string();
+            warn!(logger, "Skipped repo {}: {}", name, reason);
+            unconfigured.push(SkippedRepo {
+                repo_name: name,
+                reason,
+            });
+        } else {
+            return Err(MicrowaveError::ConfigRead(format_err!(
+                "Repo {} has no cache_warmup config: add one to its config, pass --warmup-bookmark \
+                 or --warmup-commit, or pass --skip-unconfigured=true to leave it out",
+                name
+            )));
+        }
+    }
+    let repos = configured;
+
+    let read_limiter = args.warmup_blobstore_qps.map(new_read_limiter);
+    let exclude_paths = exclude_paths_glob_set(&args.exclude_paths)
+        .map_err(MicrowaveError::ConfigRead)?
+        .map(Arc::new);
+    let derived_data_lease = if args.use_derived_data_lease {
+        let lease = MemcacheOps::new(app.fb, DERIVED_DATA_LEASE, "")
+            .context("Failed to create derived data lease")
+            .map_err(MicrowaveError::ConfigRead)?;
+        Some(Arc::new(lease) as Arc<dyn LeaseOps>)
+    } else {
+        None
+    };
+    let resources = RunResources {
+        read_limiter,
+        exclude_paths,
+        derived_data_lease,
+    };
+
+    let repo_names = repos
+        .iter()
+        .map(|(name, _config)| name.clone())
+        .collect::<Vec<_>>();
+    let soft_deadline = match args.soft_deadline_secs {
+        0 => None,
+        secs => Some(Instant::now() + Duration::from_secs(secs)),
+    };
+    let results = stream::iter(repos.into_iter().enumerate())
+        .map(|(index, (name, config))| {
+            let (app, args) = (&app, &args);
+            let resources = &resources;
+            let repo_id = config.repoid.id();
+            let cancellation = &cancellation;
             async move {
-                let logger = logger.new(o!("repo" => name.clone()));
-                let ctx = {
-                    scuba.add("reponame", name.clone());
-                    let session = SessionContainer::new_with_defaults(app.fb);
-                    session.new_context(logger.clone(), scuba)
+                let start = Instant::now();
+                // The stream only polls this once there is room for the repo to run, so this is
+                // when it would have started.
+                if soft_deadline.map_or(false, |deadline| start >= deadline) {
+                    return (
+                        index,
+                        name,
+                        repo_id,
+                        Duration::ZERO,
+                        Ok(RepoOutcome::NotStarted),
+                    );
+                }
+                let fut = build_repo_snapshot(app, args, resources, name.clone(), config, location);
+                let fut = async {
+                    match args.warmup_timeout_secs {
+                        0 => fut.await,
+                        secs => match tokio::time::timeout(Duration::from_secs(secs), fut).await {
+                            Ok(res) => res,
+                            Err(_) => Err(MicrowaveError::Timeout {
+                                repo: name.clone(),
+                                elapsed: start.elapsed(),
+                            }),
+                        },
+                    }
                 };
-
-                let (filenodes_sender, filenodes_receiver) = mpsc::channel(1000);
-                let (changesets_sender, changesets_receiver) = mpsc::channel(1000);
-                let warmup_ctx = ctx.clone();
-
-                let warmup = async move {
-                    let cache_warmup = config.cache_warmup.clone();
-                    let repo: InnerRepo = repo_factory.build(name, config, common_config).await?;
-
-                    // Rewind bookmarks to the point where we have derived data. Cache
-                    // warmup requires filenodes and hg changesets to be present.
-                    let req = match cache_warmup {
-                        Some(params) => {
-                            let CacheWarmupParams {
-                                bookmark,
-                                commit_limit,
-                                microwave_preload,
-                            } = params;
-
-                            let target = cache_warmup_target(&warmup_ctx, &repo, &bookmark).await?;
-
-                            Some(CacheWarmupRequest {
-                                target,
-                                commit_limit,
-                                microwave_preload,
-                            })
-                        }
-                        None => None,
-                    };
-
-                    let warmup_repo = repo
-                        .blob_repo
-                        .dangerous_override(|inner| -> ArcFilenodes {
-                            Arc::new(MicrowaveFilenodes::new(filenodes_sender, inner))
-                        })
-                        .dangerous_override(|inner| -> ArcChangesets {
-                            Arc::new(MicrowaveChangesets::new(changesets_sender, inner))
-                        });
-
-                    cache_warmup::cache_warmup(&warmup_ctx, &warmup_repo, req).await?;
-
-                    Result::<_, Error>::Ok(repo)
+                // Dropping the build on cancellation is safe: snapshots are never partially
+                // committed.
+                let res = tokio::select! {
+                    biased;
+                    _ = cancellation.cancelled() => Err(MicrowaveError::Cancelled),
+                    res = fut => res,
                 };
+                (index, name, repo_id, start.elapsed(), res)
+            }
+        })
+        .buffer_unordered(args.max_concurrent_repos.get())
+        .boxed();
 
-                let handle = tokio::task::spawn(warmup);
-                let snapshot = Snapshot::build(filenodes_receiver, changesets_receiver).await;
-
-                // Make sure cache warmup has succeeded before committing this snapshot, and get
-                // the repo back.
-                let repo = handle.await??;
+    // Unless failing fast, keep going when a repo fails, so that the snapshots for every other
+    // repo still get committed, and report all failures at the end. Results come in the order the
+    // repos finish, which is the order failures are counted in for the circuit breaker, and each
+    // is kept with the position of its repo, to report them in the order the repos were given.
+    let mut summaries = Vec::new();
+    let mut failures = Vec::new();
+    let mut resumed = Vec::new();
+    let mut not_started = Vec::new();
+    let mut nothing_to_warm = Vec::new();
+    let mut cancelled = false;
+    let mut consecutive_failures = 0;
+    let mut circuit_open = false;
+    while let Some((index, name, repo_id, elapsed, res)) = results.next().await {
+        let mut estimated_bytes = None;
+        let (filenodes_count, hg_mapping_count, blobstore_bytes_read, error) = match res {
+            Ok(RepoOutcome::Built {
+                filenodes_count,
+                hg_mapping_count,
+                blobstore_bytes_read,
+            }) => (
+                filenodes_count,
+                hg_mapping_count,
+                blobstore_bytes_read,
+                None,
+            ),
+            Ok(RepoOutcome::Estimated(estimate)) => {
+                estimated_bytes = Some(estimate.filenodes_bytes);
+                (estimate.filenodes_count as usize, 0, 0, None)
+            }
+            Ok(RepoOutcome::Resumed { age }) => {
+                let reason = format!(
+                    "a snapshot built {} ago already exists",
+                    humantime::format_duration(Duration::from_secs(age.as_secs()))
+                );
+                info!(logger, "Skipped repo {}: {}", name, reason);
+                resumed.push((
+                    index,
+                    SkippedRepo {
+                        repo_name: name,
+                        reason,
+                    },
+                ));
+                continue;
+            }
+            Ok(RepoOutcome::NotStarted) => {
+                not_started.push((
+                    index,
+                    SkippedRepo {
+                        repo_name: name,
+                        reason: "the soft deadline passed before it was started".to_string(),
+                    },
+                ));
+                continue;
+            }
+            Ok(RepoOutcome::Skipped { reason }) => {
+                nothing_to_warm.push((
+                    index,
+                    SkippedRepo {
+                        repo_name: name,
+                        reason,
+                    },
+                ));
+                continue;
+            }
+            Err(MicrowaveError::Cancelled) => {
+                cancelled = true;
+                break;
+            }
+            Err(e) => (0, 0, 0, Some(e)),
+        };
+        summaries.push((
+            index,
+            RepoSummary {
+                repo_name: name.clone(),
+                repo_id,
+                success: error.is_none(),
+                filenodes_count,
+                hg_mapping_count,
+                blobstore_bytes_read,
+                estimated_bytes,
+                elapsed_ms: elapsed.as_millis() as u64,
+                error: error.as_ref().map(error_chain),
+            },
+        ));
+        if let Some(e) = error {
+            failures.push((index, (name, e)));
+            consecutive_failures += 1;
+            if args.fail_fast {
+                break;
+            }
+            // Dropping the stream drops the builds of the repos that are still running, which
+            // aborts their warmup, and those that were not started yet are never started.
+            if args
+                .failure_threshold
+                .map_or(false, |threshold| consecutive_failures > threshold.get())
+            {
+                circuit_open = true;
+                break;
+            }
+        } else {
+            consecutive_failures = 0;
+        }
+    }
+    let summaries = in_submission_order(summaries);
+    let mut failures = in_submission_order(failures);
+    let resumed = in_submission_order(resumed);
+    let not_started = in_submission_order(not_started);
+    let nothing_to_warm = in_submission_order(nothing_to_warm);
 
-                snapshot.commit(&ctx, &repo.blob_repo, location).await?;
+    let mut untried = Vec::new();
+    if circuit_open {
+        let done = summaries
+            .iter()
+            .map(|s| s.repo_name.as_str())
+            .chain(resumed.iter().map(|s| s.repo_name.as_str()))
+            .chain(not_started.iter().map(|s| s.repo_name.as_str()))
+            .chain(nothing_to_warm.iter().map(|s| s.repo_name.as_str()))
+            .collect::<HashSet<_>>();
+        untried = repo_names
+            .into_iter()
+            .filter(|name| !done.contains(name.as_str()))
+            .collect::<Vec<_>>();
+        error!(
+            logger,
+            "Circuit open: {} repos failed in a row, not trying the {} remaining repos: {}",
+            consecutive_failures,
+            untried.len(),
+            untried.join(", ")
+        );
+    }
 
-                Result::<_, Error>::Ok(())
-            }
+    let skipped = app
+        .skipped_repo_configs()
+        .iter()
+        .map(|(repo_name, e)| SkippedRepo {
+            repo_name: repo_name.clone(),
+            reason: format!("{:#}", e),
         })
         .collect::<Vec<_>>();
+    for skipped in &skipped {
+        warn!(
+            logger,
+            "Skipped repo {} because of its config: {}", skipped.repo_name, skipped.reason
+        );
+    }
+    if !not_started.is_empty() {
+        warn!(
+            logger,
+            "Soft deadline passed, {} repos were not started: {}",
+            not_started.len(),
+            not_started
+                .iter()
+                .map(|skipped| skipped.repo_name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    let skipped = skipped
+        .into_iter()
+        .chain(unconfigured)
+        .chain(resumed)
+        .chain(not_started)
+        .chain(nothing_to_warm)
+        .chain(untried.iter().map(|name| SkippedRepo {
+            repo_name: name.clone(),
+            reason: "the circuit breaker tripped before it was done".to_string(),
+        }))
+        .collect::<Vec<_>>();
 
-    future::try_join_all(futs).await?;
+    let blobstore_bytes_read = summaries
+        .iter()
+        .map(|s| s.blobstore_bytes_read)
+        .sum::<u64>();
+    info!(
+        logger,
+        "Read {} bytes from the blobstore during warmup across all repos", blobstore_bytes_read
+    );
 
-    Ok(())
+    let estimated_bytes = match &args.command {
+        Commands::Estimate => Some(summaries.iter().filter_map(|s| s.estimated_bytes).sum()),
+        _ => None,
+    };
+    if let (Some(total_bytes), OutputFormat::Text) = (estimated_bytes, args.output_format) {
+        for summary in summaries.iter().filter(|s| s.success) {
+            println!(
+                "{}: {} filenodes, about {} bytes",
+                summary.repo_name,
+                summary.filenodes_count,
+                summary.estimated_bytes.unwrap_or_default()
+            );
+        }
+        println!(
+            "total: {} filenodes, about {} bytes",
+            summaries.iter().map(|s| s.filenodes_count).sum::<usize>(),
+            total_bytes
+        );
+    }
+
+    if args.output_format == OutputFormat::Json {
+        let summary = RunSummary {
+            repos: summaries,
+            blobstore_bytes_read,
+            estimated_bytes,
+            circuit_open,
+            skipped,
+        };
+        let summary =
+            serde_json::to_string_pretty(&summary).map_err(|e| MicrowaveError::Output(e.into()))?;
+        println!("{}", summary);
+    }
+
+    if cancelled {
+        return Err(MicrowaveError::Cancelled);
+    }
+
+    if args.fail_fast {
+        if let Some((_name, e)) = failures.pop() {
+            return Err(e);
+        }
+    }
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    for (name, e) in &failures {
+        error!(
+            logger,
+            "Failed to build snapshot for repo {}: {}",
+            name,
+            error_chain(e)
+        );
+    }
+
+    if circuit_open {
+        return Err(MicrowaveError::CircuitOpen {
+            consecutive: consecutive_failures,
+            untried,
+        });
+    }
+
+    Err(MicrowaveError::RepoFailures(failures))
 }
 
 #[derive(Parser)]
-#[clap(name = "Mononoke Local Replay")]
+#[clap(
+    name = "Mononoke Local Replay",
+    after_help = "Where noted, options can also be set through MICROWAVE_* environment variables, \
+                  with comma-separated values for those that may be repeated. Options passed on \
+                  the command line take precedence."
+)]
 struct MononokeMicrowaveArgs {
+    /// Run warmup and build snapshots, but do not commit them
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Fail the repos whose snapshot has no filenodes rather than committing it, as this usually
+    /// means their warmup is misconfigured. Deltas are exempt, as they are empty when nothing
+    /// has changed. Repos with none of the requested derived data types enabled fail too, rather
+    /// than being skipped.
+    #[clap(long)]
+    fail_on_empty: bool,
+
+    /// Only build snapshots for the repo with this name (may be repeated)
+    #[clap(
+        long = "repo-name",
+        env = "MICROWAVE_REPO_NAMES",
+        value_delimiter = ','
+    )]
+    repo_names: Vec<String>,
+
+    /// Only build snapshots for the repo with this id (may be repeated)
+    #[clap(long = "repo-id", env = "MICROWAVE_REPO_IDS", value_delimiter = ',')]
+    repo_ids: Vec<i32>,
+
+    /// Only build snapshots for the repos with at least this id
+    #[clap(long, env = "MICROWAVE_REPO_ID_MIN")]
+    repo_id_min: Option<i32>,
+
+    /// Only build snapshots for the repos with at most this id
+    #[clap(long, env = "MICROWAVE_REPO_ID_MAX")]
+    repo_id_max: Option<i32>,
+
+    /// Override a field of the config of every repo, as KEY=VALUE, to try out a config change
+    /// before it lands (may be repeated). Only some fields that affect warmup can be overridden:
+    /// cache_warmup.bookmark, cache_warmup.commit_limit, cache_warmup.microwave_preload and
+    /// generation_cache_size.
+    #[clap(long = "repo-config-override", value_parser = RepoConfigOverride::from_str)]
+    repo_config_overrides: Vec<RepoConfigOverride>,
+
+    /// Only build snapshots for the repos listed in this file, one per line
+    #[clap(long, env = "MICROWAVE_REPO_LIST_FILE")]
+    repo_list_file: Option<PathBuf>,
+
+    /// Maximum number of repos to warm up concurrently. All of them share the runtime's worker
+    /// threads (see `--worker-threads`), so raising this beyond the number of workers mostly adds
+    /// memory use rather than throughput
+    #[clap(long, default_value = "4", env = "MICROWAVE_MAX_CONCURRENT_REPOS")]
+    max_concurrent_repos: NonZeroUsize,
+
+    /// Only choose the warmup target by these derived data types (may be repeated). Types that
+    /// are not enabled for a repo are skipped.
+    #[clap(long = "derived-data-type")]
+    derived_data_types: Vec<String>,
+
+    /// Enable this derived data type for the repos, in addition to those enabled in their config
+    /// (may be repeated). This only applies to this run.
+    #[clap(long = "enable-derived-data-type")]
+    enable_derived_data_types: Vec<String>,
+
+    /// Do not warm this derived data type for the repos, even if it is enabled in their config
+    /// (may be repeated). Types that are not enabled for a repo are ignored with a warning.
+    #[clap(long = "skip-derived-data-type")]
+    skip_derived_data_types: Vec<String>,
+
+    /// Number of filenodes that can be buffered while waiting to be added to a snapshot
+    #[clap(long, default_value = "1000")]
+    filenodes_buffer_size: NonZeroUsize,
+
+    /// Number of channels to split filenodes across while they are being recorded
+    #[clap(long, default_value = "1")]
+    filenodes_shards: NonZeroUsize,
+
+    /// Only store each filenode once. This keeps track of every filenode that is recorded, so it
+    /// uses more memory while building the snapshot.
+    #[clap(long)]
+    dedupe: bool,
+
+    /// Stop recording filenodes once a snapshot has this many. Warmup stops early, and the
+    /// snapshot is marked as partial.
+    #[clap(long)]
+    max_filenodes: Option<usize>,
+
+    /// Like --max-filenodes, but stop once what has been recorded for a repo takes up about this
+    /// many bytes in memory. This keeps one large repo from exhausting memory for all of them.
+    #[clap(long)]
+    per_repo_memory_cap: Option<u64>,
+
+    /// Only record 1 in this many filenodes, to cheaply estimate what a full snapshot would hold.
+    /// The snapshot is marked as sampled.
+    #[clap(long)]
+    sample_rate: Option<NonZeroU64>,
+
+    /// Do not record the filenodes whose path matches this glob, e.g. `**/node_modules/**` (may
+    /// be repeated). Paths are relative to the root of the repo.
+    #[clap(long = "exclude-path")]
+    exclude_paths: Vec<String>,
+
+    /// Sort the recorded filenodes and changesets, so that runs against the same repo state
+    /// produce identical snapshots apart from their timestamp. Sorting takes time proportional
+    /// to the size of the snapshot once warmup has finished.
+    #[clap(long)]
+    deterministic: bool,
+
+    /// How snapshots are serialized: thrift, or json-lines for tools that cannot read thrift.
+    /// Snapshots in either format can be loaded, so this can be changed at any time.
+    #[clap(long, value_parser = SnapshotFormat::from_str, default_value = "thrift")]
+    snapshot_format: SnapshotFormat,
+
+    /// Log every filenode as it is recorded, at debug level. This is very noisy, and is meant for
+    /// debugging a single repo.
+    #[clap(long)]
+    trace_filenodes: bool,
+
+    /// Also capture the changesets read during warmup, so that servers can prime their changesets
+    /// cache. This makes snapshots larger.
+    #[clap(long)]
+    capture_changesets: bool,
+
+    /// Also capture the publishing bookmarks, so that servers can prime their bookmarks cache
+    #[clap(long)]
+    capture_bookmarks: bool,
+
+    /// Also capture the bonsai to hg mappings read during warmup, so that servers can prime their
+    /// mapping cache
+    #[clap(long)]
+    capture_hg_mapping: bool,
+
+    /// Also capture the keys of the blobs read during warmup, so that servers can prefetch them
+    #[clap(long)]
+    capture_content: bool,
+
+    /// Capture the contents of those blobs as well, so that they can be loaded without the
+    /// blobstore. This can make snapshots much larger.
+    #[clap(long, requires = "capture_content")]
+    capture_content_bytes: bool,
+
+    /// Also capture the content size of each file, so that servers can plan the order they
+    /// prefetch in. This reads the envelope of every file filenode that is recorded.
+    #[clap(long)]
+    capture_sizes: bool,
+
+    /// How often to log progress while cache warmup is running, in seconds (0 to disable)
+    #[clap(long, default_value = "60")]
+    progress_interval_secs: u64,
+
+    /// Only store the filenodes that are not in the existing snapshot, as a delta on top of it
+    #[clap(long)]
+    incremental: bool,
+
+    /// Skip warmup for repos whose warmup bookmark has not moved since it was captured in the
+    /// existing snapshot, and commit that snapshot again instead. Reuse is all or nothing: a repo
+    /// whose bookmark has moved is warmed up again in full. A reused snapshot keeps everything
+    /// that was recorded in it, including its cache hit ratio, and only gets a new timestamp and
+    /// builder version. This needs the bookmarks to be captured, so that the next run can tell
+    /// whether they have moved.
+    #[clap(
+        long,
+        requires = "capture_bookmarks",
+        conflicts_with = "warmup_commits"
+    )]
+    reuse_unchanged_snapshot: bool,
+
+    /// Warm up from this bookmark instead of the one in the repo's cache warmup config. Repos
+    /// without a cache warmup config are then warmed up too.
+    #[clap(long)]
+    warmup_bookmark: Option<BookmarkName>,
+
+    /// Warm up from this commit, as a bonsai or hg changeset id, instead of from a bookmark (may
+    /// be repeated). Repos without a cache warmup config are then warmed up too.
+    #[clap(long = "warmup-commit", conflicts_with = "warmup_bookmark")]
+    warmup_commits: Vec<String>,
+
+    /// What to do with repos that have no cache warmup config, when neither --warmup-bookmark nor
+    /// --warmup-commit is given: skip them with a warning, or with `false`, fail the run
+    #[clap(long, default_value_t = true, action = ArgAction::Set)]
+    skip_unconfigured: bool,
+
+    /// Only warm up the paths that have changed within this long before now (e.g. `7d`)
+    #[clap(long, value_parser = humantime::parse_duration)]
+    warmup_since: Option<Duration>,
+
+    /// Number of manifest entries each repo fetches, deriving data as needed, in parallel during
+    /// warmup. The total across repos is this times --max-concurrent-repos.
+    #[clap(
+        long,
+        default_value_t = NonZeroUsize::new(cache_warmup::DEFAULT_CONCURRENCY).unwrap(),
+        env = "MICROWAVE_WARMUP_CONCURRENCY"
+    )]
+    warmup_concurrency: NonZeroUsize,
+
+    /// Only derive the data warmup needs for its target if it has not been derived yet, rather
+    /// than going through derivation regardless. Filenodes are recorded either way.
+    #[clap(long)]
+    only_missing_derived_data: bool,
+
+    /// Take the shared derived data lease when deriving data during warmup, so that warmups for
+    /// the same repo on different hosts wait for each other rather than deriving the same data
+    #[clap(long)]
+    use_derived_data_lease: bool,
+
+    /// Limit the blobstore reads made during warmup to this many per second, across all repos
+    #[clap(long)]
+    warmup_blobstore_qps: Option<NonZeroU32>,
+
+    /// Give up on a repo if building its snapshot takes longer than this, in seconds (0 for no
+    /// timeout)
+    #[clap(long, default_value = "0", env = "MICROWAVE_WARMUP_TIMEOUT_SECS")]
+    warmup_timeout_secs: u64,
+
+    /// Give up on a repo if no filenode is recorded for this long while it is warming up, in
+    /// seconds (0 to disable). This catches hangs that the overall timeout would only catch much
+    /// later, but must allow for the time warmup takes before it reads the first filenode.
+    #[clap(long, default_value = "0", env = "MICROWAVE_STALL_TIMEOUT_SECS")]
+    stall_timeout_secs: u64,
+
+    /// Stop starting repos once the run has gone on for this many seconds (0 for no deadline).
+    /// Repos that were already started still finish and commit their snapshots, and those that
+    /// were not are listed as skipped in the summary.
+    #[clap(long, default_value = "0", env = "MICROWAVE_SOFT_DEADLINE_SECS")]
+    soft_deadline_secs: u64,
+
+    /// Read from the blobstore of each repo as soon as it is open, so that unreachable storage or
+    /// missing credentials fail the repo right away rather than partway through warmup
+    #[clap(long)]
+    preflight: bool,
+
+    /// Number of times to retry opening a repo when it fails in a way that looks transient, such
+    /// as its database being briefly unreachable
+    #[clap(long, default_value = "2")]
+    open_retries: usize,
+
+    /// Number of times to retry transient errors when committing a snapshot to the blobstore
+    #[clap(long, default_value = "3")]
+    commit_retries: usize,
+
+    /// Read each snapshot back once it is committed, and fail the repo unless it holds the same
+    /// filenodes and contents as the snapshot that was built
+    #[clap(long)]
+    verify_after_commit: bool,
+
+    /// Identity attached to the requests made while building snapshots, so that they can be told
+    /// apart from serving traffic
+    #[clap(long, default_value = "microwave")]
+    client_identity: String,
+
+    /// Abort as soon as any repo fails, instead of building snapshots for the remaining repos
+    #[clap(long)]
+    fail_fast: bool,
+
+    /// Stop trying the remaining repos once more than this many repos have failed in a row, in
+    /// the order they finish, so that storage that is having trouble is not hit by every repo in
+    /// turn
+    #[clap(long)]
+    failure_threshold: Option<NonZeroUsize>,
+
+    /// Format of the summary printed to stdout once all repos are done
+    #[clap(long, arg_enum, default_value = "text")]
+    output_format: OutputFormat,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
+#[derive(Copy, Clone, Debug, ArgEnum, Eq, PartialEq)]
+enum OutputFormat {
+    /// Only log progress and results
+    Text,
+    /// Also print a JSON summary of each repo's snapshot. For `diff`, `show-config` and `stats`,
+    /// print their output as JSON
+    Json,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     #[clap(name = "local-path", about = "Write cache priming data to path")]
@@ -193,24 +1760,348 @@ enum Commands {
         name = "blobstore",
         about = "Write cache priming data to the repository blobstore"
     )]
-    Blobstore,
+    Blobstore(BlobstoreLocation),
+    #[clap(
+        name = "object-store",
+        about = "Write cache priming data to an S3-compatible object store"
+    )]
+    ObjectStore(ObjectStore),
+    #[clap(
+        name = "verify",
+        about = "Check that filenodes in existing snapshots match the live filenodes"
+    )]
+    Verify(Verify),
+    #[clap(
+        name = "gc",
+        about = "Delete snapshots in the repository blobstore that are older than a given age"
+    )]
+    Gc(Gc),
+    #[clap(
+        name = "selftest",
+        about = "Check that each repo can be opened and read from, without running warmup"
+    )]
+    Selftest,
+    #[clap(
+        name = "merge",
+        about = "Merge several local snapshots of the same repo into one"
+    )]
+    Merge(Merge),
+    #[clap(name = "diff", about = "Compare the filenodes in two local snapshots")]
+    Diff(Diff),
+    #[clap(
+        name = "show-config",
+        about = "Print the repos a run would build snapshots for and their storage, without \
+                 opening them"
+    )]
+    ShowConfig,
+    #[clap(
+        name = "stats",
+        about = "Count the filenodes in a local snapshot by directory"
+    )]
+    Stats(Stats),
+    #[clap(
+        name = "touch",
+        about = "Update the timestamp of existing snapshots without rebuilding them, so that gc \
+                 keeps them"
+    )]
+    Touch(Touch),
+    #[clap(
+        name = "stdout",
+        about = "Write the cache priming data of a single repo to standard output"
+    )]
+    Stdout(Stdout),
+    #[clap(
+        name = "estimate",
+        about = "Run warmup and report how large each snapshot would be, without building any"
+    )]
+    Estimate,
+}
+
+#[derive(Args)]
+struct Stdout {
+    /// Compress the snapshot using gzip
+    #[clap(long)]
+    compress: bool,
+}
+
+#[derive(Args)]
+struct Touch {
+    /// Touch snapshots stored in this path instead of the repository blobstore
+    #[clap(long)]
+    local_path: Option<PathBuf>,
+
+    /// Prefix for the keys of the snapshots in the repository blobstore
+    #[clap(long, conflicts_with = "local_path")]
+    blobstore_key_prefix: Option<String>,
+
+    /// Decrypt snapshots in the local path with the key in this file, and encrypt them again once
+    /// touched
+    #[clap(long, requires = "local_path")]
+    encrypt_key_file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct Stats {
+    /// Path of the snapshot
+    #[clap(value_parser)]
+    snapshot: PathBuf,
+
+    /// Number of leading path components to group filenodes by. 0 counts them all together
+    #[clap(long, default_value = "1")]
+    depth: usize,
+}
+
+#[derive(Args)]
+struct Diff {
+    /// Path of the first snapshot
+    #[clap(value_parser)]
+    a: PathBuf,
+
+    /// Path of the second snapshot
+    #[clap(value_parser)]
+    b: PathBuf,
+}
+
+#[derive(Args)]
+struct Merge {
+    /// Paths of the snapshots to merge
+    #[clap(value_parser, required = true, min_values = 2)]
+    inputs: Vec<PathBuf>,
+
+    /// Path to write the merged snapshot to
+    #[clap(long, value_parser)]
+    output: PathBuf,
+
+    /// Compress the merged snapshot using gzip
+    #[clap(long)]
+    compress: bool,
+}
+
+#[derive(Args)]
+struct Gc {
+    /// Delete snapshots whose metadata is older than this (e.g. `30d`). `touch` resets the age of
+    /// a snapshot.
+    #[clap(long, value_parser = humantime::parse_duration)]
+    older_than: Duration,
+
+    /// Prefixes of the keys of the snapshots to consider (may be repeated). Snapshots stored
+    /// without a prefix are considered if none is given.
+    #[clap(long = "blobstore-key-prefix")]
+    blobstore_key_prefixes: Vec<String>,
+
+    /// Only list the snapshots that would be deleted
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct Verify {
+    /// Verify snapshots stored in this path instead of the repository blobstore
+    #[clap(long)]
+    local_path: Option<PathBuf>,
+
+    /// Prefix for the keys of the snapshots in the repository blobstore
+    #[clap(long, conflicts_with = "local_path")]
+    blobstore_key_prefix: Option<String>,
+
+    /// Decrypt snapshots in the local path with the key in this file
+    #[clap(long, requires = "local_path")]
+    encrypt_key_file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct BlobstoreLocation {
+    /// Prefix for the keys of the snapshots, to keep them apart from other snapshots in the same
+    /// blobstore
+    #[clap(long, env = "MICROWAVE_BLOBSTORE_KEY_PREFIX")]
+    blobstore_key_prefix: Option<String>,
+
+    /// For a multiplexed blobstore, write snapshots only to the inner store with this id rather
+    /// than through the multiplex (may be repeated)
+    #[clap(long = "blobstore-id")]
+    blobstore_ids: Vec<u64>,
+
+    /// Once a snapshot is committed, point the latest pointer at its key prefix, so that servers
+    /// loading the latest snapshot switch to it
+    #[clap(long)]
+    update_latest: bool,
+
+    /// Skip repos that already have a snapshot built within --resume-window, so that a run that
+    /// was interrupted can be restarted without redoing every repo
+    #[clap(long)]
+    resume: bool,
+
+    /// How recent an existing snapshot must be for --resume to skip its repo
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "1day")]
+    resume_window: Duration,
+}
+
+#[derive(Args)]
+struct ObjectStore {
+    /// Bucket to write snapshots to
+    #[clap(long, env = "MICROWAVE_BUCKET")]
+    bucket: String,
+
+    /// Prefix for the keys of the snapshots in the bucket
+    #[clap(long, default_value = "", env = "MICROWAVE_KEY_PREFIX")]
+    key_prefix: String,
 }
 
 #[derive(Args)]
 struct LocalPath {
-    #[clap(name = "local-path", value_parser)]
+    #[clap(name = "local-path", value_parser, env = "MICROWAVE_LOCAL_PATH")]
     local_path: PathBuf,
+
+    /// Compress snapshots using gzip
+    #[clap(long)]
+    compress: bool,
+
+    /// Also write a JSON manifest describing each snapshot next to it
+    #[clap(long)]
+    write_manifest: bool,
+
+    /// Encrypt snapshots with AES-256-GCM, using the 32 byte key in this file. Snapshots built
+    /// incrementally on top of an encrypted snapshot need the same key.
+    #[clap(long)]
+    encrypt_key_file: Option<PathBuf>,
 }
 
 #[fbinit::main]
 fn main(fb: FacebookInit) -> Result<(), Error> {
+    let deadline = DeadlineAppExtension::new();
     let app = MononokeAppBuilder::new(fb)
         .with_app_extension(Fb303AppExtension {})
+        .with_app_extension(ScubaSamplingAppExtension {})
+        .with_app_extension(RunIdAppExtension {})
+        .with_app_extension(deadline.clone())
+        .with_app_extension(WarmupLogLevelAppExtension::default())
         .with_arg_defaults(BlobstoreArgDefaults {
             put_behaviour: Some(PutBehaviour::Overwrite),
             ..Default::default()
         })
         .build::<MononokeMicrowaveArgs>()?;
 
-    app.run_with_monitoring_and_logging(async_main, "microwave", AliveService)
+    let exit_code = AtomicI32::new(0);
+    let res = app.run_with_monitoring_and_logging(
+        |app| {
+            let exit_code = &exit_code;
+            async move {
+                // The deadline shuts the app down through this token too.
+                let cancellation = app.environment().shutdown.clone();
+                cancel_on_signal(app.logger().clone(), cancellation.clone())?;
+                async_main(app, cancellation).await.map_err(|e| -> Error {
+                    exit_code.store(e.exit_code(), Ordering::Relaxed);
+                    e.into()
+                })
+            }
+        },
+        "microwave",
+        AliveService,
+    );
+
+    // The deadline cancels the run the same way SIGTERM does, but is reported separately.
+    if deadline.exceeded() {
+        std::process::exit(EXIT_CODE_DEADLINE_EXCEEDED);
+    }
+    match (exit_code.load(Ordering::Relaxed), res) {
+        (code, Err(e)) if code > 1 => {
+            // Cancellation is not worth reporting as an error.
+            if !matches!(
+                e.downcast_ref::<MicrowaveError>(),
+                Some(MicrowaveError::Cancelled)
+            ) {
+                eprintln!("Error: {:?}", e);
+            }
+            std::process::exit(code);
+        }
+        (_, res) => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mononoke_types::RepositoryId;
+
+    use super::*;
+
+    fn test_repos() -> HashMap<String, RepoConfig> {
+        ["a", "b", "c"]
+            .into_iter()
+            .enumerate()
+            .map(|(id, name)| {
+                let config = RepoConfig {
+                    repoid: RepositoryId::new(id as i32),
+                    ..Default::default()
+                };
+                (name.to_string(), config)
+            })
+            .collect()
+    }
+
+    fn names(repos: Vec<(String, RepoConfig)>) -> Vec<String> {
+        let mut names = repos.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_select_repos() -> Result<(), Error> {
+        assert_eq!(
+            names(select_repos(test_repos(), &[], &[])?),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            names(select_repos(test_repos(), &["a".to_string()], &[2])?),
+            ["a", "c"]
+        );
+        assert_eq!(names(select_repos(test_repos(), &[], &[1])?), ["b"]);
+
+        assert!(select_repos(test_repos(), &["d".to_string()], &[]).is_err());
+        assert!(select_repos(test_repos(), &[], &[3]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_repo_list_file() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("repos");
+
+        tokio::fs::write(&path, "# Repos to warm up\na\n\n  c  \n").await?;
+        assert_eq!(read_repo_list_file(&path, &test_repos()).await?, ["a", "c"]);
+
+        tokio::fs::write(&path, "a\nd\n").await?;
+        let e = read_repo_list_file(&path, &test_repos()).await.unwrap_err();
+        assert!(e.to_string().contains("line 2"));
+
+        assert!(
+            read_repo_list_file(&dir.path().join("missing"), &test_repos())
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_transient_open_error() {
+        let refused = Error::from(std::io::Error::from(ErrorKind::ConnectionRefused));
+        assert!(is_transient_open_error(&refused));
+        assert!(is_transient_open_error(
+            &refused.context("Failed to open repo")
+        ));
+
+        let busy = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            None,
+        );
+        assert!(is_transient_open_error(&busy.into()));
+
+        let not_found = Error::from(std::io::Error::from(ErrorKind::NotFound));
+        assert!(!is_transient_open_error(&not_found));
+        // Errors are no longer told apart by their message.
+        assert!(!is_transient_open_error(&Error::msg(
+            "Invalid config: connection string is missing"
+        )));
+    }
 }
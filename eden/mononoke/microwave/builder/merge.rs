@@ -0,0 +1,54 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use futures::future;
+use microwave::Snapshot;
+use mononoke_app::MononokeApp;
+use slog::info;
+
+/// Merge the snapshots in `inputs`, which must all be for the same repo, and write the result to
+/// `output`.
+pub async fn merge_snapshots(
+    app: &MononokeApp,
+    inputs: &[PathBuf],
+    output: &Path,
+    compress: bool,
+) -> Result<(), Error> {
+    let ctx = app.new_basic_context();
+
+    let snapshots = future::try_join_all(inputs.iter().map(|path| {
+        let ctx = &ctx;
+        async move {
+            Snapshot::read_file(ctx, path)
+                .await
+                .with_context(|| format!("Failed to read snapshot {}", path.display()))?
+                .ok_or_else(|| {
+                    format_err!("{} has no header, rebuild it to merge it", path.display())
+                })
+        }
+    }))
+    .await?;
+
+    let merged = Snapshot::merge(snapshots)?;
+    merged.write_file(output, compress).await?;
+
+    info!(
+        ctx.logger(),
+        "Merged {} snapshots with {} filenodes into {}",
+        inputs.len(),
+        merged.metadata().filenodes_count,
+        output.display()
+    );
+
+    Ok(())
+}
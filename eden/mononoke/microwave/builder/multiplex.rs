@@ -0,0 +1,88 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::Arc;
+
+use anyhow::bail;
+use anyhow::format_err;
+use anyhow::Error;
+use blobstore::Blobstore;
+use blobstore_factory::default_scrub_handler;
+use blobstore_factory::make_blobstore;
+use metaconfig_types::BlobConfig;
+use metaconfig_types::BlobstoreId;
+use mononoke_app::MononokeApp;
+use mononoke_types::RepositoryId;
+use prefixblob::PrefixBlobstore;
+
+/// Find the configs of the inner stores of this multiplexed blobstore with these ids. Fails if the
+/// blobstore is not multiplexed, or if any of the ids is not part of the multiplex.
+pub fn inner_blobstore_configs(
+    blobconfig: BlobConfig,
+    ids: &[BlobstoreId],
+) -> Result<Vec<(BlobstoreId, BlobConfig)>, Error> {
+    let blobstores = match blobconfig {
+        BlobConfig::Multiplexed { blobstores, .. }
+        | BlobConfig::MultiplexedWal { blobstores, .. } => blobstores,
+        _ => bail!("--blobstore-id was given but the repo blobstore is not multiplexed"),
+    };
+
+    ids.iter()
+        .map(|id| {
+            blobstores
+                .iter()
+                .find(|(blobstore_id, _, _)| blobstore_id == id)
+                .map(|(_, _, config)| (*id, config.clone()))
+                .ok_or_else(|| {
+                    format_err!(
+                        "Unknown blobstore id {}, expected one of: {}",
+                        id,
+                        blobstores
+                            .iter()
+                            .map(|(blobstore_id, _, _)| blobstore_id.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                })
+        })
+        .collect()
+}
+
+/// The configs of all the stores that blobs are physically written to: the inner stores if this
+/// blobstore is multiplexed, or the blobstore itself otherwise.
+pub fn physical_blobstore_configs(blobconfig: BlobConfig) -> Vec<BlobConfig> {
+    match blobconfig {
+        BlobConfig::Multiplexed { blobstores, .. }
+        | BlobConfig::MultiplexedWal { blobstores, .. } => blobstores
+            .into_iter()
+            .map(|(_, _, config)| config)
+            .collect(),
+        blobconfig => vec![blobconfig],
+    }
+}
+
+/// Open an inner store of a multiplex, with keys prefixed the same way as in the repository
+/// blobstore.
+pub async fn open_inner_blobstore(
+    app: &MononokeApp,
+    repo_id: RepositoryId,
+    blobconfig: BlobConfig,
+) -> Result<PrefixBlobstore<Arc<dyn Blobstore>>, Error> {
+    let blobstore = make_blobstore(
+        app.fb,
+        blobconfig,
+        app.mysql_options(),
+        *app.readonly_storage(),
+        app.blobstore_options(),
+        app.logger(),
+        app.config_store(),
+        &default_scrub_handler(),
+        None,
+    )
+    .await?;
+    Ok(PrefixBlobstore::new(blobstore, repo_id.prefix()))
+}
@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::num::NonZeroUsize;
+
+use anyhow::format_err;
+use anyhow::Error;
+use blobstore::Blobstore;
+use context::CoreContext;
+use futures::stream;
+use futures::stream::StreamExt;
+use metaconfig_types::RepoConfig;
+use mononoke_api_types::InnerRepo;
+use mononoke_app::MononokeApp;
+use slog::error;
+use slog::info;
+
+use crate::repo_context;
+
+/// A key that is read to check that the blobstore is reachable. It does not need to exist.
+const SELFTEST_KEY: &str = "microwave_selftest";
+
+/// Read a key from the blobstore of the repo, to check that its storage is reachable with the
+/// credentials we have.
+pub async fn ping_blobstore(ctx: &CoreContext, repo: &InnerRepo) -> Result<(), Error> {
+    repo.blob_repo.blobstore().get(ctx, SELFTEST_KEY).await?;
+    Ok(())
+}
+
+/// Open each repo and read from its blobstore, without running warmup. Fails if any repo could
+/// not be opened or read from.
+pub async fn selftest_repos(
+    app: &MononokeApp,
+    repos: Vec<(String, RepoConfig)>,
+    max_concurrent_repos: NonZeroUsize,
+    client_identity: &str,
+) -> Result<(), Error> {
+    let common_config = &app.repo_configs().common;
+
+    let results = stream::iter(repos)
+        .map(|(name, config)| async move {
+            let ctx = repo_context(app, &name, client_identity);
+            let res = async {
+                let repo: InnerRepo = app
+                    .repo_factory()
+                    .build(name.clone(), config, common_config.clone())
+                    .await?;
+                ping_blobstore(&ctx, &repo).await
+            }
+            .await;
+
+            match &res {
+                Ok(()) => info!(ctx.logger(), "Self-test passed"),
+                Err(e) => error!(ctx.logger(), "Self-test failed: {:?}", e),
+            }
+            (name, res)
+        })
+        .buffered(max_concurrent_repos.get())
+        .collect::<Vec<_>>()
+        .await;
+
+    let failed = results
+        .into_iter()
+        .filter_map(|(name, res)| res.err().map(|_| name))
+        .collect::<Vec<_>>();
+
+    if !failed.is_empty() {
+        return Err(format_err!(
+            "Self-test failed for {} repos: {}",
+            failed.len(),
+            failed.join(", ")
+        ));
+    }
+
+    info!(app.logger(), "Self-test passed for all repos");
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::Error;
+use metaconfig_types::BlobConfig;
+use metaconfig_types::BlobstoreId;
+use metaconfig_types::MetadataDatabaseConfig;
+use metaconfig_types::MultiplexedStoreType;
+use metaconfig_types::RepoConfig;
+use serde::Serialize;
+
+/// What a run would touch for a single repo, as printed by `show-config`.
+#[derive(Serialize)]
+struct RepoStorage {
+    repo_name: String,
+    repo_id: i32,
+    blobstore: String,
+    metadata: &'static str,
+}
+
+fn describe_inner(blobstores: &[(BlobstoreId, MultiplexedStoreType, BlobConfig)]) -> String {
+    blobstores
+        .iter()
+        .map(|(id, _, config)| format!("{}: {}", id, describe_blobstore(config)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A short description of the kind of blobstore, including the inner stores of a multiplex.
+fn describe_blobstore(blobconfig: &BlobConfig) -> String {
+    match blobconfig {
+        BlobConfig::Disabled => "disabled".to_string(),
+        BlobConfig::Files { path } => format!("files ({})", path.display()),
+        BlobConfig::Sqlite { path } => format!("sqlite ({})", path.display()),
+        BlobConfig::Manifold { bucket, .. } => format!("manifold ({})", bucket),
+        BlobConfig::ManifoldWithTtl { bucket, .. } => format!("manifold with ttl ({})", bucket),
+        BlobConfig::Mysql { .. } => "mysql".to_string(),
+        BlobConfig::S3 { bucket, .. } => format!("s3 ({})", bucket),
+        BlobConfig::Multiplexed { blobstores, .. } => {
+            format!("multiplexed [{}]", describe_inner(blobstores))
+        }
+        BlobConfig::MultiplexedWal { blobstores, .. } => {
+            format!("multiplexed wal [{}]", describe_inner(blobstores))
+        }
+        BlobConfig::Logging { blobconfig, .. } => describe_blobstore(blobconfig),
+        BlobConfig::Pack { blobconfig, .. } => format!("packed {}", describe_blobstore(blobconfig)),
+    }
+}
+
+/// Print the repos a run would build snapshots for, along with their storage, without opening
+/// any of them.
+pub fn show_config(repos: &[(String, RepoConfig)], json: bool) -> Result<(), Error> {
+    let repos = repos
+        .iter()
+        .map(|(name, config)| RepoStorage {
+            repo_name: name.clone(),
+            repo_id: config.repoid.id(),
+            blobstore: describe_blobstore(&config.storage_config.blobstore),
+            metadata: match config.storage_config.metadata {
+                MetadataDatabaseConfig::Local(_) => "local",
+                MetadataDatabaseConfig::Remote(_) => "remote",
+            },
+        })
+        .collect::<Vec<_>>();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&repos)?);
+    } else {
+        for repo in &repos {
+            println!(
+                "{} (id {}): blobstore {}, {} metadata",
+                repo.repo_name, repo.repo_id, repo.blobstore, repo.metadata
+            );
+        }
+    }
+
+    Ok(())
+}
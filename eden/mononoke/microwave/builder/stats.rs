@@ -0,0 +1,132 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use mononoke_types::MPath;
+use mononoke_types::RepoPath;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct DirectoryStats {
+    directory: String,
+    filenodes: u64,
+    /// Total content size of the files, if sizes were captured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SnapshotStats {
+    directories: Vec<DirectoryStats>,
+    total: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_content_bytes: Option<u64>,
+    /// Version of the binary that built the snapshot, if it was recorded.
+    builder_version: Option<String>,
+}
+
+/// The directory a filenode is counted under: the first `depth` components of the directory
+/// it is in, or of itself for a directory.
+fn group(path: RepoPath, depth: usize) -> Result<Option<MPath>, Error> {
+    let dir = match path {
+        RepoPath::RootPath => None,
+        RepoPath::DirectoryPath(path) => Some(path),
+        RepoPath::FilePath(path) => path.split_dirname().0,
+    };
+    match dir {
+        Some(dir) => dir.take_prefix_components(depth.min(dir.num_components())),
+        None => Ok(None),
+    }
+}
+
+/// Count the filenodes in the snapshot at `path` by directory, down to `depth` components, and
+/// print the counts from largest to smallest along with the total, as JSON if `json` is set.
+/// If the snapshot holds content sizes, those are added up by directory as well.
+/// Filenodes are read from the file one at a time, so only the counts are held in memory.
+pub async fn snapshot_stats(path: &Path, depth: usize, json: bool) -> Result<(), Error> {
+    let file = path.to_path_buf();
+    let (metadata, counts) = tokio::task::spawn_blocking(move || {
+        let mut counts = HashMap::<Option<MPath>, (u64, Option<u64>)>::new();
+        let metadata = microwave::scan_file_filenodes(&file, |filenode_path, content_size| {
+            let (filenodes, content_bytes) =
+                counts.entry(group(filenode_path, depth)?).or_default();
+            *filenodes += 1;
+            if let Some(size) = content_size {
+                *content_bytes = Some(content_bytes.unwrap_or_default() + size);
+            }
+            Ok(())
+        })?;
+        Result::<_, Error>::Ok((metadata, counts))
+    })
+    .await?
+    .with_context(|| format!("Failed to read snapshot {}", path.display()))?;
+    let metadata = metadata.ok_or_else(|| {
+        format_err!(
+            "{} has no header, rebuild it to get its stats",
+            path.display()
+        )
+    })?;
+
+    let mut directories = counts
+        .into_iter()
+        .map(|(directory, (filenodes, content_bytes))| DirectoryStats {
+            directory: directory.map_or_else(|| "/".to_string(), |dir| dir.to_string()),
+            filenodes,
+            content_bytes,
+        })
+        .collect::<Vec<_>>();
+    directories
+        .sort_unstable_by(|a, b| (b.filenodes, &a.directory).cmp(&(a.filenodes, &b.directory)));
+    let stats = SnapshotStats {
+        total: directories.iter().map(|dir| dir.filenodes).sum(),
+        total_content_bytes: directories
+            .iter()
+            .filter_map(|dir| dir.content_bytes)
+            .reduce(|a, b| a + b),
+        directories,
+        builder_version: metadata.builder_version.clone(),
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    match stats.total_content_bytes {
+        Some(total_content_bytes) => {
+            for dir in &stats.directories {
+                println!(
+                    "{:>12}  {:>16}  {}",
+                    dir.filenodes,
+                    dir.content_bytes.unwrap_or_default(),
+                    dir.directory
+                );
+            }
+            println!("{:>12}  {:>16}  total", stats.total, total_content_bytes);
+        }
+        None => {
+            for dir in &stats.directories {
+                println!("{:>12}  {}", dir.filenodes, dir.directory);
+            }
+            println!("{:>12}  total", stats.total);
+        }
+    }
+    println!(
+        "Built by version {}",
+        stats.builder_version.as_deref().unwrap_or("unknown")
+    );
+    if metadata.base_timestamp.is_some() {
+        println!("This is a delta snapshot, these are only the filenodes not in its base");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::num::NonZeroUsize;
+
+use anyhow::Context;
+use anyhow::Error;
+use futures::stream;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use metaconfig_types::RepoConfig;
+use microwave::SnapshotLocation;
+use mononoke_api_types::InnerRepo;
+use mononoke_app::MononokeApp;
+use slog::info;
+
+use crate::repo_context;
+
+/// Update the timestamp of the existing snapshot for each repo to now, so that `gc --older-than`
+/// keeps it for longer. The snapshots themselves are not rebuilt.
+pub async fn touch_repos(
+    app: &MononokeApp,
+    repos: Vec<(String, RepoConfig)>,
+    location: SnapshotLocation<'_>,
+    max_concurrent_repos: NonZeroUsize,
+    client_identity: &str,
+) -> Result<(), Error> {
+    let common_config = &app.repo_configs().common;
+
+    stream::iter(repos)
+        .map(|(name, config)| async move {
+            let ctx = repo_context(app, &name, client_identity);
+            async {
+                let repo: InnerRepo = app
+                    .repo_factory()
+                    .build(name.clone(), config, common_config.clone())
+                    .await?;
+                let metadata = microwave::touch_snapshot(&ctx, &repo.blob_repo, location).await?;
+                info!(
+                    ctx.logger(),
+                    "Touched snapshot with {} filenodes, its timestamp is now {}",
+                    metadata.filenodes_count,
+                    metadata.timestamp
+                );
+                Result::<_, Error>::Ok(())
+            }
+            .await
+            .with_context(|| format!("repo {}", name))
+        })
+        .buffered(max_concurrent_repos.get())
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    Ok(())
+}
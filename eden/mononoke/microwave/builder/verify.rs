@@ -0,0 +1,123 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::num::NonZeroUsize;
+
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use blobrepo::BlobRepo;
+use context::CoreContext;
+use filenodes::PreparedFilenode;
+use futures::future;
+use futures::stream;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use metaconfig_types::RepoConfig;
+use microwave::SnapshotLocation;
+use mononoke_api_types::InnerRepo;
+use mononoke_app::MononokeApp;
+use slog::info;
+use slog::warn;
+
+use crate::repo_context;
+
+/// Reload the snapshot for each repo and check every captured filenode against the live filenodes.
+/// Fails if any filenode is missing or differs.
+pub async fn verify_repos(
+    app: &MononokeApp,
+    repos: Vec<(String, RepoConfig)>,
+    location: SnapshotLocation<'_>,
+    max_concurrent_repos: NonZeroUsize,
+    client_identity: &str,
+) -> Result<(), Error> {
+    let common_config = &app.repo_configs().common;
+
+    let mismatches = stream::iter(repos)
+        .map(|(name, config)| async move {
+            let ctx = repo_context(app, &name, client_identity);
+            async {
+                let repo: InnerRepo = app
+                    .repo_factory()
+                    .build(name.clone(), config, common_config.clone())
+                    .await?;
+                verify_snapshot(&ctx, &repo.blob_repo, location).await
+            }
+            .await
+            .with_context(|| format!("repo {}", name))
+        })
+        .buffered(max_concurrent_repos.get())
+        .try_fold(0, |total, mismatches| future::ready(Ok(total + mismatches)))
+        .await?;
+
+    if mismatches > 0 {
+        return Err(format_err!("Found {} mismatched filenodes", mismatches));
+    }
+
+    Ok(())
+}
+
+async fn verify_snapshot(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+) -> Result<u64, Error> {
+    if let Some(manifest) = microwave::load_manifest(repo, location).await? {
+        info!(ctx.logger(), "Snapshot manifest: {:?}", manifest);
+    }
+    let builder_version = microwave::load_metadata(ctx, repo, location)
+        .await?
+        .and_then(|metadata| metadata.builder_version);
+    info!(
+        ctx.logger(),
+        "Snapshot was built by version {}",
+        builder_version.as_deref().unwrap_or("unknown")
+    );
+
+    // Filenodes are converted as they are checked, so that only the ones being checked are held
+    // besides the snapshot itself.
+    let filenodes = microwave::load_filenodes_lazily(ctx, repo, location).await?;
+
+    let (total, mismatches) = stream::iter(filenodes)
+        .map(|filenode| async move {
+            let PreparedFilenode { path, info } = filenode?;
+            let live = repo
+                .filenodes()
+                .get_filenode(ctx, &path, info.filenode)
+                .await?
+                .do_not_handle_disabled_filenodes()?;
+
+            let matches = live.as_ref() == Some(&info);
+            if !matches {
+                warn!(
+                    ctx.logger(),
+                    "Filenode {} for {} does not match: snapshot has {:?}, live has {:?}",
+                    info.filenode,
+                    path,
+                    info,
+                    live
+                );
+            }
+
+            Result::<_, Error>::Ok(matches)
+        })
+        .buffer_unordered(100)
+        .try_fold((0u64, 0), |(total, mismatches), matches| {
+            future::ready(Ok((
+                total + 1,
+                if matches { mismatches } else { mismatches + 1 },
+            )))
+        })
+        .await?;
+
+    info!(
+        ctx.logger(),
+        "Verified {} filenodes, found {} mismatches", total, mismatches
+    );
+
+    Ok(mismatches)
+}
@@ -20,6 +20,9 @@ struct FilenodeSnapshot {
   4: optional mercurial_thrift.HgNodeHash p2;
   5: optional CopyInfoSnapshot copyfrom;
   6: optional mercurial_thrift.HgNodeHash linknode;
+  // Size of the file's contents. Only present for files, and only if sizes
+  // were captured when building the snapshot.
+  7: optional i64 content_size;
 } (rust.exhaustive)
 
 struct CopyInfoSnapshot {
@@ -33,7 +36,80 @@ struct ChangesetSnapshot {
   3: optional i64 gen;
 } (rust.exhaustive)
 
+enum BookmarkKindSnapshot {
+  PUBLISHING = 1,
+  PULL_DEFAULT_PUBLISHING = 2,
+}
+
+struct BookmarkSnapshot {
+  1: optional string name;
+  2: optional BookmarkKindSnapshot kind;
+  3: optional mononoke_types_thrift.ChangesetId cs_id;
+} (rust.exhaustive)
+
+// A blob that was read during warmup. The contents are only present if they
+// were captured along with the key.
+struct BlobSnapshot {
+  1: optional string key;
+  2: optional binary data;
+  // Set if the blob is redacted. Its contents are never captured, and it is
+  // not prefetched when the snapshot is loaded.
+  3: optional bool redacted;
+} (rust.exhaustive)
+
+// A bonsai to hg changeset mapping that was read during warmup.
+struct HgMappingSnapshot {
+  1: optional mononoke_types_thrift.ChangesetId bcs_id;
+  2: optional mercurial_thrift.HgNodeHash hg_cs_id;
+} (rust.exhaustive)
+
+enum SnapshotBodyFormat {
+  COMPACT_THRIFT = 0,
+  JSON_LINES = 1,
+}
+
+// Stored ahead of the RepoSnapshot so it can be read without loading the
+// snapshot itself.
+struct SnapshotHeader {
+  1: optional i32 repo_id;
+  // Seconds since the Unix epoch.
+  2: optional i64 timestamp;
+  3: optional i64 filenodes_count;
+  // XxHash64 of the snapshot as stored after the header (i.e. after
+  // compression, if any).
+  4: optional i64 checksum;
+  // Fraction of warmup reads that were served from cache when the snapshot
+  // was built, if there were any reads.
+  5: optional double cache_hit_ratio;
+  // Only set for delta snapshots: the timestamp of the full snapshot they
+  // apply on top of.
+  6: optional i64 base_timestamp;
+  // Whether filenodes stopped being recorded because of a limit. Unset in
+  // snapshots built before limits were introduced.
+  7: optional bool truncated;
+  // How the snapshot after the header is serialized. Unset in snapshots built
+  // before other formats were introduced, which are all compact thrift.
+  8: optional SnapshotBodyFormat format;
+  // Only set for sampled snapshots: 1 in sample_rate of the filenodes warmup
+  // read were recorded.
+  9: optional i64 sample_rate;
+  // Version of the binary that wrote the snapshot, as the crate version
+  // followed by the build hash if there is one. Unset in snapshots built
+  // before it was recorded.
+  10: optional string builder_version;
+  // Number of bonsai to hg mappings in the snapshot. Unset if they were not
+  // captured.
+  11: optional i64 hg_mapping_count;
+} (rust.exhaustive)
+
 struct RepoSnapshot {
   1: optional list<FilenodeSnapshot> filenodes;
   2: optional list<ChangesetSnapshot> changesets;
+  // Only present if bookmarks were captured when building the snapshot.
+  3: optional list<BookmarkSnapshot> bookmarks;
+  // Only present if blobs were captured when building the snapshot.
+  4: optional list<BlobSnapshot> blobs;
+  // Only present if bonsai to hg mappings were captured when building the
+  // snapshot.
+  5: optional list<HgMappingSnapshot> hg_mapping;
 } (rust.exhaustive)
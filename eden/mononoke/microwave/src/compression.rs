@@ -0,0 +1,54 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+
+use anyhow::Error;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompress data if it looks like gzip, and return it unchanged otherwise. This lets us load
+/// snapshots that were written before compression was supported.
+pub fn maybe_decompress(data: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(Cow::Borrowed(data));
+    }
+
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut decompressed)?;
+    Ok(Cow::Owned(decompressed))
+}
+
+/// Like `maybe_decompress`, for data that is read incrementally rather than held in memory.
+pub fn maybe_decompress_reader<'a>(
+    mut reader: impl Read + 'a,
+) -> Result<Box<dyn Read + 'a>, Error> {
+    let mut magic = Vec::with_capacity(GZIP_MAGIC.len());
+    (&mut reader)
+        .take(GZIP_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    let compressed = magic == GZIP_MAGIC;
+
+    let reader = Cursor::new(magic).chain(reader);
+    if compressed {
+        Ok(Box::new(GzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
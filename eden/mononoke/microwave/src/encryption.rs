@@ -0,0 +1,146 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fmt;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use openssl::rand::rand_bytes;
+use openssl::symm::decrypt_aead;
+use openssl::symm::encrypt_aead;
+use openssl::symm::Cipher;
+
+// Encrypted snapshots are laid out as follows:
+//
+// - ENCRYPTION_MAGIC
+// - A random nonce of NONCE_LEN bytes
+// - The serialized snapshot encrypted with AES-256-GCM, header included
+// - The GCM tag of TAG_LEN bytes
+//
+// The magic is also authenticated, as additional data.
+const ENCRYPTION_MAGIC: &[u8; 4] = b"MWEN";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// A key to encrypt snapshots at rest with.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl EncryptionKey {
+    /// Read a key from a file, which must hold exactly 32 bytes (e.g. as generated by
+    /// `openssl rand 32`).
+    pub async fn read_from_file(path: &Path) -> Result<Self, Error> {
+        let contents = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("Failed to read encryption key from {}", path.display()))?;
+        let key = contents.try_into().map_err(|contents: Vec<u8>| {
+            format_err!(
+                "Encryption key in {} must be {} bytes, not {}",
+                path.display(),
+                KEY_LEN,
+                contents.len()
+            )
+        })?;
+        Ok(Self(key))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the key itself out of logs.
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+pub(crate) fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTION_MAGIC)
+}
+
+pub(crate) fn encrypt(key: &EncryptionKey, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut nonce = [0; NONCE_LEN];
+    rand_bytes(&mut nonce)?;
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key.0,
+        Some(&nonce),
+        ENCRYPTION_MAGIC,
+        data,
+        &mut tag,
+    )?;
+
+    let mut encrypted =
+        Vec::with_capacity(ENCRYPTION_MAGIC.len() + NONCE_LEN + ciphertext.len() + TAG_LEN);
+    encrypted.extend_from_slice(ENCRYPTION_MAGIC);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    encrypted.extend_from_slice(&tag);
+    Ok(encrypted)
+}
+
+pub(crate) fn decrypt(key: &EncryptionKey, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let data = match data.strip_prefix(ENCRYPTION_MAGIC.as_slice()) {
+        Some(data) if data.len() >= NONCE_LEN + TAG_LEN => data,
+        _ => bail!("Snapshot is not encrypted or is truncated"),
+    };
+    let (nonce, data) = data.split_at(NONCE_LEN);
+    let (ciphertext, tag) = data.split_at(data.len() - TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key.0,
+        Some(nonce),
+        ENCRYPTION_MAGIC,
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| {
+        Error::msg("Failed to decrypt snapshot, it may have been encrypted with another key")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() -> Result<(), Error> {
+        let key = EncryptionKey([1; KEY_LEN]);
+        let encrypted = encrypt(&key, b"snapshot")?;
+        assert!(is_encrypted(&encrypted));
+        assert_ne!(
+            &encrypted[ENCRYPTION_MAGIC.len() + NONCE_LEN..],
+            b"snapshot"
+        );
+        assert_eq!(decrypt(&key, &encrypted)?, b"snapshot");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_wrong_key() -> Result<(), Error> {
+        let encrypted = encrypt(&EncryptionKey([1; KEY_LEN]), b"snapshot")?;
+        assert!(decrypt(&EncryptionKey([2; KEY_LEN]), &encrypted).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_tampered() -> Result<(), Error> {
+        let key = EncryptionKey([1; KEY_LEN]);
+        let mut encrypted = encrypt(&key, b"snapshot")?;
+        encrypted[ENCRYPTION_MAGIC.len() + NONCE_LEN] ^= 1;
+        assert!(decrypt(&key, &encrypted).is_err());
+        assert!(decrypt(&key, &encrypted[..ENCRYPTION_MAGIC.len() + NONCE_LEN]).is_err());
+        assert!(decrypt(&key, b"snapshot").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,409 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::hash::Hasher;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Error;
+use fbthrift::compact_protocol;
+use mononoke_types::RepositoryId;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use twox_hash::XxHash;
+
+use crate::thrift;
+use crate::SnapshotFormat;
+
+// Serialized snapshots are laid out as follows:
+//
+// - HEADER_MAGIC
+// - Length of the header, as a big-endian u32
+// - The header, serialized as a thrift SnapshotHeader
+// - The snapshot itself
+//
+// Snapshots written before headers were introduced start directly with the snapshot, and are
+// recognized by the absence of HEADER_MAGIC.
+//
+// The header also holds a checksum of the snapshot, so that we can detect truncated snapshots.
+// Snapshots written before checksums were introduced are accepted without verification.
+const HEADER_MAGIC: &[u8; 4] = b"MWSH";
+const PREFIX_LEN: usize = HEADER_MAGIC.len() + 4;
+
+/// Information about a snapshot that can be read without loading the snapshot itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotMetadata {
+    /// Repository this snapshot was built for.
+    pub repo_id: RepositoryId,
+    /// When this snapshot was built, in seconds since the Unix epoch.
+    pub timestamp: i64,
+    /// Number of filenodes in this snapshot.
+    pub filenodes_count: u64,
+    /// Fraction of warmup reads that were already served from cache when this snapshot was
+    /// built, if known.
+    pub cache_hit_ratio: Option<f64>,
+    /// If this is a delta snapshot, the timestamp of the full snapshot it applies on top of.
+    pub base_timestamp: Option<i64>,
+    /// Whether recording filenodes stopped early because of a limit, so that this snapshot only
+    /// holds some of the filenodes that warmup read.
+    pub truncated: bool,
+    /// If only a sample of the filenodes that warmup read were recorded, 1 in this many was.
+    pub sample_rate: Option<u64>,
+    /// Version of the binary that wrote this snapshot, if known.
+    pub builder_version: Option<String>,
+    /// Number of bonsai to hg mappings in this snapshot, if they were captured.
+    pub hg_mapping_count: Option<u64>,
+}
+
+/// Version of this binary as recorded in the snapshots it writes: the crate version, followed by
+/// the build hash if one was provided through `MICROWAVE_BUILD_HASH` at build time.
+pub fn builder_version() -> String {
+    match option_env!("MICROWAVE_BUILD_HASH") {
+        Some(hash) => format!("{}+{}", env!("CARGO_PKG_VERSION"), hash),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+/// A snapshot split into its header and the snapshot itself.
+pub struct Decoded<'a> {
+    pub metadata: Option<SnapshotMetadata>,
+    /// Whether the snapshot had a checksum. If it did, it has been verified.
+    pub checksummed: bool,
+    pub format: SnapshotFormat,
+    pub body: &'a [u8],
+}
+
+/// A header read on its own from the start of a snapshot, ahead of the snapshot itself.
+pub struct Header {
+    pub metadata: SnapshotMetadata,
+    /// Checksum of the snapshot after the header, if it has one.
+    pub checksum: Option<u64>,
+    pub format: SnapshotFormat,
+}
+
+impl SnapshotMetadata {
+    fn into_thrift(self, format: SnapshotFormat, checksum: u64) -> thrift::SnapshotHeader {
+        thrift::SnapshotHeader {
+            repo_id: Some(self.repo_id.id()),
+            timestamp: Some(self.timestamp),
+            // NOTE: See the note on generation numbers in Snapshot::build.
+            filenodes_count: Some(self.filenodes_count.try_into().unwrap()),
+            checksum: Some(checksum as i64),
+            cache_hit_ratio: self.cache_hit_ratio,
+            base_timestamp: self.base_timestamp,
+            truncated: Some(self.truncated),
+            sample_rate: self
+                .sample_rate
+                .map(|rate| rate.try_into().unwrap_or(i64::MAX)),
+            format: Some(match format {
+                SnapshotFormat::Thrift => thrift::SnapshotBodyFormat::COMPACT_THRIFT,
+                SnapshotFormat::JsonLines => thrift::SnapshotBodyFormat::JSON_LINES,
+            }),
+            // Whatever binary writes a snapshot is responsible for it, even if it was loaded from
+            // one written by another.
+            builder_version: Some(builder_version()),
+            hg_mapping_count: self
+                .hg_mapping_count
+                .map(|count| count.try_into().unwrap_or(i64::MAX)),
+        }
+    }
+
+    fn from_thrift(t: thrift::SnapshotHeader) -> Result<Self, Error> {
+        let thrift::SnapshotHeader {
+            repo_id,
+            timestamp,
+            filenodes_count,
+            checksum: _,
+            cache_hit_ratio,
+            base_timestamp,
+            truncated,
+            format: _,
+            sample_rate,
+            builder_version,
+            hg_mapping_count,
+        } = t;
+
+        let repo_id = repo_id.ok_or_else(|| Error::msg("repo_id missing"))?;
+        let timestamp = timestamp.ok_or_else(|| Error::msg("timestamp missing"))?;
+        let filenodes_count =
+            filenodes_count.ok_or_else(|| Error::msg("filenodes_count missing"))?;
+
+        Ok(Self {
+            repo_id: RepositoryId::new(repo_id),
+            timestamp,
+            filenodes_count: filenodes_count.try_into()?,
+            cache_hit_ratio,
+            base_timestamp,
+            truncated: truncated.unwrap_or(false),
+            sample_rate: sample_rate.map(|rate| rate.try_into()).transpose()?,
+            builder_version,
+            hg_mapping_count: hg_mapping_count.map(|count| count.try_into()).transpose()?,
+        })
+    }
+}
+
+pub fn checksum(body: &[u8]) -> u64 {
+    let mut hasher = XxHash::with_seed(0);
+    hasher.write(body);
+    hasher.finish()
+}
+
+/// Computes the checksum of a snapshot as it is read, for snapshots that are read without
+/// holding all of them in memory.
+pub struct ChecksumReader<R> {
+    inner: R,
+    hasher: XxHash,
+}
+
+impl<R> ChecksumReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: XxHash::with_seed(0),
+        }
+    }
+
+    /// Checksum of everything read so far.
+    pub fn checksum(&self) -> u64 {
+        self.hasher.finish()
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.write(&buf[..read]);
+        Ok(read)
+    }
+}
+
+pub fn encode(metadata: &SnapshotMetadata, format: SnapshotFormat, body: &[u8]) -> Vec<u8> {
+    let header = compact_protocol::serialize(&metadata.clone().into_thrift(format, checksum(body)));
+
+    let mut encoded = Vec::with_capacity(PREFIX_LEN + header.len() + body.len());
+    encoded.extend_from_slice(HEADER_MAGIC);
+    encoded.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    encoded.extend_from_slice(&header);
+    encoded.extend_from_slice(body);
+    encoded
+}
+
+/// Split an encoded snapshot into its metadata (if it has any) and the snapshot itself, verifying
+/// the checksum of the snapshot if there is one.
+pub fn decode(data: &[u8]) -> Result<Decoded<'_>, Error> {
+    let header_len = match header_len(data) {
+        Some(header_len) => header_len,
+        None => {
+            return Ok(Decoded {
+                metadata: None,
+                checksummed: false,
+                format: SnapshotFormat::Thrift,
+                body: data,
+            });
+        }
+    };
+
+    let data = &data[PREFIX_LEN..];
+    if data.len() < header_len {
+        bail!("Snapshot header is truncated");
+    }
+
+    let (header, body) = data.split_at(header_len);
+    let header: thrift::SnapshotHeader = compact_protocol::deserialize(header)?;
+
+    let checksummed = match header.checksum {
+        Some(expected) => {
+            let actual = checksum(body) as i64;
+            if actual != expected {
+                bail!(
+                    "Snapshot checksum mismatch (expected {:x}, got {:x}), it may be truncated",
+                    expected,
+                    actual
+                );
+            }
+            true
+        }
+        None => false,
+    };
+
+    Ok(Decoded {
+        format: body_format(&header)?,
+        metadata: Some(SnapshotMetadata::from_thrift(header)?),
+        checksummed,
+        body,
+    })
+}
+
+/// Read the header at the start of `reader`, leaving `reader` at the start of the snapshot
+/// itself. Returns `None` if the snapshot has no header, in which case part of the snapshot may
+/// have been consumed.
+pub fn read(reader: &mut impl Read) -> Result<Option<Header>, Error> {
+    let mut prefix = [0; PREFIX_LEN];
+    match reader.read_exact(&mut prefix) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    if crate::encryption::is_encrypted(&prefix) {
+        bail!("Snapshot is encrypted, but no encryption key was given");
+    }
+
+    let header_len = match header_len(&prefix) {
+        Some(header_len) => header_len,
+        None => return Ok(None),
+    };
+
+    let mut header = vec![0; header_len];
+    reader.read_exact(&mut header)?;
+    let header: thrift::SnapshotHeader = compact_protocol::deserialize(header.as_slice())?;
+
+    Ok(Some(Header {
+        checksum: header.checksum.map(|checksum| checksum as u64),
+        format: body_format(&header)?,
+        metadata: SnapshotMetadata::from_thrift(header)?,
+    }))
+}
+
+fn body_format(header: &thrift::SnapshotHeader) -> Result<SnapshotFormat, Error> {
+    Ok(match header.format {
+        None | Some(thrift::SnapshotBodyFormat::COMPACT_THRIFT) => SnapshotFormat::Thrift,
+        Some(thrift::SnapshotBodyFormat::JSON_LINES) => SnapshotFormat::JsonLines,
+        Some(format) => bail!("Unknown snapshot format: {:?}", format),
+    })
+}
+
+/// Re-encode an encoded snapshot with its header updated by `update`. The snapshot itself is kept
+/// as is, so its checksum still applies. Fails if the snapshot has no header.
+pub fn rewrite(
+    data: &[u8],
+    update: impl FnOnce(&mut thrift::SnapshotHeader),
+) -> Result<(Vec<u8>, SnapshotMetadata), Error> {
+    let header_len = match header_len(data) {
+        Some(header_len) => header_len,
+        None => bail!("Snapshot predates metadata headers, its metadata cannot be updated"),
+    };
+
+    let data = &data[PREFIX_LEN..];
+    if data.len() < header_len {
+        bail!("Snapshot header is truncated");
+    }
+
+    let (header, body) = data.split_at(header_len);
+    let mut header: thrift::SnapshotHeader = compact_protocol::deserialize(header)?;
+    update(&mut header);
+    let metadata = SnapshotMetadata::from_thrift(header.clone())?;
+    let header = compact_protocol::serialize(&header);
+
+    let mut encoded = Vec::with_capacity(PREFIX_LEN + header.len() + body.len());
+    encoded.extend_from_slice(HEADER_MAGIC);
+    encoded.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    encoded.extend_from_slice(&header);
+    encoded.extend_from_slice(body);
+    Ok((encoded, metadata))
+}
+
+/// Read the metadata of a snapshot stored in a file, without reading the rest of the file.
+pub async fn read_from_file(path: &Path) -> Result<Option<SnapshotMetadata>, Error> {
+    let mut file = File::open(path).await?;
+
+    let mut prefix = [0; PREFIX_LEN];
+    match file.read_exact(&mut prefix).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    if crate::encryption::is_encrypted(&prefix) {
+        bail!("Snapshot is encrypted, but no encryption key was given");
+    }
+
+    let header_len = match header_len(&prefix) {
+        Some(header_len) => header_len,
+        None => return Ok(None),
+    };
+
+    let mut header = vec![0; header_len];
+    file.read_exact(&mut header).await?;
+    let metadata =
+        SnapshotMetadata::from_thrift(compact_protocol::deserialize(header.as_slice())?)?;
+
+    Ok(Some(metadata))
+}
+
+fn header_len(data: &[u8]) -> Option<usize> {
+    if data.len() < PREFIX_LEN || !data.starts_with(HEADER_MAGIC) {
+        return None;
+    }
+
+    let mut len = [0; 4];
+    len.copy_from_slice(&data[HEADER_MAGIC.len()..PREFIX_LEN]);
+    Some(u32::from_be_bytes(len) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_metadata() -> SnapshotMetadata {
+        SnapshotMetadata {
+            repo_id: RepositoryId::new(1),
+            timestamp: 1234,
+            filenodes_count: 2,
+            cache_hit_ratio: Some(0.5),
+            base_timestamp: None,
+            truncated: false,
+            sample_rate: None,
+            builder_version: Some(builder_version()),
+            hg_mapping_count: Some(3),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() -> Result<(), Error> {
+        let metadata = test_metadata();
+        let encoded = encode(&metadata, SnapshotFormat::JsonLines, b"snapshot");
+
+        let decoded = decode(&encoded)?;
+        assert_eq!(decoded.metadata, Some(metadata));
+        assert!(decoded.checksummed);
+        assert_eq!(decoded.format, SnapshotFormat::JsonLines);
+        assert_eq!(decoded.body, b"snapshot");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_headerless() -> Result<(), Error> {
+        let decoded = decode(b"snapshot")?;
+        assert_eq!(decoded.metadata, None);
+        assert!(!decoded.checksummed);
+        assert_eq!(decoded.format, SnapshotFormat::Thrift);
+        assert_eq!(decoded.body, b"snapshot");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_checksum_mismatch() {
+        let mut encoded = encode(&test_metadata(), SnapshotFormat::Thrift, b"snapshot");
+        encoded.truncate(encoded.len() - 1);
+        assert!(decode(&encoded).is_err());
+
+        let mut encoded = encode(&test_metadata(), SnapshotFormat::Thrift, b"snapshot");
+        *encoded.last_mut().unwrap() ^= 1;
+        assert!(decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_decode_truncated_header() {
+        let encoded = encode(&test_metadata(), SnapshotFormat::Thrift, b"");
+        assert!(decode(&encoded[..PREFIX_LEN + 1]).is_err());
+    }
+}
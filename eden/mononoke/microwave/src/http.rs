@@ -0,0 +1,42 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Read-only access to snapshots served over HTTP(S).
+
+use std::time::Duration;
+
+use anyhow::Error;
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use reqwest::Client;
+use reqwest::StatusCode;
+
+/// Download the snapshot at this URL, or `None` if the server does not have it. The timeout
+/// applies to the whole download.
+pub async fn get(url: &str, timeout: Duration, verify_tls: bool) -> Result<Option<Bytes>, Error> {
+    let client = Client::builder()
+        .timeout(timeout)
+        .danger_accept_invalid_certs(!verify_tls)
+        .build()?;
+
+    let res = client.get(url).send().await?;
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let body = res
+        .error_for_status()?
+        .bytes_stream()
+        .try_fold(BytesMut::new(), |mut body, chunk| async move {
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        })
+        .await?;
+
+    Ok(Some(body.freeze()))
+}
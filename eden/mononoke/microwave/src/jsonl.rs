@@ -0,0 +1,518 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The JSON lines snapshot format. Each line holds one filenode, changeset, bookmark, hg mapping
+//! or blob, with hashes as hex strings and paths as UTF-8 strings (or base64 encoded bytes if
+//! they are not valid UTF-8), so that snapshots can be read without thrift. It is larger and
+//! slower to load than the thrift format.
+
+use std::io::BufRead;
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::format_err;
+use anyhow::Error;
+use bonsai_hg_mapping::BonsaiHgMappingEntry;
+use changesets::ChangesetEntry;
+use filenodes::FilenodeInfo;
+use filenodes::PreparedFilenode;
+use mercurial_types::HgChangesetId;
+use mercurial_types::HgFileNodeId;
+use mononoke_types::ChangesetId;
+use mononoke_types::MPath;
+use mononoke_types::RepoPath;
+use mononoke_types::RepositoryId;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::thrift;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PathKind {
+    Root,
+    Directory,
+    File,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BookmarkKind {
+    Publishing,
+    PullDefaultPublishing,
+}
+
+/// A path, stored as a plain string when it is valid UTF-8 so that the common case stays readable,
+/// and as its base64 encoded bytes otherwise so that it round trips exactly.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RecordPath {
+    Utf8(String),
+    Bytes { base64: String },
+}
+
+impl RecordPath {
+    fn from_mpath(path: Option<&MPath>) -> Self {
+        let bytes = path.map_or_else(Vec::new, |path| path.to_vec());
+        match String::from_utf8(bytes) {
+            Ok(path) => RecordPath::Utf8(path),
+            Err(e) => RecordPath::Bytes {
+                base64: base64::encode(e.as_bytes()),
+            },
+        }
+    }
+
+    fn into_bytes(self) -> Result<Vec<u8>, Error> {
+        match self {
+            RecordPath::Utf8(path) => Ok(path.into_bytes()),
+            RecordPath::Bytes { base64 } => Ok(base64::decode(base64)?),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct FilenodeRecord {
+    kind: PathKind,
+    path: RecordPath,
+    filenode: String,
+    p1: Option<String>,
+    p2: Option<String>,
+    /// The path and filenode this file was copied from.
+    copyfrom: Option<(RecordPath, String)>,
+    linknode: String,
+    /// Only present for files, if sizes were captured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_size: Option<u64>,
+}
+
+/// Which parts of the snapshot are present, as an empty part (e.g. a delta in which all bookmarks
+/// were deleted) is not the same as a part that was not captured.
+#[derive(Serialize, Deserialize)]
+struct PresentRecord {
+    filenodes: bool,
+    changesets: bool,
+    bookmarks: bool,
+    hg_mapping: bool,
+    blobs: bool,
+}
+
+impl PresentRecord {
+    fn of(snapshot: &thrift::RepoSnapshot) -> Self {
+        Self {
+            filenodes: snapshot.filenodes.is_some(),
+            changesets: snapshot.changesets.is_some(),
+            bookmarks: snapshot.bookmarks.is_some(),
+            hg_mapping: snapshot.hg_mapping.is_some(),
+            blobs: snapshot.blobs.is_some(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Present(PresentRecord),
+    Filenode(FilenodeRecord),
+    Changeset {
+        cs_id: String,
+        parents: Vec<String>,
+        gen: u64,
+    },
+    Bookmark {
+        name: String,
+        kind: BookmarkKind,
+        cs_id: String,
+    },
+    HgMapping {
+        bcs_id: String,
+        hg_cs_id: String,
+    },
+    Blob {
+        key: String,
+        /// Base64 encoded, if the contents were captured.
+        data: Option<String>,
+        #[serde(default)]
+        redacted: bool,
+    },
+}
+
+impl From<PreparedFilenode> for FilenodeRecord {
+    fn from(filenode: PreparedFilenode) -> Self {
+        let PreparedFilenode { path, info } = filenode;
+        let kind = match &path {
+            RepoPath::RootPath => PathKind::Root,
+            RepoPath::DirectoryPath(_) => PathKind::Directory,
+            RepoPath::FilePath(_) => PathKind::File,
+        };
+        Self {
+            kind,
+            path: RecordPath::from_mpath(path.mpath()),
+            filenode: info.filenode.to_string(),
+            p1: info.p1.map(|p| p.to_string()),
+            p2: info.p2.map(|p| p.to_string()),
+            copyfrom: info.copyfrom.map(|(path, filenode)| {
+                (RecordPath::from_mpath(path.mpath()), filenode.to_string())
+            }),
+            linknode: info.linknode.to_string(),
+            content_size: None,
+        }
+    }
+}
+
+impl TryFrom<FilenodeRecord> for PreparedFilenode {
+    type Error = Error;
+
+    fn try_from(record: FilenodeRecord) -> Result<Self, Error> {
+        let FilenodeRecord {
+            kind,
+            path,
+            filenode,
+            p1,
+            p2,
+            copyfrom,
+            linknode,
+            content_size: _,
+        } = record;
+        let path = path.into_bytes()?;
+        let path = match kind {
+            PathKind::Root => RepoPath::root(),
+            PathKind::Directory => RepoPath::dir(path.as_slice())?,
+            PathKind::File => RepoPath::file(path.as_slice())?,
+        };
+        let parent = |p: Option<String>| p.as_deref().map(HgFileNodeId::from_str).transpose();
+        let copyfrom = copyfrom
+            .map(|(path, filenode)| {
+                Result::<_, Error>::Ok((
+                    RepoPath::file(path.into_bytes()?.as_slice())?,
+                    HgFileNodeId::from_str(&filenode)?,
+                ))
+            })
+            .transpose()?;
+
+        Ok(PreparedFilenode {
+            path,
+            info: FilenodeInfo {
+                filenode: HgFileNodeId::from_str(&filenode)?,
+                p1: parent(p1)?,
+                p2: parent(p2)?,
+                copyfrom,
+                linknode: HgChangesetId::from_str(&linknode)?,
+            },
+        })
+    }
+}
+
+impl FilenodeRecord {
+    fn into_thrift(self) -> Result<thrift::FilenodeSnapshot, Error> {
+        let content_size = self.content_size;
+        Ok(thrift::FilenodeSnapshot {
+            content_size: content_size.map(|size| size as i64),
+            ..crate::filenode_into_thrift(self.try_into()?)
+        })
+    }
+}
+
+/// Serialize the body of a snapshot as JSON lines.
+pub fn serialize(repo_id: RepositoryId, snapshot: &thrift::RepoSnapshot) -> Result<Vec<u8>, Error> {
+    let mut records = vec![Record::Present(PresentRecord::of(snapshot))];
+
+    let thrift_filenodes = snapshot.filenodes.clone().unwrap_or_default();
+    let content_sizes = thrift_filenodes
+        .iter()
+        .map(|filenode| filenode.content_size.map(|size| size as u64))
+        .collect::<Vec<_>>();
+    let filenodes = crate::reheat_filenodes(thrift_filenodes)?;
+    records.extend(
+        filenodes
+            .into_iter()
+            .zip(content_sizes)
+            .map(|(filenode, content_size)| {
+                Record::Filenode(FilenodeRecord {
+                    content_size,
+                    ..filenode.into()
+                })
+            }),
+    );
+
+    let changesets =
+        crate::reheat_changesets(repo_id, snapshot.changesets.clone().unwrap_or_default())?;
+    records.extend(changesets.into_iter().map(|c| Record::Changeset {
+        cs_id: c.cs_id.to_string(),
+        parents: c.parents.iter().map(|p| p.to_string()).collect(),
+        gen: c.gen,
+    }));
+
+    let bookmarks = crate::reheat_bookmarks(snapshot.bookmarks.clone().unwrap_or_default())?;
+    records.extend(bookmarks.into_iter().map(|(bookmark, cs_id)| {
+        let kind = match bookmark.kind() {
+            bookmarks::BookmarkKind::PullDefaultPublishing => BookmarkKind::PullDefaultPublishing,
+            _ => BookmarkKind::Publishing,
+        };
+        Record::Bookmark {
+            name: bookmark.into_name().into_string(),
+            kind,
+            cs_id: cs_id.to_string(),
+        }
+    }));
+
+    let hg_mapping = crate::reheat_hg_mapping(snapshot.hg_mapping.clone().unwrap_or_default())?;
+    records.extend(hg_mapping.into_iter().map(|m| Record::HgMapping {
+        bcs_id: m.bcs_id.to_string(),
+        hg_cs_id: m.hg_cs_id.to_string(),
+    }));
+
+    for blob in snapshot.blobs.iter().flatten() {
+        let key = blob
+            .key
+            .clone()
+            .ok_or_else(|| Error::msg("blob key missing"))?;
+        records.push(Record::Blob {
+            key,
+            data: blob.data.as_ref().map(base64::encode),
+            redacted: blob.redacted == Some(true),
+        });
+    }
+
+    let mut serialized = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut serialized, &record)?;
+        serialized.write_all(b"\n")?;
+    }
+    Ok(serialized)
+}
+
+/// Deserialize the body of a snapshot that was serialized as JSON lines.
+pub fn deserialize(repo_id: RepositoryId, data: &[u8]) -> Result<thrift::RepoSnapshot, Error> {
+    let mut present = None;
+    let mut filenodes = Vec::new();
+    let mut changesets = Vec::new();
+    let mut bookmarks = Vec::new();
+    let mut hg_mapping = Vec::new();
+    let mut blobs = Vec::new();
+
+    for (i, line) in data.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|e| format_err!("Invalid snapshot record on line {}: {}", i + 1, e))?;
+        match record {
+            Record::Present(record) => present = Some(record),
+            Record::Filenode(record) => filenodes.push(record.into_thrift()?),
+            Record::Changeset {
+                cs_id,
+                parents,
+                gen,
+            } => changesets.push(crate::changeset_into_thrift(ChangesetEntry {
+                repo_id,
+                cs_id: ChangesetId::from_str(&cs_id)?,
+                parents: parents
+                    .iter()
+                    .map(|p| ChangesetId::from_str(p))
+                    .collect::<Result<_, _>>()?,
+                gen,
+            })),
+            Record::Bookmark { name, kind, cs_id } => bookmarks.push(thrift::BookmarkSnapshot {
+                name: Some(name),
+                kind: Some(match kind {
+                    BookmarkKind::Publishing => thrift::BookmarkKindSnapshot::PUBLISHING,
+                    BookmarkKind::PullDefaultPublishing => {
+                        thrift::BookmarkKindSnapshot::PULL_DEFAULT_PUBLISHING
+                    }
+                }),
+                cs_id: Some(ChangesetId::from_str(&cs_id)?.into_thrift()),
+            }),
+            Record::HgMapping { bcs_id, hg_cs_id } => {
+                hg_mapping.push(crate::hg_mapping_into_thrift(BonsaiHgMappingEntry {
+                    hg_cs_id: HgChangesetId::from_str(&hg_cs_id)?,
+                    bcs_id: ChangesetId::from_str(&bcs_id)?,
+                }))
+            }
+            Record::Blob {
+                key,
+                data,
+                redacted,
+            } => blobs.push(thrift::BlobSnapshot {
+                key: Some(key),
+                data: data.map(base64::decode).transpose()?,
+                redacted: if redacted { Some(true) } else { None },
+            }),
+        }
+    }
+
+    let present = present.ok_or_else(|| Error::msg("Snapshot is missing its present record"))?;
+    Ok(thrift::RepoSnapshot {
+        filenodes: present.filenodes.then_some(filenodes),
+        changesets: present.changesets.then_some(changesets),
+        bookmarks: present.bookmarks.then_some(bookmarks),
+        blobs: present.blobs.then_some(blobs),
+        hg_mapping: present.hg_mapping.then_some(hg_mapping),
+    })
+}
+
+/// Call `f` with each filenode of the snapshot serialized as JSON lines read from `reader`, in
+/// the order they are stored. Only one line is held in memory at a time.
+pub fn for_each_filenode(
+    reader: impl BufRead,
+    mut f: impl FnMut(thrift::FilenodeSnapshot) -> Result<(), Error>,
+) -> Result<(), Error> {
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|e| format_err!("Invalid snapshot record on line {}: {}", i + 1, e))?;
+        if let Record::Filenode(record) = record {
+            f(record.into_thrift()?)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mercurial_types_mocks::nodehash::ONES_CSID as HG_ONES_CSID;
+    use mercurial_types_mocks::nodehash::ONES_FNID;
+    use mercurial_types_mocks::nodehash::TWOS_FNID;
+    use mononoke_types_mocks::changesetid::ONES_CSID;
+    use mononoke_types_mocks::changesetid::TWOS_CSID;
+
+    use super::*;
+
+    fn test_snapshot() -> Result<thrift::RepoSnapshot, Error> {
+        let file = PreparedFilenode {
+            path: RepoPath::file("dir/file")?,
+            info: FilenodeInfo {
+                filenode: ONES_FNID,
+                p1: None,
+                p2: None,
+                copyfrom: None,
+                linknode: HG_ONES_CSID,
+            },
+        };
+        // Paths are bytes, and are not always valid UTF-8.
+        let copy = PreparedFilenode {
+            path: RepoPath::file(b"dir/copy\xff".as_slice())?,
+            info: FilenodeInfo {
+                filenode: TWOS_FNID,
+                p1: Some(ONES_FNID),
+                p2: None,
+                copyfrom: Some((RepoPath::file(b"dir/\xfe".as_slice())?, ONES_FNID)),
+                linknode: HG_ONES_CSID,
+            },
+        };
+        let dir = PreparedFilenode {
+            path: RepoPath::dir("dir")?,
+            ..file.clone()
+        };
+        let root = PreparedFilenode {
+            path: RepoPath::root(),
+            ..file.clone()
+        };
+
+        Ok(thrift::RepoSnapshot {
+            filenodes: Some(vec![
+                thrift::FilenodeSnapshot {
+                    content_size: Some(42),
+                    ..crate::filenode_into_thrift(file)
+                },
+                crate::filenode_into_thrift(copy),
+                crate::filenode_into_thrift(dir),
+                crate::filenode_into_thrift(root),
+            ]),
+            changesets: Some(vec![crate::changeset_into_thrift(ChangesetEntry {
+                repo_id: RepositoryId::new(1),
+                cs_id: TWOS_CSID,
+                parents: vec![ONES_CSID],
+                gen: 2,
+            })]),
+            bookmarks: Some(vec![thrift::BookmarkSnapshot {
+                name: Some("main".to_string()),
+                kind: Some(thrift::BookmarkKindSnapshot::PULL_DEFAULT_PUBLISHING),
+                cs_id: Some(TWOS_CSID.into_thrift()),
+            }]),
+            hg_mapping: Some(vec![crate::hg_mapping_into_thrift(BonsaiHgMappingEntry {
+                hg_cs_id: HG_ONES_CSID,
+                bcs_id: ONES_CSID,
+            })]),
+            blobs: Some(vec![
+                thrift::BlobSnapshot {
+                    key: Some("key".to_string()),
+                    data: Some(b"data".to_vec()),
+                    redacted: None,
+                },
+                thrift::BlobSnapshot {
+                    key: Some("redacted".to_string()),
+                    data: None,
+                    redacted: Some(true),
+                },
+            ]),
+        })
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), Error> {
+        let repo_id = RepositoryId::new(1);
+        let snapshot = test_snapshot()?;
+        let serialized = serialize(repo_id, &snapshot)?;
+        assert_eq!(deserialize(repo_id, &serialized)?, snapshot);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_empty_parts() -> Result<(), Error> {
+        let repo_id = RepositoryId::new(1);
+        // A delta in which all bookmarks were deleted, without any hg mapping or blobs captured.
+        let snapshot = thrift::RepoSnapshot {
+            bookmarks: Some(vec![]),
+            hg_mapping: None,
+            blobs: None,
+            ..test_snapshot()?
+        };
+        let serialized = serialize(repo_id, &snapshot)?;
+        assert_eq!(deserialize(repo_id, &serialized)?, snapshot);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_without_present_record() -> Result<(), Error> {
+        let repo_id = RepositoryId::new(1);
+        let serialized = serialize(repo_id, &test_snapshot()?)?;
+        let mut lines = serialized.split(|b| *b == b'\n');
+        assert!(lines.next().unwrap().starts_with(br#"{"type":"present""#));
+        let without_present = lines.collect::<Vec<_>>().join(b"\n".as_slice());
+
+        assert!(deserialize(repo_id, &without_present).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_each_filenode() -> Result<(), Error> {
+        let snapshot = test_snapshot()?;
+        let serialized = serialize(RepositoryId::new(1), &snapshot)?;
+
+        let mut filenodes = Vec::new();
+        for_each_filenode(serialized.as_slice(), |filenode| {
+            filenodes.push(filenode);
+            Ok(())
+        })?;
+        assert_eq!(Some(filenodes), snapshot.filenodes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_invalid_record() {
+        assert!(deserialize(RepositoryId::new(1), b"{\"type\":\"unknown\"}\n").is_err());
+        assert!(deserialize(RepositoryId::new(1), b"not json\n").is_err());
+    }
+}
@@ -5,12 +5,33 @@
  * GNU General Public License version 2.
  */
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::ErrorKind;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
+use anyhow::bail;
+use anyhow::format_err;
 use anyhow::Error;
 use blobrepo::BlobRepo;
 use blobstore::Blobstore;
+use bonsai_hg_mapping::BonsaiHgMappingEntry;
+use bookmarks::Bookmark;
+use bookmarks::BookmarkKind;
+use bookmarks::BookmarkName;
+use bytes::Bytes;
 use changesets::ChangesetEntry;
 use changesets::ChangesetsRef;
 use context::CoreContext;
@@ -18,158 +39,1587 @@ use fbthrift::compact_protocol;
 use filenodes::FilenodeInfo;
 use filenodes::PreparedFilenode;
 use futures::future;
+use futures::stream;
 use futures::stream::Stream;
 use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
 use mercurial_types::HgChangesetId;
 use mercurial_types::HgFileNodeId;
 use mercurial_types::HgNodeHash;
 use mononoke_types::BlobstoreBytes;
 use mononoke_types::ChangesetId;
+use mononoke_types::MPath;
 use mononoke_types::RepoPath;
 use mononoke_types::RepositoryId;
+use retry::retry;
+use retry::RetryLogic;
 use slog::info;
+use slog::warn;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 
+mod compression;
+mod encryption;
+mod header;
+mod http;
+mod jsonl;
+mod manifest;
+mod memory;
+mod object_store;
+mod scan;
+
 mod thrift {
     pub use microwave_if::*;
 }
 
+pub use crate::encryption::EncryptionKey;
+pub use crate::header::SnapshotMetadata;
+pub use crate::manifest::SnapshotManifest;
+pub use crate::memory::MemorySnapshots;
+
+const COMMIT_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const BLOB_PREFETCH_CONCURRENCY: usize = 100;
+
 #[derive(Debug, Copy, Clone)]
 pub enum SnapshotLocation<'a> {
-    SharedLocalPath(&'a Path),
-    Blobstore,
+    /// Snapshots are stored as files in this directory. When writing, `compress` selects whether
+    /// to gzip the snapshot, and `manifest` whether to write a `SnapshotManifest` next to it.
+    /// When reading, compression is detected automatically. If `encryption_key` is set,
+    /// snapshots are encrypted with it when writing, and encrypted snapshots are decrypted with it
+    /// when reading. Unencrypted snapshots can be read either way.
+    SharedLocalPath {
+        path: &'a Path,
+        compress: bool,
+        manifest: bool,
+        encryption_key: Option<&'a EncryptionKey>,
+    },
+    /// Snapshots are stored in the repository blobstore. If `key_prefix` is set, it is prepended
+    /// to the keys, so that several generations of snapshots can be kept side by side.
+    Blobstore { key_prefix: Option<&'a str> },
+    /// Snapshots are read from the repository blobstore, under the key prefix that the latest
+    /// pointer refers to (see `update_latest_pointer`). Snapshots cannot be committed to this
+    /// location, only to the key prefix itself.
+    BlobstoreLatest,
+    /// Snapshots are stored in this S3-compatible bucket, with keys starting with `key_prefix`.
+    ObjectStore {
+        bucket: &'a str,
+        key_prefix: &'a str,
+    },
+    /// Snapshots are downloaded from this HTTP(S) URL, followed by the snapshot name. Snapshots
+    /// cannot be committed to this location.
+    Http {
+        url: &'a str,
+        timeout: Duration,
+        verify_tls: bool,
+    },
+    /// Snapshots are written to standard output, gzipped if `compress` is set, so that they can be
+    /// piped into other tools. Nothing is ever read back from this location, as if it were empty.
+    Stdout { compress: bool },
+    /// Snapshots are kept in memory, e.g. to test building and loading them or to pass them
+    /// between parts of the same process.
+    Memory(&'a MemorySnapshots),
+}
+
+/// Callbacks made while a snapshot is being built, e.g. to export metrics about what is recorded.
+/// They are made from the tasks that build the snapshot, so they should return quickly.
+pub trait SnapshotObserver: Send + Sync {
+    /// Called for each filenode that is recorded in the snapshot, after deduplication and limits.
+    fn on_filenode(&self, _filenode: &FilenodeInfo) {}
+
+    /// Called once recording has finished, with the number of filenodes in the snapshot.
+    fn on_complete(&self, _filenodes_count: usize) {}
+}
+
+/// Options for `Snapshot::build`.
+#[derive(Clone, Default)]
+pub struct BuildOptions {
+    /// Store filenodes that are seen more than once only once. This is done per stream, so all
+    /// filenodes for a given path should be sent to the same stream.
+    pub dedupe: bool,
+    /// Stop recording filenodes once this many have been recorded. Streams that reach the limit
+    /// are dropped, so sending further filenodes to them fails.
+    pub max_filenodes: Option<usize>,
+    /// Like `max_filenodes`, but stop once the filenodes and changesets recorded take up about
+    /// this many bytes in memory. This is an estimate from the size of each entry.
+    pub max_bytes: Option<u64>,
+    /// Sort filenodes by path and hash, and changesets by id, so that the same recorded data
+    /// always produces the same snapshot (apart from the timestamp in its metadata) regardless of
+    /// the order it was recorded in. Sorting needs every filenode to be buffered, which is
+    /// already the case when building, so the cost is the O(n log n) sort itself, done once
+    /// recording has finished. This is noticeable for snapshots with millions of filenodes.
+    pub deterministic: bool,
+    /// Told about what is recorded while building, if set.
+    pub observer: Option<Arc<dyn SnapshotObserver>>,
+}
+
+/// Options for loading snapshots into a repo.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct LoadOptions {
+    /// Load snapshots that were built for another repo, rather than refusing to. This is only
+    /// useful to prime a repo with the data of another one that it shares history with.
+    pub allow_repo_id_mismatch: bool,
+}
+
+/// How the body of a snapshot is serialized. The format is recorded in the snapshot header, so
+/// snapshots can be loaded whatever their format.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    /// Compact thrift, the smallest and fastest to load
+    Thrift,
+    /// One JSON object per line, for tools that cannot read thrift
+    JsonLines,
+}
+
+impl Default for SnapshotFormat {
+    fn default() -> Self {
+        Self::Thrift
+    }
+}
+
+impl FromStr for SnapshotFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "thrift" => Ok(Self::Thrift),
+            "json-lines" => Ok(Self::JsonLines),
+            _ => bail!(
+                "Unknown snapshot format {}, expected thrift or json-lines",
+                s
+            ),
+        }
+    }
 }
 
 pub struct Snapshot {
+    metadata: SnapshotMetadata,
     snapshot: thrift::RepoSnapshot,
+    duplicate_filenodes: u64,
+    recorded_bytes: u64,
+    format: SnapshotFormat,
+}
+
+/// Approximate memory used by a filenode while a snapshot is being built.
+fn filenode_size(filenode: &PreparedFilenode) -> u64 {
+    let path_len = |path: &RepoPath| path.mpath().map_or(0, MPath::len);
+    let copyfrom_len = filenode
+        .info
+        .copyfrom
+        .as_ref()
+        .map_or(0, |(path, _)| path_len(path));
+    (std::mem::size_of::<PreparedFilenode>() + path_len(&filenode.path) + copyfrom_len) as u64
+}
+
+/// Approximate memory used by a changeset while a snapshot is being built.
+fn changeset_size(changeset: &ChangesetEntry) -> u64 {
+    (std::mem::size_of::<ChangesetEntry>()
+        + changeset.parents.len() * std::mem::size_of::<ChangesetId>()) as u64
 }
 
 impl Snapshot {
-    pub async fn build<FilenodesStream, ChangesetsStream>(
-        filenodes: FilenodesStream,
+    /// Build a snapshot from the filenodes, changesets and bookmarks recorded during warmup.
+    /// Filenodes may be split across any number of streams.
+    pub async fn build<FilenodesStreams, FilenodesStream, ChangesetsStream, BookmarksStream>(
+        repo_id: RepositoryId,
+        filenodes: FilenodesStreams,
         changesets: ChangesetsStream,
+        bookmarks: BookmarksStream,
+        options: BuildOptions,
     ) -> Self
     where
+        FilenodesStreams: IntoIterator<Item = FilenodesStream>,
         FilenodesStream: Stream<Item = PreparedFilenode>,
         ChangesetsStream: Stream<Item = ChangesetEntry>,
+        BookmarksStream: Stream<Item = (Bookmark, ChangesetId)>,
     {
-        let filenodes = filenodes.fold(Vec::new(), |mut v, c| {
-            let PreparedFilenode { path, info } = c;
-
-            let t = thrift::FilenodeSnapshot {
-                path: Some(path.into_thrift()),
-                filenode: Some(info.filenode.into_nodehash().into_thrift()),
-                p1: info.p1.map(|p| p.into_nodehash().into_thrift()),
-                p2: info.p2.map(|p| p.into_nodehash().into_thrift()),
-                copyfrom: info.copyfrom.map(|copyfrom| thrift::CopyInfoSnapshot {
-                    path: Some(copyfrom.0.into_thrift()),
-                    filenode: Some(copyfrom.1.into_nodehash().into_thrift()),
-                }),
-                linknode: Some(info.linknode.into_nodehash().into_thrift()),
+        let BuildOptions {
+            dedupe,
+            max_filenodes,
+            max_bytes,
+            deterministic,
+            observer,
+        } = options;
+        let timestamp = now_timestamp();
+
+        // Filenodes may be captured across several streams. Their order does not matter unless
+        // building deterministically, so just collect each of them and concatenate the results.
+        let duplicate_filenodes = AtomicU64::new(0);
+        let recorded_filenodes = AtomicUsize::new(0);
+        let recorded_bytes = AtomicU64::new(0);
+        let bytes_exceeded = AtomicBool::new(false);
+        let filenodes = future::join_all(filenodes.into_iter().map(|filenodes| {
+            let mut seen = dedupe.then(HashSet::new);
+            let observer = observer.clone();
+            let (duplicate_filenodes, recorded_filenodes, recorded_bytes, bytes_exceeded) = (
+                &duplicate_filenodes,
+                &recorded_filenodes,
+                &recorded_bytes,
+                &bytes_exceeded,
+            );
+            filenodes
+                .filter(move |filenode| {
+                    let duplicate = seen.as_mut().map_or(false, |seen| {
+                        !seen.insert((filenode.path.clone(), filenode.info.filenode))
+                    });
+                    if duplicate {
+                        duplicate_filenodes.fetch_add(1, Ordering::Relaxed);
+                    }
+                    future::ready(!duplicate)
+                })
+                // Ending the stream drops the receiver, so filenodes can no longer be recorded.
+                .take_while(move |filenode| {
+                    let within_count = max_filenodes.map_or(true, |max| {
+                        recorded_filenodes.fetch_add(1, Ordering::Relaxed) < max
+                    });
+                    let size = filenode_size(filenode);
+                    let within_bytes = max_bytes.map_or(true, |max| {
+                        recorded_bytes.fetch_add(size, Ordering::Relaxed) + size <= max
+                    });
+                    if !within_bytes {
+                        bytes_exceeded.store(true, Ordering::Relaxed);
+                    }
+                    future::ready(within_count && within_bytes)
+                })
+                .inspect(move |filenode| {
+                    if let Some(observer) = &observer {
+                        observer.on_filenode(&filenode.info);
+                    }
+                })
+                .collect::<Vec<_>>()
+        }));
+
+        let changesets = changesets
+            .inspect(|changeset| {
+                recorded_bytes.fetch_add(changeset_size(changeset), Ordering::Relaxed);
+            })
+            .collect::<Vec<_>>();
+
+        // The same bookmark may be seen more than once, keep the last value we saw.
+        let bookmarks = bookmarks.fold(BTreeMap::new(), |mut m, (bookmark, cs_id)| {
+            let kind = match bookmark.kind() {
+                BookmarkKind::Publishing => thrift::BookmarkKindSnapshot::PUBLISHING,
+                BookmarkKind::PullDefaultPublishing => {
+                    thrift::BookmarkKindSnapshot::PULL_DEFAULT_PUBLISHING
+                }
+                // Only publishing bookmarks are cached, so scratch bookmarks are not worth keeping.
+                BookmarkKind::Scratch => return future::ready(m),
             };
 
-            v.push(t);
+            m.insert(bookmark.into_name(), (kind, cs_id));
 
-            future::ready(v)
+            future::ready(m)
         });
 
-        let changesets = changesets.fold(Vec::new(), |mut v, c| {
-            let ChangesetEntry {
-                repo_id: _,
-                cs_id,
-                parents,
-                gen,
-            } = c;
+        let (filenodes, mut changesets, bookmarks) =
+            future::join3(filenodes, changesets, bookmarks).await;
+        let mut filenodes = filenodes.into_iter().flatten().collect::<Vec<_>>();
+        if deterministic {
+            filenodes.sort_unstable_by(|a, b| {
+                (a.path.mpath(), a.path.is_file(), a.info.filenode).cmp(&(
+                    b.path.mpath(),
+                    b.path.is_file(),
+                    b.info.filenode,
+                ))
+            });
+        }
+        let filenodes = filenodes
+            .into_iter()
+            .map(filenode_into_thrift)
+            .collect::<Vec<_>>();
 
-            let t = thrift::ChangesetSnapshot {
-                cs_id: Some(cs_id.into_thrift()),
-                parents: Some(parents.into_iter().map(|p| p.into_thrift()).collect()),
-                // NOTE: We expect this conversion (and the reverse one) between u64 and i64 to
-                // succeed because the generation number is >= 0, but also not so large that it
-                // cannot fit in a i64.
-                gen: Some(gen.try_into().unwrap()),
-            };
+        if deterministic {
+            changesets.sort_unstable_by_key(|c| c.cs_id);
+        }
+        let changesets = changesets
+            .into_iter()
+            .map(changeset_into_thrift)
+            .collect::<Vec<_>>();
 
-            v.push(t);
+        let truncated = max_filenodes.map_or(false, |max| recorded_filenodes.into_inner() > max)
+            || bytes_exceeded.into_inner();
 
-            future::ready(v)
-        });
+        let bookmarks = bookmarks
+            .into_iter()
+            .map(|(name, (kind, cs_id))| thrift::BookmarkSnapshot {
+                name: Some(name.into_string()),
+                kind: Some(kind),
+                cs_id: Some(cs_id.into_thrift()),
+            })
+            .collect::<Vec<_>>();
 
-        let (filenodes, changesets) = future::join(filenodes, changesets).await;
+        if let Some(observer) = &observer {
+            observer.on_complete(filenodes.len());
+        }
 
         Snapshot {
+            metadata: SnapshotMetadata {
+                repo_id,
+                timestamp,
+                filenodes_count: filenodes.len() as u64,
+                cache_hit_ratio: None,
+                base_timestamp: None,
+                truncated,
+                sample_rate: None,
+                builder_version: Some(header::builder_version()),
+                hg_mapping_count: None,
+            },
             snapshot: thrift::RepoSnapshot {
                 filenodes: Some(filenodes),
                 changesets: Some(changesets),
+                bookmarks: if bookmarks.is_empty() {
+                    None
+                } else {
+                    Some(bookmarks)
+                },
+                blobs: None,
+                hg_mapping: None,
             },
+            duplicate_filenodes: duplicate_filenodes.into_inner(),
+            recorded_bytes: recorded_bytes.into_inner(),
+            format: SnapshotFormat::default(),
+        }
+    }
+
+    /// Whether some filenodes were not recorded because `BuildOptions::max_filenodes` or
+    /// `BuildOptions::max_bytes` was reached.
+    pub fn is_truncated(&self) -> bool {
+        self.metadata.truncated
+    }
+
+    /// Record that only 1 in `sample_rate` of the filenodes read during warmup were sent to the
+    /// build, so that this snapshot is not mistaken for a complete one.
+    pub fn set_sample_rate(&mut self, sample_rate: Option<u64>) {
+        self.metadata.sample_rate = sample_rate;
+    }
+
+    /// If this snapshot only holds a sample of the filenodes, 1 in how many were recorded.
+    pub fn sample_rate(&self) -> Option<u64> {
+        self.metadata.sample_rate
+    }
+
+    /// The filenodes captured in this snapshot. For a delta snapshot, these are only the
+    /// filenodes that are not in its base.
+    pub fn filenodes(&self) -> Result<Vec<PreparedFilenode>, Error> {
+        reheat_filenodes(self.snapshot.filenodes.clone().unwrap_or_default())
+    }
+
+    /// The bookmarks captured in this snapshot, with the changesets they pointed to.
+    pub fn bookmarks(&self) -> Result<Vec<(Bookmark, ChangesetId)>, Error> {
+        reheat_bookmarks(self.snapshot.bookmarks.clone().unwrap_or_default())
+    }
+
+    /// Mark this snapshot as built now by this binary, so that it can be committed again in place
+    /// of a new one built from the same repo state.
+    pub fn reset_timestamp(&mut self) {
+        self.metadata.timestamp = now_timestamp();
+        self.metadata.builder_version = Some(header::builder_version());
+    }
+
+    /// The paths of the filenodes captured in this snapshot. Unlike `filenodes`, these are
+    /// converted one at a time as the iterator is consumed.
+    pub fn filenode_paths(&self) -> impl Iterator<Item = Result<RepoPath, Error>> + '_ {
+        self.snapshot.filenodes.iter().flatten().map(|filenode| {
+            let path = filenode
+                .path
+                .clone()
+                .ok_or_else(|| Error::msg("path missing"))?;
+            RepoPath::from_thrift(path)
+        })
+    }
+
+    /// The content sizes of the filenodes captured in this snapshot, in the same order as
+    /// `filenode_paths`. Sizes are only present for files, and only if they were captured.
+    pub fn filenode_content_sizes(&self) -> impl Iterator<Item = Option<u64>> + '_ {
+        self.snapshot
+            .filenodes
+            .iter()
+            .flatten()
+            .map(|filenode| filenode.content_size.map(|size| size as u64))
+    }
+
+    /// Record the content sizes of the filenodes in this snapshot, as read during warmup. Sizes
+    /// of filenodes that are not in the snapshot are ignored.
+    pub fn set_content_sizes(
+        &mut self,
+        sizes: impl IntoIterator<Item = (HgFileNodeId, u64)>,
+    ) -> Result<(), Error> {
+        let sizes = sizes.into_iter().collect::<HashMap<_, _>>();
+        for filenode in self.snapshot.filenodes.iter_mut().flatten() {
+            let id = filenode
+                .filenode
+                .clone()
+                .ok_or_else(|| Error::msg("filenode missing"))?;
+            let id = HgFileNodeId::new(HgNodeHash::from_thrift(id)?);
+            if let Some(size) = sizes.get(&id) {
+                filenode.content_size = Some(*size as i64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of filenodes whose content size was captured in this snapshot.
+    pub fn content_sizes_count(&self) -> usize {
+        self.filenode_content_sizes().flatten().count()
+    }
+
+    /// Number of filenodes that were dropped as duplicates while building this snapshot.
+    pub fn duplicate_filenodes(&self) -> u64 {
+        self.duplicate_filenodes
+    }
+
+    /// Approximate memory used by the entries recorded while building this snapshot, as counted
+    /// against `BuildOptions::max_bytes`. This is 0 for snapshots that were not built here.
+    pub fn recorded_bytes(&self) -> u64 {
+        self.recorded_bytes
+    }
+
+    /// Record the blobs that were read while building this snapshot, with their contents if they
+    /// were captured. Each key is only kept once.
+    pub fn set_blobs(&mut self, blobs: impl IntoIterator<Item = (String, Option<Bytes>)>) {
+        let blobs = blobs.into_iter().collect::<BTreeMap<_, _>>();
+        self.snapshot.blobs = Some(
+            blobs
+                .into_iter()
+                .map(|(key, data)| thrift::BlobSnapshot {
+                    key: Some(key),
+                    data: data.map(|data| data.to_vec()),
+                    redacted: None,
+                })
+                .collect(),
+        );
+    }
+
+    /// Record the blobs that could not be read while building this snapshot because they are
+    /// redacted. They are kept without their contents, and are not prefetched on load.
+    pub fn set_redacted_blobs(&mut self, keys: impl IntoIterator<Item = String>) {
+        let keys = keys.into_iter().collect::<BTreeSet<_>>();
+        if keys.is_empty() {
+            return;
+        }
+        let blobs = self.snapshot.blobs.get_or_insert_with(Vec::new);
+        blobs.retain(|b| b.key.as_ref().map_or(true, |key| !keys.contains(key)));
+        blobs.extend(keys.into_iter().map(|key| thrift::BlobSnapshot {
+            key: Some(key),
+            data: None,
+            redacted: Some(true),
+        }));
+    }
+
+    /// Drop the changesets recorded while building this snapshot, so that it records that they
+    /// were not captured rather than that none were read.
+    pub fn clear_changesets(&mut self) {
+        self.snapshot.changesets = None;
+    }
+
+    /// Record the bonsai to hg mappings that were read while building this snapshot. Each
+    /// changeset is only kept once.
+    pub fn set_hg_mapping(&mut self, entries: impl IntoIterator<Item = BonsaiHgMappingEntry>) {
+        let entries = entries
+            .into_iter()
+            .map(|entry| (entry.bcs_id, entry.hg_cs_id))
+            .collect::<BTreeMap<_, _>>();
+        self.metadata.hg_mapping_count = Some(entries.len() as u64);
+        self.snapshot.hg_mapping = Some(
+            entries
+                .into_iter()
+                .map(|(bcs_id, hg_cs_id)| {
+                    hg_mapping_into_thrift(BonsaiHgMappingEntry { hg_cs_id, bcs_id })
+                })
+                .collect(),
+        );
+    }
+
+    /// Number of bonsai to hg mappings captured in this snapshot.
+    pub fn hg_mapping_count(&self) -> usize {
+        self.snapshot.hg_mapping.as_ref().map_or(0, |m| m.len())
+    }
+
+    /// Number of blobs in this snapshot that are marked as redacted.
+    pub fn redacted_blobs_count(&self) -> usize {
+        self.snapshot
+            .blobs
+            .iter()
+            .flatten()
+            .filter(|b| b.redacted == Some(true))
+            .count()
+    }
+
+    /// Version of the binary that wrote this snapshot, if it was recorded. For a snapshot built
+    /// here, this is the version of this binary.
+    pub fn builder_version(&self) -> Option<&str> {
+        self.metadata.builder_version.as_deref()
+    }
+
+    /// Information about this snapshot that is stored alongside it.
+    pub fn metadata(&self) -> &SnapshotMetadata {
+        &self.metadata
+    }
+
+    /// Record the fraction of warmup reads that were served from cache while building this
+    /// snapshot.
+    pub fn set_cache_hit_ratio(&mut self, cache_hit_ratio: Option<f64>) {
+        self.metadata.cache_hit_ratio = cache_hit_ratio;
+    }
+
+    /// Fraction of warmup reads that were served from cache while building this snapshot.
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        self.metadata.cache_hit_ratio
+    }
+
+    /// Number of filenodes captured in this snapshot.
+    pub fn filenodes_count(&self) -> usize {
+        self.snapshot.filenodes.as_ref().map_or(0, |f| f.len())
+    }
+
+    /// Size in bytes this snapshot will occupy once serialized.
+    pub fn serialized_size(&self) -> usize {
+        self.serialize(false).map_or(0, |s| s.len())
+    }
+
+    /// Serialize this snapshot in `format` when it is written. Snapshots are written as thrift
+    /// unless set otherwise, including those that were loaded in another format.
+    pub fn set_format(&mut self, format: SnapshotFormat) {
+        self.format = format;
+    }
+
+    fn serialize(&self, compress: bool) -> Result<Vec<u8>, Error> {
+        Ok(header::encode(
+            &self.metadata,
+            self.format,
+            &self.serialize_body(compress)?,
+        ))
+    }
+
+    /// Serialize the snapshot itself, without its header.
+    fn serialize_body(&self, compress: bool) -> Result<Vec<u8>, Error> {
+        let body = match self.format {
+            SnapshotFormat::Thrift => compact_protocol::serialize(&self.snapshot).to_vec(),
+            SnapshotFormat::JsonLines => jsonl::serialize(self.metadata.repo_id, &self.snapshot)?,
+        };
+        if compress {
+            Ok(compression::compress(&body)?)
+        } else {
+            Ok(body)
+        }
+    }
+
+    /// Whether this snapshot only holds the differences with a base snapshot.
+    pub fn is_delta(&self) -> bool {
+        self.metadata.base_timestamp.is_some()
+    }
+
+    /// Turn this snapshot into a delta that only holds the filenodes and blobs that are not
+    /// already in `base`. Changesets, bookmarks and hg mappings are kept in full. The
+    /// delta must be committed to the same location as `base`, and is applied on top of it when
+    /// loaded.
+    pub fn into_delta(mut self, base: &Snapshot) -> Result<Self, Error> {
+        base.check_base_of(&self)?;
+
+        let base_filenodes = base
+            .snapshot
+            .filenodes
+            .iter()
+            .flatten()
+            .map(compact_protocol::serialize)
+            .collect::<HashSet<_>>();
+
+        if let Some(filenodes) = self.snapshot.filenodes.as_mut() {
+            filenodes.retain(|f| !base_filenodes.contains(&compact_protocol::serialize(f)));
+            self.metadata.filenodes_count = filenodes.len() as u64;
+        }
+
+        let base_blobs = base
+            .snapshot
+            .blobs
+            .iter()
+            .flatten()
+            .filter_map(|b| b.key.as_ref())
+            .collect::<HashSet<_>>();
+
+        if let Some(blobs) = self.snapshot.blobs.as_mut() {
+            blobs.retain(|b| b.key.as_ref().map_or(true, |key| !base_blobs.contains(key)));
         }
+
+        self.metadata.base_timestamp = Some(base.metadata.timestamp);
+
+        Ok(self)
+    }
+
+    /// Check that this snapshot can be the base of a delta built from `snapshot`.
+    pub fn check_base_of(&self, snapshot: &Snapshot) -> Result<(), Error> {
+        if self.metadata.repo_id != snapshot.metadata.repo_id {
+            bail!(
+                "Base snapshot is for repo {}, not {}",
+                self.metadata.repo_id,
+                snapshot.metadata.repo_id
+            );
+        }
+
+        if self.is_delta() {
+            bail!("Base snapshot is itself a delta");
+        }
+
+        Ok(())
+    }
+
+    /// Merge full snapshots of the same repo into one that holds all of their filenodes,
+    /// changesets, hg mappings and blobs, each only once. Bookmarks are taken from the most recent
+    /// snapshot that has any. The merged snapshot is as old as the oldest of its inputs.
+    pub fn merge(snapshots: Vec<Snapshot>) -> Result<Snapshot, Error> {
+        let mut snapshots = snapshots.into_iter();
+        let mut merged = snapshots
+            .next()
+            .ok_or_else(|| Error::msg("No snapshots to merge"))?;
+        if merged.is_delta() {
+            bail!("Cannot merge delta snapshots");
+        }
+
+        // The inputs may not have been built with dedupe, so duplicates within the first one are
+        // dropped as well.
+        let mut seen_filenodes = HashSet::new();
+        if let Some(filenodes) = merged.snapshot.filenodes.as_mut() {
+            filenodes.retain(|f| seen_filenodes.insert(compact_protocol::serialize(f)));
+        }
+        let mut seen_changesets = HashSet::new();
+        if let Some(changesets) = merged.snapshot.changesets.as_mut() {
+            changesets.retain(|c| seen_changesets.insert(compact_protocol::serialize(c)));
+        }
+        let mut seen_hg_mapping = HashSet::new();
+        if let Some(hg_mapping) = merged.snapshot.hg_mapping.as_mut() {
+            hg_mapping.retain(|m| seen_hg_mapping.insert(compact_protocol::serialize(m)));
+        }
+        let mut seen_blobs = merged
+            .snapshot
+            .blobs
+            .iter()
+            .flatten()
+            .filter_map(|b| b.key.clone())
+            .collect::<HashSet<_>>();
+        let mut bookmarks_timestamp = merged.metadata.timestamp;
+
+        for snapshot in snapshots {
+            let Snapshot {
+                metadata,
+                snapshot,
+                duplicate_filenodes: _,
+                recorded_bytes: _,
+                format: _,
+            } = snapshot;
+
+            if metadata.repo_id != merged.metadata.repo_id {
+                bail!(
+                    "Cannot merge snapshots for repo {} and {}",
+                    merged.metadata.repo_id,
+                    metadata.repo_id
+                );
+            }
+            if metadata.base_timestamp.is_some() {
+                bail!("Cannot merge delta snapshots");
+            }
+
+            let filenodes = merged.snapshot.filenodes.get_or_insert_with(Vec::new);
+            filenodes.extend(
+                snapshot
+                    .filenodes
+                    .into_iter()
+                    .flatten()
+                    .filter(|f| seen_filenodes.insert(compact_protocol::serialize(f))),
+            );
+            let changesets = merged.snapshot.changesets.get_or_insert_with(Vec::new);
+            changesets.extend(
+                snapshot
+                    .changesets
+                    .into_iter()
+                    .flatten()
+                    .filter(|c| seen_changesets.insert(compact_protocol::serialize(c))),
+            );
+            if let Some(hg_mapping) = snapshot.hg_mapping {
+                merged
+                    .snapshot
+                    .hg_mapping
+                    .get_or_insert_with(Vec::new)
+                    .extend(
+                        hg_mapping
+                            .into_iter()
+                            .filter(|m| seen_hg_mapping.insert(compact_protocol::serialize(m))),
+                    );
+            }
+            if let Some(blobs) = snapshot.blobs {
+                merged.snapshot.blobs.get_or_insert_with(Vec::new).extend(
+                    blobs.into_iter().filter(|b| {
+                        b.key
+                            .as_ref()
+                            .map_or(true, |key| seen_blobs.insert(key.clone()))
+                    }),
+                );
+            }
+            if snapshot.bookmarks.is_some()
+                && (merged.snapshot.bookmarks.is_none() || metadata.timestamp > bookmarks_timestamp)
+            {
+                merged.snapshot.bookmarks = snapshot.bookmarks;
+                bookmarks_timestamp = metadata.timestamp;
+            }
+
+            merged.metadata.timestamp = merged.metadata.timestamp.min(metadata.timestamp);
+            merged.metadata.truncated |= metadata.truncated;
+            // Merging with a sampled snapshot still only holds a sample.
+            merged.metadata.sample_rate = merged.metadata.sample_rate.max(metadata.sample_rate);
+        }
+
+        merged.metadata.filenodes_count =
+            merged.snapshot.filenodes.as_ref().map_or(0, |f| f.len()) as u64;
+        merged.metadata.hg_mapping_count =
+            merged.snapshot.hg_mapping.as_ref().map(|m| m.len() as u64);
+        // Hit ratios of separate warmups cannot be combined meaningfully.
+        merged.metadata.cache_hit_ratio = None;
+
+        Ok(merged)
+    }
+
+    /// Load a snapshot from a file, as written by `write_file` or committed to a local path.
+    /// Returns `None` if the snapshot was written before snapshots had a header, as it does not
+    /// record the repo it was built for.
+    pub async fn read_file(ctx: &CoreContext, path: &Path) -> Result<Option<Self>, Error> {
+        let contents = tokio::fs::read(path).await?;
+        let (metadata, snapshot) = decode(ctx, &contents)?;
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => return Ok(None),
+        };
+        Ok(Some(Self {
+            metadata,
+            snapshot,
+            duplicate_filenodes: 0,
+            recorded_bytes: 0,
+            format: SnapshotFormat::default(),
+        }))
     }
 
+    /// Write this snapshot to a file. Like when committing to a local path, the file is replaced
+    /// atomically.
+    pub async fn write_file(&self, path: &Path, compress: bool) -> Result<(), Error> {
+        let serialized = self.serialize(compress)?;
+        let tmp_path = tmp_snapshot_path(path);
+        if let Err(e) = write_and_rename(&tmp_path, path, &serialized).await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Build a delta snapshot against `base`. See `into_delta`.
+    pub async fn build_incremental<
+        FilenodesStreams,
+        FilenodesStream,
+        ChangesetsStream,
+        BookmarksStream,
+    >(
+        base: &Snapshot,
+        repo_id: RepositoryId,
+        filenodes: FilenodesStreams,
+        changesets: ChangesetsStream,
+        bookmarks: BookmarksStream,
+        options: BuildOptions,
+    ) -> Result<Self, Error>
+    where
+        FilenodesStreams: IntoIterator<Item = FilenodesStream>,
+        FilenodesStream: Stream<Item = PreparedFilenode>,
+        ChangesetsStream: Stream<Item = ChangesetEntry>,
+        BookmarksStream: Stream<Item = (Bookmark, ChangesetId)>,
+    {
+        Self::build(repo_id, filenodes, changesets, bookmarks, options)
+            .await
+            .into_delta(base)
+    }
+
+    /// Load the full snapshot stored at this location, ignoring any delta stored alongside it.
+    /// Returns `None` if there is no snapshot, or if it cannot be built upon: snapshots written
+    /// before snapshots had a header do not record what they were built from, and those that
+    /// cannot be decoded are ignored too, so that a full snapshot is built in their place.
+    pub async fn load(
+        ctx: &CoreContext,
+        repo: &BlobRepo,
+        location: SnapshotLocation<'_>,
+    ) -> Result<Option<Self>, Error> {
+        let contents = match read(ctx, repo, location, &snapshot_name()).await? {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+
+        let (metadata, snapshot) = match decode(ctx, &contents) {
+            Ok((Some(metadata), snapshot)) => (metadata, snapshot),
+            Ok((None, _)) => {
+                warn!(
+                    ctx.logger(),
+                    "Existing snapshot has no header, it cannot be built upon"
+                );
+                return Ok(None);
+            }
+            Err(e) => {
+                warn!(
+                    ctx.logger(),
+                    "Existing snapshot cannot be decoded, it cannot be built upon: {:#}", e
+                );
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(Self {
+            metadata,
+            snapshot,
+            duplicate_filenodes: 0,
+            recorded_bytes: 0,
+            format: SnapshotFormat::default(),
+        }))
+    }
+
+    /// Store this snapshot in `blobstore`, under the same key as `SnapshotLocation::Blobstore`
+    /// would use. This allows writing to blobstores other than the repository blobstore, such as
+    /// the inner stores of a multiplex, which must already apply the repo prefix to keys.
+    pub async fn commit_to_blobstore(
+        &self,
+        ctx: &CoreContext,
+        blobstore: &dyn Blobstore,
+        key_prefix: Option<&str>,
+        retries: usize,
+    ) -> Result<(), Error> {
+        let key = blobstore_key(key_prefix, &self.name());
+        let value = BlobstoreBytes::from_bytes(self.serialize(false)?);
+        retry(
+            Some(ctx.logger()),
+            |_| blobstore.put(ctx, key.clone(), value.clone()),
+            is_transient,
+            RetryLogic::Exponential {
+                base: COMMIT_RETRY_BASE_DELAY,
+                factor: 2.0,
+            },
+            retries + 1,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Store this snapshot. Transient errors writing to the blobstore are retried up to
+    /// `retries` times.
     pub async fn commit(
         &self,
         ctx: &CoreContext,
         repo: &BlobRepo,
         location: SnapshotLocation<'_>,
+        retries: usize,
     ) -> Result<(), Error> {
-        let serialized = compact_protocol::serialize(&self.snapshot);
+        let name = self.name();
 
         match location {
-            SnapshotLocation::SharedLocalPath(path) => {
-                let mut file = File::create(snapshot_path(path, repo.get_repoid())).await?;
-                file.write_all(&serialized).await?;
+            SnapshotLocation::SharedLocalPath {
+                path,
+                compress,
+                manifest: write_manifest,
+                encryption_key,
+            } => {
+                let body = self.serialize_body(compress)?;
+                let checksum = header::checksum(&body);
+                let serialized = header::encode(&self.metadata, self.format, &body);
+                let serialized = match encryption_key {
+                    Some(key) => encryption::encrypt(key, &serialized)?,
+                    None => serialized,
+                };
+                let path = snapshot_path(path, repo.get_repoid(), &name);
+                // Write to a temporary file first, so that an interrupted commit never leaves a
+                // partial snapshot at the path servers load from.
+                let tmp_path = tmp_snapshot_path(&path);
+                if let Err(e) = write_and_rename(&tmp_path, &path, &serialized).await {
+                    let _ = tokio::fs::remove_file(&tmp_path).await;
+                    return Err(e.into());
+                }
+                if write_manifest {
+                    manifest::write(&path, &SnapshotManifest::new(&self.metadata, checksum))
+                        .await?;
+                }
             }
-            SnapshotLocation::Blobstore => {
-                repo.blobstore()
-                    .put(ctx, snapshot_name(), BlobstoreBytes::from_bytes(serialized))
+            SnapshotLocation::Blobstore { key_prefix } => {
+                self.commit_to_blobstore(ctx, repo.blobstore(), key_prefix, retries)
                     .await?;
             }
+            SnapshotLocation::ObjectStore { bucket, key_prefix } => {
+                let serialized = self.serialize(false)?;
+                object_store::put(
+                    bucket,
+                    &snapshot_key(key_prefix, repo.get_repoid(), &name),
+                    serialized,
+                )
+                .await?;
+            }
+            SnapshotLocation::Http { url, .. } => {
+                bail!("Cannot commit snapshot to HTTP location {}", url);
+            }
+            SnapshotLocation::BlobstoreLatest => {
+                bail!("Cannot commit snapshot to the latest pointer, commit it to a key prefix");
+            }
+            SnapshotLocation::Stdout { compress } => {
+                let serialized = self.serialize(compress)?;
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(&serialized).await?;
+                stdout.flush().await?;
+            }
+            SnapshotLocation::Memory(snapshots) => {
+                snapshots.put(
+                    snapshot_key("", repo.get_repoid(), &name),
+                    self.serialize(false)?,
+                );
+            }
         };
 
         Ok(())
     }
+
+    /// Read this snapshot back from `location` after committing it there, and check that it
+    /// decodes to the same filenodes and contents, so that snapshots corrupted on their way to
+    /// storage are caught before servers try to load them.
+    pub async fn verify_commit(
+        &self,
+        ctx: &CoreContext,
+        repo: &BlobRepo,
+        location: SnapshotLocation<'_>,
+    ) -> Result<(), Error> {
+        if let SnapshotLocation::Stdout { .. } = location {
+            bail!("Cannot read back a snapshot written to stdout");
+        }
+        let contents = read(ctx, repo, location, &self.name()).await?;
+        self.verify_contents(ctx, contents)
+    }
+
+    /// Like `verify_commit`, for a snapshot committed with `commit_to_blobstore`.
+    pub async fn verify_commit_to_blobstore(
+        &self,
+        ctx: &CoreContext,
+        blobstore: &dyn Blobstore,
+        key_prefix: Option<&str>,
+    ) -> Result<(), Error> {
+        let contents = blobstore
+            .get(ctx, &blobstore_key(key_prefix, &self.name()))
+            .await?
+            .map(|blob| blob.into_raw_bytes());
+        self.verify_contents(ctx, contents)
+    }
+
+    fn verify_contents(&self, ctx: &CoreContext, contents: Option<Bytes>) -> Result<(), Error> {
+        let contents = contents.ok_or_else(|| Error::msg("Committed snapshot was not found"))?;
+        let (_metadata, committed) = decode(ctx, &contents)?;
+
+        let committed_count = committed.filenodes.as_ref().map_or(0, |f| f.len());
+        if committed_count != self.filenodes_count() {
+            bail!(
+                "Committed snapshot holds {} filenodes, expected {}",
+                committed_count,
+                self.filenodes_count()
+            );
+        }
+
+        // Both are compared as thrift, so that this also works for snapshots written in other
+        // formats.
+        let committed_checksum = header::checksum(&compact_protocol::serialize(&committed));
+        let checksum = header::checksum(&compact_protocol::serialize(&self.snapshot));
+        if committed_checksum != checksum {
+            bail!(
+                "Committed snapshot has checksum {:016x}, expected {:016x}",
+                committed_checksum,
+                checksum
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Name the snapshot is stored under, depending on whether it is a delta.
+    fn name(&self) -> String {
+        if self.is_delta() {
+            delta_snapshot_name()
+        } else {
+            snapshot_name()
+        }
+    }
+}
+
+/// Errors that are known to go away by retrying: I/O errors from timeouts and dropped
+/// connections, and multiplexed puts that only failed because of such errors. Anything else is
+/// assumed to be permanent.
+fn is_transient(e: &Error) -> bool {
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        return matches!(
+            e.kind(),
+            ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::WouldBlock
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+        );
+    }
+    if e.downcast_ref::<tokio::time::error::Elapsed>().is_some() {
+        return true;
+    }
+    match e.downcast_ref::<multiplexedblob::base::ErrorKind>() {
+        Some(multiplexedblob::base::ErrorKind::MultiplePutFailures(errors)) => {
+            errors.values().all(is_transient)
+        }
+        _ => false,
+    }
+}
+
+/// Whether this error comes from reading a blob that is redacted.
+pub fn is_redacted(e: &Error) -> bool {
+    matches!(
+        e.downcast_ref::<redactedblobstore::ErrorKind>(),
+        Some(redactedblobstore::ErrorKind::Censored(..))
+    )
+}
+
+fn now_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
 }
 
 fn snapshot_name() -> String {
     format!("microwave_snapshot_v{}", thrift::CODEVER)
 }
 
-fn snapshot_path(shared_local_path: &Path, repo_id: RepositoryId) -> PathBuf {
-    let name = format!("{}{}", repo_id.prefix(), snapshot_name());
+fn delta_snapshot_name() -> String {
+    format!("microwave_snapshot_delta_v{}", thrift::CODEVER)
+}
+
+fn snapshot_path(shared_local_path: &Path, repo_id: RepositoryId, name: &str) -> PathBuf {
+    let name = format!("{}{}", repo_id.prefix(), name);
     shared_local_path.join(&name)
 }
 
-async fn load_snapshot(
+/// Write `contents` to `tmp_path`, and once they are on disk, move them to `path`. On POSIX
+/// filesystems, `path` then either has its previous contents or all of the new ones.
+async fn write_and_rename(tmp_path: &Path, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = File::create(tmp_path).await?;
+    file.write_all(contents).await?;
+    file.flush().await?;
+    file.sync_all().await?;
+    tokio::fs::rename(tmp_path, path).await
+}
+
+fn tmp_snapshot_path(path: &Path) -> PathBuf {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    PathBuf::from(tmp_path)
+}
+
+fn blobstore_key(key_prefix: Option<&str>, name: &str) -> String {
+    match key_prefix {
+        Some(key_prefix) => format!("{}{}", key_prefix, name),
+        None => name.to_string(),
+    }
+}
+
+fn latest_pointer_key() -> String {
+    format!("microwave_latest_v{}", thrift::CODEVER)
+}
+
+fn snapshot_key(key_prefix: &str, repo_id: RepositoryId, name: &str) -> String {
+    format!("{}{}{}", key_prefix, repo_id.prefix(), name)
+}
+
+fn snapshot_url(url: &str, repo_id: RepositoryId, name: &str) -> String {
+    format!("{}/{}{}", url.trim_end_matches('/'), repo_id.prefix(), name)
+}
+
+/// Read the raw contents of the snapshot with this name, if it exists.
+async fn read(
     ctx: &CoreContext,
     repo: &BlobRepo,
     location: SnapshotLocation<'_>,
-) -> Result<thrift::RepoSnapshot, Error> {
+    name: &str,
+) -> Result<Option<Bytes>, Error> {
     match location {
-        SnapshotLocation::SharedLocalPath(path) => {
+        SnapshotLocation::SharedLocalPath {
+            path,
+            encryption_key,
+            ..
+        } => {
+            let mut snapshot = match File::open(snapshot_path(path, repo.get_repoid(), name)).await
+            {
+                Ok(snapshot) => snapshot,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
             let mut contents = vec![];
-            let mut snapshot = File::open(snapshot_path(path, repo.get_repoid())).await?;
             snapshot.read_to_end(&mut contents).await?;
-            Ok(compact_protocol::deserialize(&contents)?)
+            if encryption::is_encrypted(&contents) {
+                let key = encryption_key.ok_or_else(|| {
+                    Error::msg("Snapshot is encrypted, but no encryption key was given")
+                })?;
+                contents = encryption::decrypt(key, &contents)?;
+            }
+            Ok(Some(Bytes::from(contents)))
         }
-        SnapshotLocation::Blobstore => {
-            let bytes = repo
+        SnapshotLocation::Blobstore { key_prefix } => Ok(repo
+            .blobstore()
+            .get(ctx, &blobstore_key(key_prefix, name))
+            .await?
+            .map(|blob| blob.into_raw_bytes())),
+        SnapshotLocation::BlobstoreLatest => {
+            let key_prefix = match resolve_latest_pointer(ctx, repo.blobstore()).await? {
+                Some(key_prefix) => key_prefix,
+                None => return Ok(None),
+            };
+            Ok(repo
                 .blobstore()
-                .get(ctx, &snapshot_name())
+                .get(ctx, &blobstore_key(Some(&key_prefix), name))
+                .await?
+                .map(|blob| blob.into_raw_bytes()))
+        }
+        SnapshotLocation::ObjectStore { bucket, key_prefix } => {
+            object_store::get(bucket, &snapshot_key(key_prefix, repo.get_repoid(), name)).await
+        }
+        SnapshotLocation::Http {
+            url,
+            timeout,
+            verify_tls,
+        } => {
+            let url = snapshot_url(url, repo.get_repoid(), name);
+            http::get(&url, timeout, verify_tls).await
+        }
+        SnapshotLocation::Stdout { .. } => Ok(None),
+        SnapshotLocation::Memory(snapshots) => {
+            Ok(snapshots.get(&snapshot_key("", repo.get_repoid(), name)))
+        }
+    }
+}
+
+/// Replace the raw contents of the snapshot with this name. Unlike `Snapshot::commit`, these are
+/// written as they are, and manifests are left alone.
+async fn write(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+    name: &str,
+    contents: Vec<u8>,
+) -> Result<(), Error> {
+    match location {
+        SnapshotLocation::SharedLocalPath {
+            path,
+            encryption_key,
+            ..
+        } => {
+            let contents = match encryption_key {
+                Some(key) => encryption::encrypt(key, &contents)?,
+                None => contents,
+            };
+            let path = snapshot_path(path, repo.get_repoid(), name);
+            let tmp_path = tmp_snapshot_path(&path);
+            if let Err(e) = write_and_rename(&tmp_path, &path, &contents).await {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                return Err(e.into());
+            }
+        }
+        SnapshotLocation::Blobstore { key_prefix } => {
+            repo.blobstore()
+                .put(
+                    ctx,
+                    blobstore_key(key_prefix, name),
+                    BlobstoreBytes::from_bytes(contents),
+                )
+                .await?;
+        }
+        SnapshotLocation::ObjectStore { bucket, key_prefix } => {
+            object_store::put(
+                bucket,
+                &snapshot_key(key_prefix, repo.get_repoid(), name),
+                contents,
+            )
+            .await?;
+        }
+        SnapshotLocation::Http { url, .. } => {
+            bail!("Cannot write snapshot to HTTP location {}", url);
+        }
+        SnapshotLocation::BlobstoreLatest => {
+            bail!("Cannot write snapshot to the latest pointer, write it to a key prefix");
+        }
+        SnapshotLocation::Stdout { .. } => {
+            bail!("Cannot rewrite a snapshot written to stdout");
+        }
+        SnapshotLocation::Memory(snapshots) => {
+            snapshots.put(snapshot_key("", repo.get_repoid(), name), contents);
+        }
+    }
+    Ok(())
+}
+
+fn decode(
+    ctx: &CoreContext,
+    contents: &[u8],
+) -> Result<(Option<SnapshotMetadata>, thrift::RepoSnapshot), Error> {
+    if encryption::is_encrypted(contents) {
+        bail!("Snapshot is encrypted, it can only be loaded from a local path with its key");
+    }
+    let decoded = header::decode(contents)?;
+    if !decoded.checksummed {
+        warn!(
+            ctx.logger(),
+            "Snapshot has no checksum, it will be loaded without verification"
+        );
+    }
+    let body = compression::maybe_decompress(decoded.body)?;
+    let snapshot = match decoded.format {
+        SnapshotFormat::Thrift => compact_protocol::deserialize(body.as_ref())?,
+        SnapshotFormat::JsonLines => {
+            // JSON lines snapshots always have a header, so this is only missing if it is corrupt.
+            let repo_id = decoded
+                .metadata
+                .as_ref()
+                .map(|metadata| metadata.repo_id)
+                .ok_or_else(|| Error::msg("Snapshot has no metadata"))?;
+            jsonl::deserialize(repo_id, body.as_ref())?
+        }
+    };
+    Ok((decoded.metadata, snapshot))
+}
+
+/// Check that a snapshot that is about to be loaded into `repo` was built for it. Snapshots
+/// without metadata predate it recording the repo, and cannot be checked.
+fn check_repo_id(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    kind: &str,
+    metadata: Option<&SnapshotMetadata>,
+    options: LoadOptions,
+) -> Result<(), Error> {
+    let snapshot_repo_id = match metadata {
+        Some(metadata) => metadata.repo_id,
+        None => return Ok(()),
+    };
+    if snapshot_repo_id == repo.get_repoid() {
+        return Ok(());
+    }
+    if options.allow_repo_id_mismatch {
+        warn!(
+            ctx.logger(),
+            "Loading {} built for repo {} into repo {}",
+            kind,
+            snapshot_repo_id,
+            repo.get_repoid()
+        );
+        return Ok(());
+    }
+    bail!(
+        "Refusing to load {} built for repo {} into repo {}, it may be the wrong snapshot",
+        kind,
+        snapshot_repo_id,
+        repo.get_repoid()
+    );
+}
+
+/// Load the snapshot stored at this location, with its delta applied if there is one.
+async fn load_snapshot(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+    options: LoadOptions,
+) -> Result<thrift::RepoSnapshot, Error> {
+    let base = read(ctx, repo, location, &snapshot_name()).await?;
+    let delta = read(ctx, repo, location, &delta_snapshot_name()).await?;
+
+    let (base, delta) = match (base, delta) {
+        (Some(base), None) => {
+            let (metadata, snapshot) = decode(ctx, &base)?;
+            check_repo_id(ctx, repo, "snapshot", metadata.as_ref(), options)?;
+            return Ok(snapshot);
+        }
+        (Some(base), Some(delta)) => (base, delta),
+        (None, Some(_)) => bail!("Delta snapshot found, but its base snapshot is missing"),
+        (None, None) => bail!("Snapshot is missing"),
+    };
+
+    let (base_metadata, mut base) = decode(ctx, &base)?;
+    let (delta_metadata, delta) = decode(ctx, &delta)?;
+    let delta_metadata = delta_metadata.ok_or_else(|| Error::msg("Delta has no metadata"))?;
+
+    check_repo_id(ctx, repo, "delta snapshot", Some(&delta_metadata), options)?;
+    check_repo_id(ctx, repo, "base snapshot", base_metadata.as_ref(), options)?;
+
+    // A full snapshot may have been committed after the delta was built, in which case the delta
+    // no longer applies. This is expected, so just use the full snapshot.
+    let base_timestamp = base_metadata.map(|m| m.timestamp);
+    if base_timestamp.is_none() || base_timestamp != delta_metadata.base_timestamp {
+        info!(
+            ctx.logger(),
+            "Ignoring delta snapshot that was built against a different base"
+        );
+        return Ok(base);
+    }
+
+    // Filenodes that are only in the base are kept. They may no longer be needed, but priming
+    // them is harmless.
+    if let Some(filenodes) = delta.filenodes {
+        base.filenodes
+            .get_or_insert_with(Vec::new)
+            .extend(filenodes);
+    }
+    if delta.changesets.is_some() {
+        base.changesets = delta.changesets;
+    }
+    if delta.bookmarks.is_some() {
+        base.bookmarks = delta.bookmarks;
+    }
+    if delta.hg_mapping.is_some() {
+        base.hg_mapping = delta.hg_mapping;
+    }
+    if let Some(blobs) = delta.blobs {
+        base.blobs.get_or_insert_with(Vec::new).extend(blobs);
+    }
+
+    Ok(base)
+}
+
+/// Load the metadata of the snapshot stored at this location, without deserializing the snapshot
+/// itself. Returns `None` if the snapshot predates metadata being recorded.
+pub async fn load_metadata(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+) -> Result<Option<SnapshotMetadata>, Error> {
+    match location {
+        SnapshotLocation::SharedLocalPath {
+            path,
+            encryption_key: None,
+            ..
+        } => {
+            header::read_from_file(&snapshot_path(path, repo.get_repoid(), &snapshot_name())).await
+        }
+        // The header of an encrypted snapshot can only be read by decrypting all of it.
+        SnapshotLocation::SharedLocalPath { .. }
+        | SnapshotLocation::Blobstore { .. }
+        | SnapshotLocation::BlobstoreLatest
+        | SnapshotLocation::ObjectStore { .. }
+        | SnapshotLocation::Http { .. }
+        | SnapshotLocation::Stdout { .. }
+        | SnapshotLocation::Memory(_) => {
+            let contents = read(ctx, repo, location, &snapshot_name())
                 .await?
-                .ok_or_else(|| Error::msg("Snapshot is missing"))?
-                .into_raw_bytes();
-            Ok(compact_protocol::deserialize(&bytes)?)
+                .ok_or_else(|| Error::msg("Snapshot is missing"))?;
+            Ok(header::decode(&contents)?.metadata)
+        }
+    }
+}
+
+/// Mark the snapshot stored at this location as built now, so that garbage collection treats it
+/// as new, without rebuilding it. Only its header is rewritten. A delta on top of it is updated
+/// as well, so that it still applies. When a key is given for a local path, the snapshots are
+/// written back encrypted with it. Returns the updated metadata of the snapshot.
+pub async fn touch_snapshot(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+) -> Result<SnapshotMetadata, Error> {
+    let timestamp = now_timestamp();
+
+    let contents = read(ctx, repo, location, &snapshot_name())
+        .await?
+        .ok_or_else(|| Error::msg("Snapshot is missing"))?;
+    let mut old_timestamp = None;
+    let (contents, metadata) = header::rewrite(&contents, |header| {
+        old_timestamp = header.timestamp;
+        header.timestamp = Some(timestamp);
+    })?;
+    if metadata.repo_id != repo.get_repoid() {
+        bail!(
+            "Snapshot is for repo {}, not {}",
+            metadata.repo_id,
+            repo.get_repoid()
+        );
+    }
+
+    // If the delta then fails to be rewritten, it no longer applies, and loading falls back to
+    // just the snapshot.
+    let delta = read(ctx, repo, location, &delta_snapshot_name()).await?;
+    write(ctx, repo, location, &snapshot_name(), contents).await?;
+    if let Some(delta) = delta {
+        let (delta, _) = header::rewrite(&delta, |header| {
+            if header.base_timestamp == old_timestamp {
+                header.base_timestamp = Some(timestamp);
+            }
+            header.timestamp = Some(timestamp);
+        })?;
+        write(ctx, repo, location, &delta_snapshot_name(), delta).await?;
+    }
+
+    if let SnapshotLocation::SharedLocalPath { path, .. } = location {
+        let path = snapshot_path(path, repo.get_repoid(), &snapshot_name());
+        if let Some(mut manifest) = manifest::read(&path).await? {
+            manifest.timestamp = timestamp;
+            manifest::write(&path, &manifest).await?;
+        }
+    }
+
+    Ok(metadata)
+}
+
+/// Point the latest pointer in `blobstore` at the snapshots with this key prefix, so that they are
+/// what `SnapshotLocation::BlobstoreLatest` loads. Only call this once the snapshot has been
+/// committed: a single put replaces the pointer, so readers then see either the previous
+/// snapshot or this one, and never a pointer to a snapshot that has not been written yet.
+pub async fn update_latest_pointer(
+    ctx: &CoreContext,
+    blobstore: &dyn Blobstore,
+    key_prefix: Option<&str>,
+    retries: usize,
+) -> Result<(), Error> {
+    let value = BlobstoreBytes::from_bytes(key_prefix.unwrap_or_default().as_bytes().to_vec());
+    retry(
+        Some(ctx.logger()),
+        |_| blobstore.put(ctx, latest_pointer_key(), value.clone()),
+        is_transient,
+        RetryLogic::Exponential {
+            base: COMMIT_RETRY_BASE_DELAY,
+            factor: 2.0,
+        },
+        retries + 1,
+    )
+    .await?;
+    Ok(())
+}
+
+/// The key prefix that the latest pointer in `blobstore` refers to, if it has been set.
+pub async fn resolve_latest_pointer(
+    ctx: &CoreContext,
+    blobstore: &dyn Blobstore,
+) -> Result<Option<String>, Error> {
+    match blobstore.get(ctx, &latest_pointer_key()).await? {
+        Some(value) => Ok(Some(String::from_utf8(value.into_raw_bytes().to_vec())?)),
+        None => Ok(None),
+    }
+}
+
+/// Find the snapshots (full and delta) stored in the repository blobstore with this key prefix,
+/// along with their metadata if they have any. Only the header of each snapshot is decoded.
+pub async fn find_blobstore_snapshots(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    key_prefix: Option<&str>,
+) -> Result<Vec<(String, Option<SnapshotMetadata>)>, Error> {
+    let mut snapshots = Vec::new();
+    for name in [snapshot_name(), delta_snapshot_name()] {
+        let key = blobstore_key(key_prefix, &name);
+        if let Some(contents) = repo.blobstore().get(ctx, &key).await? {
+            let metadata = header::decode(contents.as_raw_bytes())?.metadata;
+            snapshots.push((key, metadata));
+        }
+    }
+    Ok(snapshots)
+}
+
+/// Load the manifest written next to the snapshot stored at this location, if there is one. Only
+/// local path snapshots have manifests.
+pub async fn load_manifest(
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+) -> Result<Option<SnapshotManifest>, Error> {
+    match location {
+        SnapshotLocation::SharedLocalPath { path, .. } => {
+            manifest::read(&snapshot_path(path, repo.get_repoid(), &snapshot_name())).await
+        }
+        SnapshotLocation::Blobstore { .. }
+        | SnapshotLocation::BlobstoreLatest
+        | SnapshotLocation::ObjectStore { .. }
+        | SnapshotLocation::Http { .. }
+        | SnapshotLocation::Stdout { .. }
+        | SnapshotLocation::Memory(_) => Ok(None),
+    }
+}
+
+/// Load the filenodes captured in the snapshot stored at this location.
+pub async fn load_filenodes(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+) -> Result<Vec<PreparedFilenode>, Error> {
+    let snapshot = load_snapshot(ctx, repo, location, LoadOptions::default()).await?;
+    let filenodes = snapshot
+        .filenodes
+        .ok_or_else(|| Error::msg("filenodes missing"))?;
+    reheat_filenodes(filenodes)
+}
+
+/// Load the filenodes captured in the snapshot stored at this location. Unlike `load_filenodes`,
+/// these are converted one at a time as the iterator is consumed.
+pub async fn load_filenodes_lazily(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+) -> Result<impl Iterator<Item = Result<PreparedFilenode, Error>>, Error> {
+    let snapshot = load_snapshot(ctx, repo, location, LoadOptions::default()).await?;
+    let filenodes = snapshot
+        .filenodes
+        .ok_or_else(|| Error::msg("filenodes missing"))?;
+    Ok(filenodes.into_iter().map(reheat_filenode))
+}
+
+/// Read the filenodes of the snapshot file at `path` one at a time, calling `f` with the path and
+/// content size of each. Unlike `Snapshot::read_file`, neither the file nor the decoded snapshot
+/// are held in memory, so this works for snapshots of any size. The checksum can only be verified
+/// once the whole file has been read, so `f` may see filenodes of a corrupt snapshot before this
+/// fails. Returns `None` without calling `f` if the snapshot has no header.
+pub fn scan_file_filenodes(
+    path: &Path,
+    mut f: impl FnMut(RepoPath, Option<u64>) -> Result<(), Error>,
+) -> Result<Option<SnapshotMetadata>, Error> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let header = match header::read(&mut file)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    let mut body = header::ChecksumReader::new(file);
+    {
+        let mut filenode = |filenode: thrift::FilenodeSnapshot| -> Result<(), Error> {
+            let path = filenode.path.ok_or_else(|| Error::msg("path missing"))?;
+            f(
+                RepoPath::from_thrift(path)?,
+                filenode.content_size.map(|size| size as u64),
+            )
+        };
+        let reader = compression::maybe_decompress_reader(&mut body)?;
+        match header.format {
+            SnapshotFormat::Thrift => scan::for_each_filenode(reader, &mut filenode)?,
+            SnapshotFormat::JsonLines => {
+                jsonl::for_each_filenode(std::io::BufReader::new(reader), &mut filenode)?
+            }
+        }
+    }
+
+    // Anything after the filenodes still counts towards the checksum.
+    std::io::copy(&mut body, &mut std::io::sink())?;
+    if let Some(expected) = header.checksum {
+        let actual = body.checksum();
+        if actual != expected {
+            bail!(
+                "Snapshot checksum mismatch (expected {:x}, got {:x}), it may be truncated",
+                expected,
+                actual
+            );
         }
     }
+
+    Ok(Some(header.metadata))
 }
 
+/// Prime the caches of `repo` with the snapshot stored at this location. The snapshot must have
+/// been built for `repo`, unless `options` allow otherwise.
 pub async fn prime_cache(
     ctx: &CoreContext,
     repo: &BlobRepo,
     location: SnapshotLocation<'_>,
+    options: LoadOptions,
 ) -> Result<(), Error> {
-    let snapshot = load_snapshot(ctx, repo, location).await?;
+    let snapshot = load_snapshot(ctx, repo, location, options).await?;
 
     let filenodes = snapshot
         .filenodes
@@ -183,67 +1633,179 @@ pub async fn prime_cache(
         filenodes.len()
     );
 
-    let changesets = snapshot
-        .changesets
-        .ok_or_else(|| Error::msg("changesets missing"))?;
-    let changesets = reheat_changesets(repo.get_repoid(), changesets)?;
+    // Changesets are missing from snapshots that were built without capturing them.
+    if let Some(changesets) = snapshot.changesets {
+        let changesets = reheat_changesets(repo.get_repoid(), changesets)?;
 
-    repo.changesets().prime_cache(ctx, changesets.as_ref());
-    info!(
-        ctx.logger(),
-        "primed changesets cache with {} entries",
-        changesets.len()
-    );
+        repo.changesets().prime_cache(ctx, changesets.as_ref());
+        info!(
+            ctx.logger(),
+            "primed changesets cache with {} entries",
+            changesets.len()
+        );
+    }
+
+    if let Some(bookmarks) = snapshot.bookmarks {
+        let bookmarks = reheat_bookmarks(bookmarks)?;
+
+        repo.bookmarks().prime_cache(ctx, bookmarks.as_ref());
+        info!(
+            ctx.logger(),
+            "primed bookmarks cache with {} entries",
+            bookmarks.len()
+        );
+    }
+
+    if let Some(hg_mapping) = snapshot.hg_mapping {
+        let hg_mapping = reheat_hg_mapping(hg_mapping)?;
+
+        repo.bonsai_hg_mapping()
+            .prime_cache(ctx, hg_mapping.as_ref());
+        info!(
+            ctx.logger(),
+            "primed bonsai hg mapping cache with {} entries",
+            hg_mapping.len()
+        );
+    }
+
+    if let Some(blobs) = snapshot.blobs {
+        let keys = blobs
+            .into_iter()
+            .filter(|blob| blob.redacted != Some(true))
+            .filter_map(|blob| blob.key);
+        // Reading the blobs through the repo blobstore fills its caches on the way. Blobs may
+        // have been redacted since the snapshot was built, and those are skipped.
+        let (count, redacted) = stream::iter(keys)
+            .map(|key| async move {
+                match repo.blobstore().get(ctx, &key).await {
+                    Ok(_) => Ok(false),
+                    Err(e) if is_redacted(&e) => Ok(true),
+                    Err(e) => Err(e),
+                }
+            })
+            .buffer_unordered(BLOB_PREFETCH_CONCURRENCY)
+            .try_fold((0, 0), |(count, redacted), skipped| {
+                future::ready(Ok(if skipped {
+                    (count, redacted + 1)
+                } else {
+                    (count + 1, redacted)
+                }))
+            })
+            .await?;
+        info!(ctx.logger(), "prefetched {} blobs", count);
+        if redacted > 0 {
+            info!(ctx.logger(), "skipped {} redacted blobs", redacted);
+        }
+    }
 
     Ok(())
 }
 
+/// Load the blobs captured in the snapshot at this location, with their contents if those were
+/// captured too. This is useful to seed caches without access to the blobstore. Redacted blobs
+/// are left out.
+pub async fn load_blobs(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    location: SnapshotLocation<'_>,
+) -> Result<Vec<(String, Option<Bytes>)>, Error> {
+    let snapshot = load_snapshot(ctx, repo, location, LoadOptions::default()).await?;
+    snapshot
+        .blobs
+        .into_iter()
+        .flatten()
+        .filter(|blob| blob.redacted != Some(true))
+        .map(|blob| {
+            let key = blob.key.ok_or_else(|| Error::msg("blob key missing"))?;
+            Ok((key, blob.data.map(Bytes::from)))
+        })
+        .collect()
+}
+
 fn reheat_filenodes(
     filenodes: Vec<thrift::FilenodeSnapshot>,
 ) -> Result<Vec<PreparedFilenode>, Error> {
-    filenodes
-        .into_iter()
+    filenodes.into_iter().map(reheat_filenode).collect()
+}
+
+fn reheat_filenode(t: thrift::FilenodeSnapshot) -> Result<PreparedFilenode, Error> {
+    let thrift::FilenodeSnapshot {
+        path,
+        filenode,
+        p1,
+        p2,
+        copyfrom,
+        linknode,
+        content_size: _,
+    } = t;
+
+    let path = path.ok_or_else(|| Error::msg("path missing"))?;
+    let filenode = filenode.ok_or_else(|| Error::msg("filenode missing"))?;
+    let linknode = linknode.ok_or_else(|| Error::msg("linknode missing"))?;
+
+    let copyfrom = copyfrom
         .map(|t| {
-            let thrift::FilenodeSnapshot {
-                path,
-                filenode,
-                p1,
-                p2,
-                copyfrom,
-                linknode,
-            } = t;
-
-            let path = path.ok_or_else(|| Error::msg("path missing"))?;
-            let filenode = filenode.ok_or_else(|| Error::msg("filenode missing"))?;
-            let linknode = linknode.ok_or_else(|| Error::msg("linknode missing"))?;
-
-            let copyfrom = copyfrom
-                .map(|t| {
-                    let thrift::CopyInfoSnapshot { path, filenode } = t;
-                    let path = path.ok_or_else(|| Error::msg("copy info path missing"))?;
-                    let filenode =
-                        filenode.ok_or_else(|| Error::msg("copy info filenode missing"))?;
-                    Result::<_, Error>::Ok((
-                        RepoPath::from_thrift(path)?,
-                        HgFileNodeId::new(HgNodeHash::from_thrift(filenode)?),
-                    ))
-                })
-                .transpose()?;
-
-            let filenode = HgFileNodeId::new(HgNodeHash::from_thrift(filenode)?);
-
-            Ok(PreparedFilenode {
-                path: RepoPath::from_thrift(path)?,
-                info: FilenodeInfo {
-                    filenode,
-                    p1: HgNodeHash::from_thrift_opt(p1)?.map(HgFileNodeId::new),
-                    p2: HgNodeHash::from_thrift_opt(p2)?.map(HgFileNodeId::new),
-                    copyfrom,
-                    linknode: HgChangesetId::new(HgNodeHash::from_thrift(linknode)?),
-                },
-            })
+            let thrift::CopyInfoSnapshot { path, filenode } = t;
+            let path = path.ok_or_else(|| Error::msg("copy info path missing"))?;
+            let filenode = filenode.ok_or_else(|| Error::msg("copy info filenode missing"))?;
+            Result::<_, Error>::Ok((
+                RepoPath::from_thrift(path)?,
+                HgFileNodeId::new(HgNodeHash::from_thrift(filenode)?),
+            ))
         })
-        .collect()
+        .transpose()?;
+
+    let filenode = HgFileNodeId::new(HgNodeHash::from_thrift(filenode)?);
+
+    Ok(PreparedFilenode {
+        path: RepoPath::from_thrift(path)?,
+        info: FilenodeInfo {
+            filenode,
+            p1: HgNodeHash::from_thrift_opt(p1)?.map(HgFileNodeId::new),
+            p2: HgNodeHash::from_thrift_opt(p2)?.map(HgFileNodeId::new),
+            copyfrom,
+            linknode: HgChangesetId::new(HgNodeHash::from_thrift(linknode)?),
+        },
+    })
+}
+
+fn filenode_into_thrift(filenode: PreparedFilenode) -> thrift::FilenodeSnapshot {
+    let PreparedFilenode { path, info } = filenode;
+    thrift::FilenodeSnapshot {
+        path: Some(path.into_thrift()),
+        filenode: Some(info.filenode.into_nodehash().into_thrift()),
+        p1: info.p1.map(|p| p.into_nodehash().into_thrift()),
+        p2: info.p2.map(|p| p.into_nodehash().into_thrift()),
+        copyfrom: info.copyfrom.map(|copyfrom| thrift::CopyInfoSnapshot {
+            path: Some(copyfrom.0.into_thrift()),
+            filenode: Some(copyfrom.1.into_nodehash().into_thrift()),
+        }),
+        linknode: Some(info.linknode.into_nodehash().into_thrift()),
+        content_size: None,
+    }
+}
+
+/// Size a filenode takes up in a snapshot serialized as compact thrift, before compression.
+pub fn filenode_serialized_size(filenode: &PreparedFilenode) -> u64 {
+    compact_protocol::serialize(&filenode_into_thrift(filenode.clone())).len() as u64
+}
+
+fn changeset_into_thrift(changeset: ChangesetEntry) -> thrift::ChangesetSnapshot {
+    let ChangesetEntry {
+        repo_id: _,
+        cs_id,
+        parents,
+        gen,
+    } = changeset;
+
+    thrift::ChangesetSnapshot {
+        cs_id: Some(cs_id.into_thrift()),
+        parents: Some(parents.into_iter().map(|p| p.into_thrift()).collect()),
+        // NOTE: We expect this conversion (and the reverse one) between u64 and i64 to succeed
+        // because the generation number is >= 0, but also not so large that it cannot fit in a
+        // i64.
+        gen: Some(gen.try_into().unwrap()),
+    }
 }
 
 fn reheat_changesets(
@@ -277,3 +1839,298 @@ fn reheat_changesets(
         })
         .collect()
 }
+
+fn hg_mapping_into_thrift(entry: BonsaiHgMappingEntry) -> thrift::HgMappingSnapshot {
+    let BonsaiHgMappingEntry { hg_cs_id, bcs_id } = entry;
+
+    thrift::HgMappingSnapshot {
+        bcs_id: Some(bcs_id.into_thrift()),
+        hg_cs_id: Some(hg_cs_id.into_nodehash().into_thrift()),
+    }
+}
+
+fn reheat_hg_mapping(
+    hg_mapping: Vec<thrift::HgMappingSnapshot>,
+) -> Result<Vec<BonsaiHgMappingEntry>, Error> {
+    hg_mapping
+        .into_iter()
+        .map(|m| {
+            let thrift::HgMappingSnapshot { bcs_id, hg_cs_id } = m;
+
+            let bcs_id = bcs_id.ok_or_else(|| Error::msg("hg mapping bcs_id missing"))?;
+            let hg_cs_id = hg_cs_id.ok_or_else(|| Error::msg("hg mapping hg_cs_id missing"))?;
+
+            Ok(BonsaiHgMappingEntry {
+                hg_cs_id: HgChangesetId::new(HgNodeHash::from_thrift(hg_cs_id)?),
+                bcs_id: ChangesetId::from_thrift(bcs_id)?,
+            })
+        })
+        .collect()
+}
+
+fn reheat_bookmarks(
+    bookmarks: Vec<thrift::BookmarkSnapshot>,
+) -> Result<Vec<(Bookmark, ChangesetId)>, Error> {
+    bookmarks
+        .into_iter()
+        .map(|b| {
+            let thrift::BookmarkSnapshot { name, kind, cs_id } = b;
+
+            let name = name.ok_or_else(|| Error::msg("bookmark name missing"))?;
+            let kind = kind.ok_or_else(|| Error::msg("bookmark kind missing"))?;
+            let cs_id = cs_id.ok_or_else(|| Error::msg("bookmark cs_id missing"))?;
+
+            let kind = match kind {
+                thrift::BookmarkKindSnapshot::PUBLISHING => BookmarkKind::Publishing,
+                thrift::BookmarkKindSnapshot::PULL_DEFAULT_PUBLISHING => {
+                    BookmarkKind::PullDefaultPublishing
+                }
+                kind => return Err(format_err!("invalid bookmark kind: {:?}", kind)),
+            };
+
+            Ok((
+                Bookmark::new(BookmarkName::new(name)?, kind),
+                ChangesetId::from_thrift(cs_id)?,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use fbinit::FacebookInit;
+    use mercurial_types_mocks::nodehash::ONES_CSID;
+    use mercurial_types_mocks::nodehash::ONES_FNID;
+    use mercurial_types_mocks::nodehash::THREES_FNID;
+    use mercurial_types_mocks::nodehash::TWOS_FNID;
+    use mononoke_types_mocks::changesetid::TWOS_CSID;
+
+    use super::*;
+
+    fn test_filenode(path: &str, filenode: HgFileNodeId) -> PreparedFilenode {
+        PreparedFilenode {
+            path: RepoPath::file(path).unwrap(),
+            info: FilenodeInfo {
+                filenode,
+                p1: None,
+                p2: None,
+                copyfrom: None,
+                linknode: ONES_CSID,
+            },
+        }
+    }
+
+    async fn test_snapshot(repo_id: RepositoryId, filenodes: Vec<PreparedFilenode>) -> Snapshot {
+        Snapshot::build(
+            repo_id,
+            vec![stream::iter(filenodes)],
+            stream::empty(),
+            stream::empty(),
+            BuildOptions::default(),
+        )
+        .await
+    }
+
+    #[fbinit::test]
+    async fn test_load_headerless_snapshot(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo: BlobRepo = test_repo_factory::build_empty(fb)?;
+        let snapshots = MemorySnapshots::new();
+        let location = SnapshotLocation::Memory(&snapshots);
+
+        // Snapshots written before headers were introduced only hold the serialized snapshot.
+        let headerless = thrift::RepoSnapshot {
+            filenodes: Some(vec![filenode_into_thrift(test_filenode("a", ONES_FNID))]),
+            changesets: Some(vec![]),
+            bookmarks: None,
+            blobs: None,
+            hg_mapping: None,
+        };
+        snapshots.put(
+            snapshot_key("", repo.get_repoid(), &snapshot_name()),
+            compact_protocol::serialize(&headerless).to_vec(),
+        );
+
+        // Such a snapshot cannot be built upon, but it can still be loaded by servers.
+        assert!(Snapshot::load(&ctx, &repo, location).await?.is_none());
+        assert_eq!(
+            load_filenodes(&ctx, &repo, location).await?,
+            vec![test_filenode("a", ONES_FNID)]
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_commit_and_load(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo: BlobRepo = test_repo_factory::build_empty(fb)?;
+        let snapshots = MemorySnapshots::new();
+        let location = SnapshotLocation::Memory(&snapshots);
+
+        let filenodes = vec![test_filenode("a", ONES_FNID), test_filenode("b", TWOS_FNID)];
+        let bookmark = Bookmark::new(BookmarkName::new("main")?, BookmarkKind::Publishing);
+        let snapshot = Snapshot::build(
+            repo.get_repoid(),
+            vec![stream::iter(filenodes.clone())],
+            stream::empty(),
+            stream::iter(vec![(bookmark.clone(), TWOS_CSID)]),
+            BuildOptions::default(),
+        )
+        .await;
+        snapshot.commit(&ctx, &repo, location, 0).await?;
+        assert_eq!(
+            snapshots.keys(),
+            vec![snapshot_key("", repo.get_repoid(), &snapshot_name())]
+        );
+
+        assert_eq!(load_filenodes(&ctx, &repo, location).await?, filenodes);
+        prime_cache(&ctx, &repo, location, LoadOptions::default()).await?;
+
+        let loaded = Snapshot::load(&ctx, &repo, location)
+            .await?
+            .ok_or_else(|| Error::msg("committed snapshot missing"))?;
+        assert_eq!(loaded.metadata(), snapshot.metadata());
+        assert_eq!(loaded.filenodes()?, filenodes);
+        assert_eq!(loaded.bookmarks()?, vec![(bookmark, TWOS_CSID)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_base_of() -> Result<(), Error> {
+        let base = test_snapshot(RepositoryId::new(1), vec![test_filenode("a", ONES_FNID)]).await;
+        let snapshot =
+            test_snapshot(RepositoryId::new(1), vec![test_filenode("b", TWOS_FNID)]).await;
+        base.check_base_of(&snapshot)?;
+
+        let other_repo = test_snapshot(RepositoryId::new(2), vec![]).await;
+        assert!(base.check_base_of(&other_repo).is_err());
+
+        let delta = snapshot.into_delta(&base)?;
+        assert!(delta.check_base_of(&base).is_err());
+
+        Ok(())
+    }
+
+    fn blob_keys(snapshot: &Snapshot) -> Vec<&str> {
+        snapshot
+            .snapshot
+            .blobs
+            .iter()
+            .flatten()
+            .filter_map(|b| b.key.as_deref())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_into_delta() -> Result<(), Error> {
+        let repo_id = RepositoryId::new(1);
+        let mut base = test_snapshot(
+            repo_id,
+            vec![test_filenode("a", ONES_FNID), test_filenode("b", TWOS_FNID)],
+        )
+        .await;
+        base.set_blobs(vec![("x".to_string(), Some(Bytes::from("x")))]);
+        let mut snapshot = test_snapshot(
+            repo_id,
+            vec![
+                test_filenode("a", ONES_FNID),
+                test_filenode("c", THREES_FNID),
+            ],
+        )
+        .await;
+        snapshot.set_blobs(vec![
+            ("x".to_string(), Some(Bytes::from("x"))),
+            ("y".to_string(), Some(Bytes::from("y"))),
+        ]);
+
+        let delta = snapshot.into_delta(&base)?;
+        assert!(delta.is_delta());
+        assert_eq!(
+            delta.metadata().base_timestamp,
+            Some(base.metadata().timestamp)
+        );
+        assert_eq!(delta.filenodes()?, vec![test_filenode("c", THREES_FNID)]);
+        assert_eq!(delta.filenodes_count(), 1);
+        assert_eq!(blob_keys(&delta), vec!["y"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge() -> Result<(), Error> {
+        let repo_id = RepositoryId::new(1);
+        let mut first = test_snapshot(
+            repo_id,
+            vec![test_filenode("a", ONES_FNID), test_filenode("b", TWOS_FNID)],
+        )
+        .await;
+        first.metadata.timestamp = 10;
+        first.set_blobs(vec![("x".to_string(), None)]);
+        let bookmark = Bookmark::new(BookmarkName::new("main")?, BookmarkKind::Publishing);
+        let mut second = Snapshot::build(
+            repo_id,
+            vec![stream::iter(vec![
+                test_filenode("b", TWOS_FNID),
+                test_filenode("c", THREES_FNID),
+            ])],
+            stream::empty(),
+            stream::iter(vec![(bookmark.clone(), TWOS_CSID)]),
+            BuildOptions::default(),
+        )
+        .await;
+        second.metadata.timestamp = 20;
+        second.set_blobs(vec![("x".to_string(), None), ("y".to_string(), None)]);
+
+        let merged = Snapshot::merge(vec![first, second])?;
+        assert_eq!(
+            merged.filenodes()?,
+            vec![
+                test_filenode("a", ONES_FNID),
+                test_filenode("b", TWOS_FNID),
+                test_filenode("c", THREES_FNID),
+            ]
+        );
+        assert_eq!(merged.filenodes_count(), 3);
+        assert_eq!(merged.metadata().timestamp, 10);
+        assert_eq!(merged.bookmarks()?, vec![(bookmark, TWOS_CSID)]);
+        assert_eq!(blob_keys(&merged), vec!["x", "y"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_invalid() -> Result<(), Error> {
+        assert!(Snapshot::merge(vec![]).is_err());
+
+        let base = test_snapshot(RepositoryId::new(1), vec![]).await;
+        let other_repo = test_snapshot(RepositoryId::new(2), vec![]).await;
+        assert!(Snapshot::merge(vec![base, other_repo]).is_err());
+
+        let base = test_snapshot(RepositoryId::new(1), vec![]).await;
+        let delta = test_snapshot(RepositoryId::new(1), vec![])
+            .await
+            .into_delta(&base)?;
+        assert!(Snapshot::merge(vec![base, delta]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(
+            &std::io::Error::from(ErrorKind::TimedOut).into()
+        ));
+        assert!(is_transient(
+            &Error::from(std::io::Error::from(ErrorKind::ConnectionReset)).context("put failed")
+        ));
+        assert!(!is_transient(
+            &std::io::Error::from(ErrorKind::PermissionDenied).into()
+        ));
+        assert!(!is_transient(&Error::msg("blobstore is read-only")));
+        assert!(!is_transient(
+            &blobstore::ErrorKind::NotFound("key".to_string()).into()
+        ));
+    }
+}
@@ -0,0 +1,66 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Error;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::header::SnapshotMetadata;
+
+/// A description of a snapshot written next to it, so that it can be inspected without reading
+/// the snapshot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub repo_id: i32,
+    pub timestamp: i64,
+    pub filenodes_count: u64,
+    /// Set if only 1 in this many filenodes were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u64>,
+    /// Checksum of the snapshot as stored, which is also recorded in its header.
+    pub checksum: u64,
+    /// Version of the binary that built the snapshot, as also recorded in its header.
+    pub builder_version: String,
+}
+
+impl SnapshotManifest {
+    pub(crate) fn new(metadata: &SnapshotMetadata, checksum: u64) -> Self {
+        Self {
+            repo_id: metadata.repo_id.id(),
+            timestamp: metadata.timestamp,
+            filenodes_count: metadata.filenodes_count,
+            sample_rate: metadata.sample_rate,
+            checksum,
+            builder_version: crate::header::builder_version(),
+        }
+    }
+}
+
+pub(crate) fn manifest_path(snapshot_path: &Path) -> PathBuf {
+    let mut path = snapshot_path.as_os_str().to_owned();
+    path.push(".manifest.json");
+    PathBuf::from(path)
+}
+
+pub(crate) async fn write(snapshot_path: &Path, manifest: &SnapshotManifest) -> Result<(), Error> {
+    let contents = serde_json::to_vec_pretty(manifest)?;
+    tokio::fs::write(manifest_path(snapshot_path), contents).await?;
+    Ok(())
+}
+
+/// Read the manifest written next to this snapshot, if there is one.
+pub(crate) async fn read(snapshot_path: &Path) -> Result<Option<SnapshotManifest>, Error> {
+    match tokio::fs::read(manifest_path(snapshot_path)).await {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
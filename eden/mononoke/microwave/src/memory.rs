@@ -0,0 +1,44 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Storage for snapshots in memory, so that they can be built, committed and loaded within a
+//! single process without a filesystem or blobstore.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+/// Snapshots committed to `SnapshotLocation::Memory`, with the same keys as they would have in an
+/// object store without a key prefix. They are lost once this is dropped.
+#[derive(Debug, Default)]
+pub struct MemorySnapshots {
+    snapshots: Mutex<HashMap<String, Bytes>>,
+}
+
+impl MemorySnapshots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The serialized snapshot with this key, if one was committed.
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        self.snapshots.lock().unwrap().get(key).cloned()
+    }
+
+    pub(crate) fn put(&self, key: String, data: Vec<u8>) {
+        self.snapshots
+            .lock()
+            .unwrap()
+            .insert(key, Bytes::from(data));
+    }
+
+    /// The keys of the snapshots that were committed, in no particular order.
+    pub fn keys(&self) -> Vec<String> {
+        self.snapshots.lock().unwrap().keys().cloned().collect()
+    }
+}
@@ -0,0 +1,51 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Storage for snapshots in an S3-compatible object store. Credentials and region are resolved
+//! through the standard AWS environment chain.
+
+use anyhow::Error;
+use aws_sdk_s3::types::ByteStream;
+use aws_sdk_s3::types::SdkError;
+use aws_sdk_s3::Client;
+use bytes::Bytes;
+
+async fn client() -> Client {
+    let config = aws_config::load_from_env().await;
+    Client::new(&config)
+}
+
+pub async fn put(bucket: &str, key: &str, data: Vec<u8>) -> Result<(), Error> {
+    client()
+        .await
+        .put_object()
+        .bucket(bucket)
+        .key(key)
+        .body(ByteStream::from(data))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Fetch the object with this key, or `None` if there is no such object.
+pub async fn get(bucket: &str, key: &str) -> Result<Option<Bytes>, Error> {
+    let res = client()
+        .await
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await;
+
+    let object = match res {
+        Ok(object) => object,
+        Err(SdkError::ServiceError { err, .. }) if err.is_no_such_key() => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(object.body.collect().await?.into_bytes()))
+}
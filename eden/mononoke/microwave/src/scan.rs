@@ -0,0 +1,262 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Reading the filenodes of a snapshot serialized as compact thrift one at a time, without
+//! deserializing the whole snapshot. The snapshot is walked following the compact protocol, and
+//! only the bytes of one filenode at a time are kept, to be deserialized on their own.
+
+use std::io;
+use std::io::Read;
+
+use anyhow::bail;
+use anyhow::Error;
+use fbthrift::compact_protocol;
+
+use crate::thrift;
+
+// Field of thrift::RepoSnapshot that holds the filenodes.
+const FILENODES_FIELD_ID: i16 = 1;
+
+// Types of values, as they are encoded in the compact protocol.
+const TYPE_STOP: u8 = 0;
+const TYPE_BOOL_TRUE: u8 = 1;
+const TYPE_BOOL_FALSE: u8 = 2;
+const TYPE_BYTE: u8 = 3;
+const TYPE_I16: u8 = 4;
+const TYPE_I32: u8 = 5;
+const TYPE_I64: u8 = 6;
+const TYPE_DOUBLE: u8 = 7;
+const TYPE_BINARY: u8 = 8;
+const TYPE_LIST: u8 = 9;
+const TYPE_SET: u8 = 10;
+const TYPE_MAP: u8 = 11;
+const TYPE_STRUCT: u8 = 12;
+const TYPE_FLOAT: u8 = 13;
+
+// Snapshots never nest values this deeply, so this is only reached by corrupt snapshots.
+const MAX_DEPTH: usize = 64;
+
+/// Call `f` with each filenode of the compact thrift serialized snapshot read from `reader`, in
+/// the order they are stored. Everything else in the snapshot is skipped over.
+pub fn for_each_filenode(
+    reader: impl Read,
+    mut f: impl FnMut(thrift::FilenodeSnapshot) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut reader = CompactReader::new(reader);
+
+    let mut last_id = 0;
+    while let Some((ty, id)) = reader.field_header(&mut last_id)? {
+        if id != FILENODES_FIELD_ID || ty != TYPE_LIST {
+            reader.skip(ty, 0)?;
+            continue;
+        }
+
+        let (ty, len) = reader.collection_header()?;
+        if len > 0 && ty != TYPE_STRUCT {
+            bail!("Snapshot filenodes are not structs");
+        }
+        for _ in 0..len {
+            reader.recorded = Some(Vec::new());
+            reader.skip_struct(1)?;
+            let recorded = reader.recorded.take().unwrap_or_default();
+            f(compact_protocol::deserialize(recorded.as_slice())?)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads values encoded with the compact protocol, keeping the bytes that are read while
+/// `recorded` is set.
+struct CompactReader<R> {
+    reader: R,
+    recorded: Option<Vec<u8>>,
+}
+
+impl<R: Read> CompactReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            recorded: None,
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let mut byte = [0];
+        self.reader.read_exact(&mut byte)?;
+        if let Some(recorded) = &mut self.recorded {
+            recorded.push(byte[0]);
+        }
+        Ok(byte[0])
+    }
+
+    fn bytes(&mut self, len: u64) -> Result<(), Error> {
+        let read = match &mut self.recorded {
+            Some(recorded) => (&mut self.reader).take(len).read_to_end(recorded)? as u64,
+            None => io::copy(&mut (&mut self.reader).take(len), &mut io::sink())?,
+        };
+        if read != len {
+            bail!("Snapshot is truncated");
+        }
+        Ok(())
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("Snapshot holds an invalid varint")
+    }
+
+    /// Read the header of the next field of a struct, given the id of the previous one. Returns
+    /// `None` at the end of the struct.
+    fn field_header(&mut self, last_id: &mut i16) -> Result<Option<(u8, i16)>, Error> {
+        let byte = self.byte()?;
+        let ty = byte & 0x0f;
+        if ty == TYPE_STOP {
+            return Ok(None);
+        }
+        let id = match byte >> 4 {
+            0 => {
+                let zigzag = self.varint()?;
+                ((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64)) as i16
+            }
+            delta => last_id.wrapping_add(delta.into()),
+        };
+        *last_id = id;
+        Ok(Some((ty, id)))
+    }
+
+    /// Read the header of a list or set, returning the type of its elements and their number.
+    fn collection_header(&mut self) -> Result<(u8, u64), Error> {
+        let byte = self.byte()?;
+        let len = match byte >> 4 {
+            0x0f => self.varint()?,
+            len => len.into(),
+        };
+        Ok((byte & 0x0f, len))
+    }
+
+    fn skip_struct(&mut self, depth: usize) -> Result<(), Error> {
+        let mut last_id = 0;
+        while let Some((ty, _)) = self.field_header(&mut last_id)? {
+            self.skip(ty, depth)?;
+        }
+        Ok(())
+    }
+
+    /// Skip a value of this type. Booleans that are fields are encoded in their type, so there is
+    /// nothing to skip for them.
+    fn skip(&mut self, ty: u8, depth: usize) -> Result<(), Error> {
+        self.skip_value(ty, false, depth)
+    }
+
+    fn skip_value(&mut self, ty: u8, in_collection: bool, depth: usize) -> Result<(), Error> {
+        if depth > MAX_DEPTH {
+            bail!("Snapshot nests values too deeply");
+        }
+        match ty {
+            TYPE_BOOL_TRUE | TYPE_BOOL_FALSE if in_collection => {
+                self.byte()?;
+            }
+            TYPE_BOOL_TRUE | TYPE_BOOL_FALSE => {}
+            TYPE_BYTE => {
+                self.byte()?;
+            }
+            TYPE_I16 | TYPE_I32 | TYPE_I64 => {
+                self.varint()?;
+            }
+            TYPE_DOUBLE => self.bytes(8)?,
+            TYPE_FLOAT => self.bytes(4)?,
+            TYPE_BINARY => {
+                let len = self.varint()?;
+                self.bytes(len)?;
+            }
+            TYPE_LIST | TYPE_SET => {
+                let (ty, len) = self.collection_header()?;
+                for _ in 0..len {
+                    self.skip_value(ty, true, depth + 1)?;
+                }
+            }
+            TYPE_MAP => {
+                let len = self.varint()?;
+                if len > 0 {
+                    let types = self.byte()?;
+                    for _ in 0..len {
+                        self.skip_value(types >> 4, true, depth + 1)?;
+                        self.skip_value(types & 0x0f, true, depth + 1)?;
+                    }
+                }
+            }
+            TYPE_STRUCT => self.skip_struct(depth + 1)?,
+            ty => bail!("Snapshot holds a value of unknown type {}", ty),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mercurial_types_mocks::nodehash::ONES_CSID;
+    use mercurial_types_mocks::nodehash::ONES_FNID;
+    use mercurial_types_mocks::nodehash::TWOS_FNID;
+    use mononoke_types::RepoPath;
+
+    use super::*;
+
+    #[test]
+    fn test_for_each_filenode() -> Result<(), Error> {
+        let filenode = |path: &str, filenode| {
+            crate::filenode_into_thrift(filenodes::PreparedFilenode {
+                path: RepoPath::file(path).unwrap(),
+                info: filenodes::FilenodeInfo {
+                    filenode,
+                    p1: None,
+                    p2: Some(ONES_FNID),
+                    copyfrom: Some((RepoPath::file("c").unwrap(), ONES_FNID)),
+                    linknode: ONES_CSID,
+                },
+            })
+        };
+        let filenodes = vec![
+            thrift::FilenodeSnapshot {
+                content_size: Some(42),
+                ..filenode("a", ONES_FNID)
+            },
+            filenode("b", TWOS_FNID),
+        ];
+        // Fields after the filenodes are skipped, whatever their contents.
+        let snapshot = thrift::RepoSnapshot {
+            filenodes: Some(filenodes.clone()),
+            changesets: Some(vec![]),
+            bookmarks: None,
+            blobs: Some(vec![thrift::BlobSnapshot {
+                key: Some("key".to_string()),
+                data: Some(vec![0; 1000]),
+                redacted: Some(true),
+            }]),
+            hg_mapping: None,
+        };
+        let serialized = compact_protocol::serialize(&snapshot);
+
+        let mut read = Vec::new();
+        for_each_filenode(serialized.as_ref(), |filenode| {
+            read.push(filenode);
+            Ok(())
+        })?;
+        assert_eq!(read, filenodes);
+
+        assert!(for_each_filenode(&serialized[..serialized.len() / 2], |_| Ok(())).is_err());
+
+        Ok(())
+    }
+}
@@ -10,6 +10,7 @@
 use std::path::Path;
 use std::sync::Arc;
 
+use anyhow::Error;
 use anyhow::Result;
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
@@ -20,11 +21,13 @@ use cloned::cloned;
 use futures::future::join_all;
 use metaconfig_parser::config::configerator_config_handle;
 use metaconfig_parser::config::load_configs_from_raw;
+use metaconfig_parser::config::load_configs_from_raw_skipping_invalid;
 use metaconfig_parser::RepoConfigs;
 use metaconfig_parser::StorageConfigs;
 use repos::RawRepoConfigs;
 use slog::error;
 use slog::info;
+use slog::warn;
 use slog::Logger;
 use tokio::runtime::Handle;
 use tokio::task::JoinHandle;
@@ -40,6 +43,7 @@ pub struct MononokeConfigs {
     update_receivers: Swappable<Vec<Arc<dyn ConfigUpdateReceiver>>>,
     maybe_config_updater: Option<JoinHandle<()>>,
     maybe_config_handle: Option<ConfigHandle<RawRepoConfigs>>,
+    skipped_repo_configs: Vec<(String, Error)>,
 }
 
 impl MononokeConfigs {
@@ -51,10 +55,44 @@ impl MononokeConfigs {
         config_store: &ConfigStore,
         runtime_handle: Handle,
         logger: Logger,
+    ) -> Result<Self> {
+        Self::new_impl(config_path, config_store, runtime_handle, logger, false)
+    }
+
+    /// Like `new`, but repos with an invalid config are left out with a warning instead of
+    /// failing. See `skipped_repo_configs`. The same applies to later updates.
+    pub fn new_skipping_invalid_repos(
+        config_path: impl AsRef<Path>,
+        config_store: &ConfigStore,
+        runtime_handle: Handle,
+        logger: Logger,
+    ) -> Result<Self> {
+        Self::new_impl(config_path, config_store, runtime_handle, logger, true)
+    }
+
+    fn new_impl(
+        config_path: impl AsRef<Path>,
+        config_store: &ConfigStore,
+        runtime_handle: Handle,
+        logger: Logger,
+        skip_invalid: bool,
     ) -> Result<Self> {
         let storage_configs = metaconfig_parser::load_storage_configs(&config_path, config_store)?;
         let storage_configs = Arc::new(ArcSwap::from_pointee(storage_configs));
-        let repo_configs = metaconfig_parser::load_repo_configs(&config_path, config_store)?;
+        let (repo_configs, skipped_repo_configs) = if skip_invalid {
+            metaconfig_parser::load_repo_configs_skipping_invalid(&config_path, config_store)?
+        } else {
+            (
+                metaconfig_parser::load_repo_configs(&config_path, config_store)?,
+                Vec::new(),
+            )
+        };
+        for (repo_name, e) in &skipped_repo_configs {
+            warn!(
+                logger,
+                "Skipping repo {} with invalid config: {:#}", repo_name, e
+            );
+        }
         let repo_configs = Arc::new(ArcSwap::from_pointee(repo_configs));
         let update_receivers = Arc::new(ArcSwap::from_pointee(vec![]));
         let maybe_config_handle = configerator_config_handle(config_path.as_ref(), config_store)?;
@@ -72,6 +110,7 @@ impl MononokeConfigs {
                 update_receivers,
                 config_watcher,
                 logger,
+                skip_invalid,
             ))
         });
         Ok(Self {
@@ -80,9 +119,17 @@ impl MononokeConfigs {
             update_receivers,
             maybe_config_updater,
             maybe_config_handle,
+            skipped_repo_configs,
         })
     }
 
+    /// The repos that were left out of the initial configs because their config is invalid,
+    /// along with the reason. This is always empty unless created by
+    /// `new_skipping_invalid_repos`.
+    pub fn skipped_repo_configs(&self) -> &[(String, Error)] {
+        &self.skipped_repo_configs
+    }
+
     /// The latest repo configs fetched from the underlying configuration store.
     pub fn repo_configs(&self) -> Arc<RepoConfigs> {
         // Load full since there could be lots of calls to repo_configs.
@@ -139,6 +186,7 @@ async fn watch_and_update(
     update_receivers: Swappable<Vec<Arc<dyn ConfigUpdateReceiver>>>,
     mut config_watcher: ConfigUpdateWatcher<RawRepoConfigs>,
     logger: Logger,
+    skip_invalid: bool,
 ) {
     loop {
         match config_watcher.wait_for_next().await {
@@ -148,7 +196,23 @@ async fn watch_and_update(
                     "Raw Repo Configs changed in config store, applying update: {:?}",
                     raw_repo_configs
                 );
-                match load_configs_from_raw(Arc::unwrap_or_clone(raw_repo_configs)) {
+                let raw_repo_configs = Arc::unwrap_or_clone(raw_repo_configs);
+                let loaded = if skip_invalid {
+                    load_configs_from_raw_skipping_invalid(raw_repo_configs).map(
+                        |(repo_configs, storage_configs, skipped)| {
+                            for (repo_name, e) in skipped {
+                                warn!(
+                                    logger,
+                                    "Skipping repo {} with invalid config: {:#}", repo_name, e
+                                );
+                            }
+                            (repo_configs, storage_configs)
+                        },
+                    )
+                } else {
+                    load_configs_from_raw(raw_repo_configs)
+                };
+                match loaded {
                     Ok((new_repo_configs, new_storage_configs)) => {
                         let new_repo_configs = Arc::new(new_repo_configs);
                         let new_storage_configs = Arc::new(new_storage_configs);
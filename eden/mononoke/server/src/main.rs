@@ -11,12 +11,14 @@ use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use async_trait::async_trait;
-use cache_warmup::cache_warmup;
+use cache_warmup::cache_warmup_from_location;
+use clap::Args;
 use clap::Parser;
 use cloned::cloned;
 use cmdlib_logging::ScribeLoggingArgs;
@@ -29,6 +31,8 @@ use futures::channel::oneshot;
 use futures::stream;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
+use microwave::LoadOptions;
+use microwave::SnapshotLocation;
 use mononoke_api::CoreContext;
 use mononoke_api::Repo;
 use mononoke_app::args::HooksAppExtension;
@@ -90,18 +94,72 @@ struct MononokeServerArgs {
     /// Path to a file with land service client private key
     #[clap(long, requires = "land-service-client-cert")]
     land_service_client_private_key: Option<String>,
+    #[clap(flatten)]
+    microwave_args: MicrowaveArgs,
+}
+
+/// Where to preload microwave snapshots from during cache warmup
+#[derive(Args, Clone)]
+struct MicrowaveArgs {
+    /// Download microwave snapshots from this HTTP(S) URL instead of the repository blobstore
+    #[clap(long)]
+    microwave_snapshot_url: Option<String>,
+    /// Load microwave snapshots from the key prefix that the latest pointer in the repository
+    /// blobstore refers to, rather than from the keys without a prefix
+    #[clap(long, conflicts_with = "microwave_snapshot_url")]
+    microwave_snapshot_latest: bool,
+    /// Timeout for downloading a microwave snapshot, in seconds
+    #[clap(long, default_value = "60")]
+    microwave_snapshot_timeout_secs: u64,
+    /// Do not verify TLS certificates when downloading microwave snapshots. Only use this for
+    /// internal hosts.
+    #[clap(long)]
+    microwave_snapshot_insecure: bool,
+    /// Load microwave snapshots even if they were built for another repo. By default such
+    /// snapshots are rejected, as they are most likely the wrong ones.
+    #[clap(long)]
+    allow_repo_id_mismatch: bool,
+}
+
+impl MicrowaveArgs {
+    fn snapshot_location(&self) -> SnapshotLocation<'_> {
+        match &self.microwave_snapshot_url {
+            Some(url) => SnapshotLocation::Http {
+                url,
+                timeout: Duration::from_secs(self.microwave_snapshot_timeout_secs),
+                verify_tls: !self.microwave_snapshot_insecure,
+            },
+            None if self.microwave_snapshot_latest => SnapshotLocation::BlobstoreLatest,
+            None => SnapshotLocation::Blobstore { key_prefix: None },
+        }
+    }
+
+    fn load_options(&self) -> LoadOptions {
+        LoadOptions {
+            allow_repo_id_mismatch: self.allow_repo_id_mismatch,
+        }
+    }
 }
 
 /// Struct representing the Mononoke server process when sharding by repo.
 pub struct MononokeServerProcess {
     fb: FacebookInit,
     repos_mgr: Arc<MononokeReposManager<Repo>>,
+    microwave_args: MicrowaveArgs,
 }
 
 impl MononokeServerProcess {
-    fn new(fb: FacebookInit, repos_mgr: MononokeReposManager<Repo>) -> Self {
+    fn new(
+        fb: FacebookInit,
+        repos_mgr: MononokeReposManager<Repo>,
+        microwave_args: MicrowaveArgs,
+    ) -> Self {
         let repos_mgr = Arc::new(repos_mgr);
-        Self { fb, repos_mgr }
+        Self {
+            fb,
+            repos_mgr,
+            microwave_args,
+        }
     }
 
     async fn add_repo(&self, repo_name: &str, logger: &Logger) -> Result<()> {
@@ -116,11 +174,17 @@ impl MononokeServerProcess {
                     let blob_repo = repo.blob_repo().clone();
                     let cache_warmup_params = repo.config().cache_warmup.clone();
                     let ctx = CoreContext::new_with_logger(self.fb, logger.clone());
-                    cache_warmup(&ctx, &blob_repo, cache_warmup_params)
-                        .await
-                        .with_context(|| {
-                            format!("Error while warming up cache for repo {}", repo_name)
-                        })?;
+                    cache_warmup_from_location(
+                        &ctx,
+                        &blob_repo,
+                        cache_warmup_params,
+                        self.microwave_args.snapshot_location(),
+                        self.microwave_args.load_options(),
+                    )
+                    .await
+                    .with_context(|| {
+                        format!("Error while warming up cache for repo {}", repo_name)
+                    })?;
                     info!(
                         &logger,
                         "Completed repo {} setup in Mononoke service", repo_name
@@ -284,6 +348,7 @@ fn main(fb: FacebookInit) -> Result<()> {
             info!(&root_log, "Built Mononoke");
 
             info!(&root_log, "Warming up cache");
+            let microwave_args = &args.microwave_args;
             stream::iter(mononoke.repos())
                 .map(|repo| {
                     let repo_name = repo.name().to_string();
@@ -293,24 +358,36 @@ fn main(fb: FacebookInit) -> Result<()> {
                     async move {
                         let logger = root_log.new(o!("repo" => repo_name.clone()));
                         let ctx = CoreContext::new_with_logger(fb, logger);
-                        cache_warmup(&ctx, &blob_repo, cache_warmup_params)
-                            .await
-                            .with_context(|| {
-                                format!("Error while warming up cache for repo {}", repo_name)
-                            })
+                        cache_warmup_from_location(
+                            &ctx,
+                            &blob_repo,
+                            cache_warmup_params,
+                            microwave_args.snapshot_location(),
+                            microwave_args.load_options(),
+                        )
+                        .await
+                        .with_context(|| {
+                            format!("Error while warming up cache for repo {}", repo_name)
+                        })
                     }
                 })
                 // Repo cache warmup can be quite expensive, let's limit to 40
                 // at a time.
                 .buffer_unordered(40)
-                .try_collect()
+                .try_collect::<Vec<_>>()
                 .await?;
             info!(&root_log, "Cache warmup completed");
             if let Some(mut executor) = args.sharded_executor_args.build_executor(
                 app.fb,
                 runtime.clone(),
                 app.logger(),
-                || Arc::new(MononokeServerProcess::new(app.fb, repos_mgr)),
+                || {
+                    Arc::new(MononokeServerProcess::new(
+                        app.fb,
+                        repos_mgr,
+                        args.microwave_args.clone(),
+                    ))
+                },
                 false, // disable shard (repo) level healing
                 SM_CLEANUP_TIMEOUT_SECS,
             )? {